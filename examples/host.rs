@@ -0,0 +1,74 @@
+// A host embedding the VM: it registers the functions a plugin can call, loads it, runs it so it
+// subscribes its handlers, then calls each of them once per tick, reporting the ones that trap
+// instead of stopping. `cargo run --example host` prints what happened, tests/example_host.rs
+// checks it
+// VmError is as large as the LinkError in it, as for the library
+#![allow(clippy::result_large_err)]
+extern crate lib;
+
+use std::sync::{Arc, Mutex};
+use lib::asm;
+use lib::embed::{Output, Stdout, Value, VmBuilder, VmError};
+
+pub const PLUGIN: &str = include_str!("host.undoasm");
+
+// What the host saw, as it saw it, the plugin's printing going to `output`
+pub fn run(ticks: i64, output: Arc<dyn Output>) -> Result<Vec<String>, VmError> {
+    let log = Arc::new(Mutex::new(vec!()));
+    let handlers = Arc::new(Mutex::new(vec!()));
+
+    let plugin = asm::assemble(PLUGIN).expect("the plugin assembles");
+    let subscribed = handlers.clone();
+    let reported = log.clone();
+    let vm = VmBuilder::new()
+        .module(plugin)
+        .max_frames(100)
+        .output(output)
+        // Callbacks come as function refs, to call back once the program is done
+        .register("App::subscribe", move |args| match args {
+            [handler @ Value::Function(..)] => {
+                subscribed.lock().unwrap().push(handler.clone());
+                Ok(Value::Int(0))
+            }
+            _ => Err(format!("expected a function, got {:?}", args)),
+        })
+        .register_fn("App::report", move |(score,): (i64,)| {
+            reported.lock().unwrap().push(format!("reported {}", score));
+            Ok(0)
+        })
+        .build()?;
+
+    vm.run()?;
+    let handlers = handlers.lock().unwrap().clone();
+    for tick in 0..ticks {
+        for handler in &handlers {
+            let (module, name) = match handler {
+                Value::Function(module, name) => (module, name),
+                _ => unreachable!(),
+            };
+            let path = format!("{}::{}", module.join("::"), name);
+            let line = match vm.call::<_, Value>(&path, (tick,)) {
+                Ok(value) => format!("tick {}: {} returned {:?}", tick, name, value),
+                Err(VmError::Trap(diagnostic)) => format!("tick {}: {} trapped: {}", tick, name, diagnostic.message),
+                Err(err) => return Err(err),
+            };
+            log.lock().unwrap().push(line);
+        }
+    }
+    let log = log.lock().unwrap().clone();
+    Ok(log)
+}
+
+fn main() {
+    match run(3, Arc::new(Stdout)) {
+        Ok(log) => {
+            for line in log {
+                println!("{}", line);
+            }
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// `.undoasm`, modules written by hand. One directive or instruction per line, `#` to the end of
+// the line is a comment:
+//
+//     module Hello.World
+//     depends Other          # dependencies, `loads` for dynamic ones
+//     export greet
+//     reserve 16             # heap_reserve
+//     compiler undo 0.3      # what built it, and `version` of the module itself
+//     entrypoint start       # where running it starts, MAIN if left out
+//
+//     fn greet 1             # the arity is optional
+//     locals who             # names for the locals, for errors and the debugger, `_` for none
+//     needs net              # only links with the VM feature, a stub that traps otherwise
+//         PushString "hello"
+//         LoadLocal 0
+//         LoadName Prelude.print
+//         Call 2
+//
+//     fn MAIN
+//     at hello.undo:4:5      # where the instructions from here on came from, `at -` to stop
+//         PushInt 3
+//         StoreLocal 0
+//     loop:
+//         LoadLocal 0
+//         Unless done
+//         ...
+//         Jump loop
+//     done:
+//
+// Instructions are named as in the JSON. Jumps take a label of the function, an offset or one
+// relative to the jump like `+2`, resolved as for the JSON. String literals go in the string
+// table, LoadName takes `Module.fn`
+use std::collections::{BTreeMap as Map, HashMap, HashSet};
+use std::fmt::Write;
+use bc;
+use mangle;
+use vm::{format_module_name, resolve_labels, Instruction, Location, Module, ModuleName, RawInstruction, Target};
+
+struct Function {
+    name: String,
+    // Labels in place, for `vm::resolve_labels` once the function's done
+    instructions: Vec<RawInstruction>,
+    // The line each of `instructions` is on
+    lines: Vec<usize>,
+    // Only for actual instructions, labels take no room
+    locations: Vec<Option<Location>>,
+    // Set by the latest `at`
+    at: Option<Location>,
+}
+
+pub fn assemble(source: &str) -> Result<Module, String> {
+    let mut module = Module {
+        format_version: bc::current_version(),
+        name: vec!(),
+        strings: vec!(),
+        functions: Map::new(),
+        dependencies: vec!(),
+        heap_reserve: 0,
+        arities: Map::new(),
+        exports: None,
+        dynamic_dependencies: vec!(),
+        source_map: Map::new(),
+        local_names: Map::new(),
+        version: None,
+        compiler: None,
+        checksum: None,
+        entrypoint: None,
+        conditions: Map::new(),
+        origins: Map::new(),
+    };
+    let mut function: Option<Function> = None;
+    for (idx, line) in source.lines().enumerate() {
+        let number = idx + 1;
+        let located = |err: String| format!("line {}: {}", number, err);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (word, rest) = match line.find(char::is_whitespace) {
+            Some(at) => (&line[..at], line[at..].trim()),
+            None => (line, ""),
+        };
+
+        if let Some(label) = word.strip_suffix(':').filter(|_| rest.is_empty()) {
+            let function = function.as_mut().ok_or_else(|| located("label outside a function".to_string()))?;
+            function.instructions.push(RawInstruction::Label(label.to_string()));
+            function.lines.push(number);
+            continue;
+        }
+        match word {
+            "module" => module.name = qualified(rest).map_err(located)?,
+            "depends" => module.dependencies.push(qualified(rest).map_err(located)?),
+            "loads" => module.dynamic_dependencies.push(qualified(rest).map_err(located)?),
+            "export" => module.exports.get_or_insert_with(Vec::new).extend(rest.split_whitespace().map(String::from)),
+            "reserve" => module.heap_reserve = number_operand(rest).map_err(located)?,
+            "version" => module.version = Some(rest.to_string()),
+            "compiler" => module.compiler = Some(rest.to_string()),
+            "entrypoint" => module.entrypoint = Some(rest.to_string()),
+            "fn" => {
+                let mut words = rest.split_whitespace();
+                let name = words.next().ok_or_else(|| located("fn needs a name".to_string()))?.to_string();
+                if let Some(arity) = words.next() {
+                    module.arities.insert(name.clone(), number_operand(arity).map_err(located)?);
+                }
+                if let Some(done) = function.take() {
+                    finish(done, &mut module)?;
+                }
+                if module.functions.contains_key(&name) {
+                    return Err(located(format!("function {} is defined twice", name)));
+                }
+                function = Some(Function {
+                    name,
+                    instructions: vec!(),
+                    lines: vec!(),
+                    locations: vec!(),
+                    at: None,
+                });
+            }
+            "locals" => {
+                let function = function.as_ref().ok_or_else(|| located("locals outside a function".to_string()))?;
+                let names = rest.split_whitespace().map(|name| if name == "_" { String::new() } else { name.to_string() });
+                module.local_names.insert(function.name.clone(), names.collect());
+            }
+            "needs" => {
+                let function = function.as_ref().ok_or_else(|| located("needs outside a function".to_string()))?;
+                if rest.is_empty() || rest.contains(char::is_whitespace) {
+                    return Err(located("needs takes one feature".to_string()));
+                }
+                module.conditions.insert(function.name.clone(), rest.to_string());
+            }
+            "at" => {
+                let function = function.as_mut().ok_or_else(|| located("at outside a function".to_string()))?;
+                function.at = if rest == "-" { None } else { Some(location(rest).map_err(located)?) };
+            }
+            _ => {
+                let function = function.as_mut().ok_or_else(|| located(format!("{} outside a function", word)))?;
+                let instruction = instruction(word, rest, &mut module.strings).map_err(located)?;
+                function.instructions.push(instruction);
+                function.lines.push(number);
+                function.locations.push(function.at.clone());
+            }
+        }
+    }
+    if let Some(done) = function.take() {
+        finish(done, &mut module)?;
+    }
+    if module.name.is_empty() {
+        return Err("no module name, it needs a `module` line".to_string());
+    }
+    Ok(module)
+}
+
+// Each assembled, keyed by name as linking takes them
+#[cfg(test)]
+pub(crate) fn modules(sources: &[&str]) -> HashMap<Vec<String>, Module> {
+    sources.iter().map(|source| assemble(source).unwrap()).map(|module| (module.name.clone(), module)).collect()
+}
+
+fn instruction(word: &str, rest: &str, strings: &mut Vec<String>) -> Result<RawInstruction, String> {
+    let jump = |rest: &str| match rest.parse() {
+        Ok(offset) if !rest.starts_with('+') => Ok(Target::Absolute(offset)),
+        _ if !rest.is_empty() => Target::parse(rest),
+        _ => Err(format!("{} needs a label", word)),
+    };
+    Ok(match word {
+        "PushInt" => RawInstruction::PushInt(rest.parse().map_err(|_| format!("PushInt needs an integer, not {:?}", rest))?),
+        "PushString" => {
+            let string = string_literal(rest)?;
+            let idx = match strings.iter().position(|existing| *existing == string) {
+                Some(idx) => idx,
+                None => {
+                    strings.push(string);
+                    strings.len() - 1
+                }
+            };
+            RawInstruction::PushString(idx)
+        }
+        "LoadLocal" => RawInstruction::LoadLocal(number_operand(rest)?),
+        "StoreLocal" => RawInstruction::StoreLocal(number_operand(rest)?),
+        "LoadName" => {
+            let at = rest.rfind('.').ok_or(format!("LoadName needs Module.fn, not {:?}", rest))?;
+            RawInstruction::LoadName(ModuleName::new(qualified(&rest[..at])?), rest[at + 1..].to_string())
+        }
+        "LoadGlobal" if !rest.is_empty() => RawInstruction::LoadGlobal(rest.to_string()),
+        "Unless" => RawInstruction::Unless(jump(rest)?),
+        "Jump" => RawInstruction::Jump(jump(rest)?),
+        "Call" => RawInstruction::Call(number_operand(rest)?),
+        "Checkpoint" if rest.is_empty() => RawInstruction::Checkpoint,
+        "Rollback" if rest.is_empty() => RawInstruction::Rollback,
+        "Commit" if rest.is_empty() => RawInstruction::Commit,
+        "LoadGlobal" | "Checkpoint" | "Rollback" | "Commit" => return Err(format!("bad operand for {}", word)),
+        _ => return Err(format!("unknown instruction {}", word)),
+    })
+}
+
+// Points every jump at its label
+fn finish(function: Function, module: &mut Module) -> Result<(), String> {
+    let Function { name, instructions, lines, locations, .. } = function;
+    let instructions = resolve_labels(instructions)
+        .map_err(|(idx, err)| format!("line {}: {} in {}", lines[idx], err, name))?;
+    if locations.iter().any(Option::is_some) {
+        module.source_map.insert(name.clone(), locations);
+    }
+    module.functions.insert(name, instructions);
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (at, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..at],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn qualified(name: &str) -> Result<Vec<String>, String> {
+    if name.is_empty() || name.split('.').any(str::is_empty) || name.contains(char::is_whitespace) {
+        return Err(format!("bad module name {:?}", name));
+    }
+    Ok(name.split('.').map(String::from).collect())
+}
+
+// `file:line:column`, the file may have colons of its own
+fn location(operand: &str) -> Result<Location, String> {
+    let mut parts = operand.rsplitn(3, ':');
+    let bad = || format!("expected file:line:column, not {:?}", operand);
+    let column = parts.next().and_then(|column| column.parse().ok()).ok_or_else(bad)?;
+    let line = parts.next().and_then(|line| line.parse().ok()).ok_or_else(bad)?;
+    let file = parts.next().filter(|file| !file.is_empty()).ok_or_else(bad)?;
+    Ok(Location { file: file.to_string(), line, column })
+}
+
+fn number_operand(operand: &str) -> Result<usize, String> {
+    operand.parse().map_err(|_| format!("expected a count, not {:?}", operand))
+}
+
+fn string_literal(literal: &str) -> Result<String, String> {
+    let inner = literal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"'))
+        .ok_or(format!("expected a string literal, not {}", literal))?;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err(format!("unescaped quote in {}", literal));
+        }
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            other => return Err(format!("bad escape \\{}", other.map(String::from).unwrap_or_default())),
+        });
+    }
+    Ok(string)
+}
+
+// The other way around: `module` as assembly, which assembles back to the same module but for
+// the order of its string table (and so without a checksum). Jump targets get labels, and with `modules` (linked) calls say
+// which function they end up in
+pub fn disassemble(module: &Module, modules: Option<&HashMap<Vec<String>, Module>>) -> String {
+    let mut listing = String::new();
+    writeln!(listing, "module {}", format_module_name(&module.name)).unwrap();
+    for dep in &module.dependencies {
+        writeln!(listing, "depends {}", format_module_name(dep)).unwrap();
+    }
+    for dep in &module.dynamic_dependencies {
+        writeln!(listing, "loads {}", format_module_name(dep)).unwrap();
+    }
+    if let Some(exports) = &module.exports {
+        writeln!(listing, "export {}", exports.join(" ")).unwrap();
+    }
+    if module.heap_reserve > 0 {
+        writeln!(listing, "reserve {}", module.heap_reserve).unwrap();
+    }
+    if let Some(version) = &module.version {
+        writeln!(listing, "version {}", version).unwrap();
+    }
+    if let Some(compiler) = &module.compiler {
+        writeln!(listing, "compiler {}", compiler).unwrap();
+    }
+    if let Some(entrypoint) = &module.entrypoint {
+        writeln!(listing, "entrypoint {}", entrypoint).unwrap();
+    }
+    for (fun, instructions) in &module.functions {
+        listing.push('\n');
+        match module.arities.get(fun) {
+            Some(arity) => writeln!(listing, "fn {} {}", fun, arity).unwrap(),
+            None => writeln!(listing, "fn {}", fun).unwrap(),
+        }
+        if let Some(names) = module.local_names.get(fun) {
+            let names: Vec<&str> = names.iter().map(|name| if name.is_empty() { "_" } else { name.as_str() }).collect();
+            writeln!(listing, "locals {}", names.join(" ")).unwrap();
+        }
+        if let Some(feature) = module.conditions.get(fun) {
+            writeln!(listing, "needs {}", feature).unwrap();
+        }
+        let targets: HashSet<usize> = instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jump(target) | Instruction::Unless(target) if *target <= instructions.len() => Some(*target),
+                _ => None,
+            })
+            .collect();
+        let mut at = None;
+        for (ip, instruction) in instructions.iter().enumerate() {
+            if targets.contains(&ip) {
+                writeln!(listing, "L{}:", ip).unwrap();
+            }
+            let location = module.location(fun, ip);
+            if location != at {
+                match location {
+                    Some(location) => writeln!(listing, "at {}", location).unwrap(),
+                    None => writeln!(listing, "at -").unwrap(),
+                }
+                at = location;
+            }
+            let line = match instruction {
+                Instruction::Jump(target) | Instruction::Unless(target) if targets.contains(target) =>
+                    format!("{} L{}", instruction.name(), target),
+                _ => format_instruction(module, instruction),
+            };
+            let callee = match (instruction, ip.checked_sub(1).map(|ip| &instructions[ip])) {
+                (Instruction::Call(n), Some(Instruction::LoadName(namespace, name))) => Some((&namespace.module, name, *n)),
+                (Instruction::Call(n), Some(Instruction::LoadGlobal(name))) => Some((&module.name, name, *n)),
+                _ => None,
+            };
+            let resolved = callee.and_then(|(callee, name, n)| {
+                let fun = mangle::resolve(modules?.get(callee)?, name, n)?;
+                Some(format!("{}.{}", format_module_name(callee), fun))
+            });
+            match resolved {
+                Some(resolved) => writeln!(listing, "    {:<24} # {}", line, resolved).unwrap(),
+                None => writeln!(listing, "    {}", line).unwrap(),
+            }
+        }
+        if targets.contains(&instructions.len()) {
+            writeln!(listing, "L{}:", instructions.len()).unwrap();
+        }
+    }
+    listing
+}
+
+// As it would be written, jumps to their offset
+pub(crate) fn format_instruction(module: &Module, instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::PushString(idx) => match module.strings.get(*idx) {
+            Some(string) => format!("PushString {}", string_literal_of(string)),
+            // Won't assemble, but the linker would have said so
+            None => format!("PushString {}", idx),
+        },
+        Instruction::LoadName(namespace, name) => format!("LoadName {}.{}", format_module_name(&namespace.module), name),
+        Instruction::LoadGlobal(name) => format!("LoadGlobal {}", name),
+        Instruction::PushInt(n) => format!("PushInt {}", n),
+        Instruction::LoadLocal(n) | Instruction::StoreLocal(n) | Instruction::Call(n)
+            | Instruction::Jump(n) | Instruction::Unless(n) => format!("{} {}", instruction.name(), n),
+        Instruction::Checkpoint | Instruction::Rollback | Instruction::Commit => instruction.name().to_string(),
+    }
+}
+
+fn string_literal_of(string: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bc;
+
+    const LOOP: &str = "
+module Loop
+
+fn MAIN
+    PushInt 3
+loop:
+    Unless done
+    Jump +2
+    Jump -2
+    Jump loop
+done:
+";
+
+    // The same jumps written in the JSON
+    const LOOP_JSON: &str = r#"{
+        "name": ["Loop"],
+        "strings": [],
+        "functions": {"MAIN": [
+            {"tag": "PushInt", "contents": 3},
+            {"tag": "Label", "contents": "loop"},
+            {"tag": "Unless", "contents": "done"},
+            {"tag": "Jump", "contents": "+2"},
+            {"tag": "Jump", "contents": "-2"},
+            {"tag": "Jump", "contents": "loop"},
+            {"tag": "Label", "contents": "done"}
+        ]},
+        "dependencies": []
+    }"#;
+
+    fn jumps(module: &Module) -> Vec<usize> {
+        module.functions["MAIN"].iter().filter_map(|instruction| match instruction {
+            Instruction::Unless(target) | Instruction::Jump(target) => Some(*target),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn jumps_resolve_as_in_the_json() {
+        let assembled = assemble(LOOP).unwrap();
+        assert_eq!(jumps(&assembled), vec!(5, 4, 1, 1));
+        assert_eq!(jumps(&assembled), jumps(&bc::read(LOOP_JSON.as_bytes()).unwrap()));
+    }
+
+    #[test]
+    fn label_errors_say_the_line() {
+        let missing = LOOP.replace("Jump loop", "Jump nowhere");
+        assert_eq!(assemble(&missing).err().unwrap(), "line 10: no label nowhere in MAIN");
+        let twice = LOOP.replace("done:", "loop:");
+        assert_eq!(assemble(&twice).err().unwrap(), "line 11: label loop is defined twice in MAIN");
+        let before = LOOP.replace("Jump -2", "Jump -9");
+        assert_eq!(assemble(&before).err().unwrap(), "line 9: relative offset -9 goes before the function in MAIN");
+    }
+
+    #[test]
+    fn conditions_round_trip() {
+        let module = assemble("module Net\nfn MAIN\n    PushInt 0\nfn fetch 1\nneeds net\n    LoadLocal 0\n").unwrap();
+        assert_eq!(module.conditions.get("fetch").map(String::as_str), Some("net"));
+        let listing = disassemble(&module, None);
+        assert!(listing.contains("fn fetch 1\nneeds net\n"), "{}", listing);
+        assert_eq!(assemble(&listing).unwrap().conditions, module.conditions);
+        assert_eq!(assemble("module Net\nneeds net\n").err().unwrap(), "line 2: needs outside a function");
+    }
+}
@@ -0,0 +1,874 @@
+// Reading modules, and the compact binary encoding of them, `.undoc` files. After the magic
+// number and a version, everything is what the JSON has, in field order: numbers as LEB128
+// (zigzagged for PushInt), strings and lists as their length then their contents, and each
+// instruction as an opcode byte then its operands
+use std::collections::BTreeMap as Map;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::fmt;
+use std::hash::Hasher;
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+use asm;
+use embed;
+use vm::{resolve_labels, Instruction, Location, Module, ModuleName, RawInstruction};
+
+pub const MAGIC: &[u8; 4] = b"UNDO";
+// 2 adds the functions' conditions after the checksum, 1 is still read
+pub const VERSION: u16 = 2;
+
+// Of the module layout, whichever the encoding. A new major can't be read by older VMs, a new
+// minor only adds fields they can do without
+pub const FORMAT_VERSION: (u32, u32) = (1, 1);
+
+pub(crate) fn current_version() -> String {
+    format!("{}.{}", FORMAT_VERSION.0, FORMAT_VERSION.1)
+}
+
+// Modules from before there was a version
+pub(crate) fn unversioned() -> String {
+    "0.0".to_string()
+}
+
+// A module file, assembly going by its extension and either encoding by the magic number
+pub fn load(path: &str) -> Result<Module, String> {
+    if path.ends_with(".undoasm") {
+        return asm::assemble(&fs::read_to_string(path).map_err(|err| err.to_string())?);
+    }
+    if starts_with(path, MAGIC)? {
+        return read(&fs::read(path).map_err(|err| err.to_string())?);
+    }
+    let file = File::open(path).map(BufReader::new).map_err(|err| err.to_string())?;
+    let Fields(fields) = serde_json::from_reader(file).map_err(|err| err.to_string())?;
+    checked(from_json(fields)?)
+}
+
+// Like `load`, except that a bundle gives all its modules
+pub fn load_bundle(path: &str) -> Result<Bundle, String> {
+    if starts_with(path, BUNDLE_MAGIC)? {
+        return unbundle(&fs::read(path).map_err(|err| err.to_string())?);
+    }
+    let module = load(path)?;
+    Ok(Bundle { entry: module.name.clone(), modules: vec![module] })
+}
+
+// Like `read`, for a bundle or a module, or JSON modules one after the other (JSON Lines, say) or
+// in an array. The first one is the entry then
+pub fn read_bundle(bytes: &[u8]) -> Result<Bundle, String> {
+    if bytes.starts_with(BUNDLE_MAGIC) {
+        return unbundle(bytes);
+    }
+    if bytes.starts_with(MAGIC) {
+        let module = read(bytes)?;
+        return Ok(Bundle { entry: module.name.clone(), modules: vec![module] });
+    }
+    // Errors say at which line and column, the line being the module for JSON Lines
+    let values = serde_json::Deserializer::from_slice(bytes).into_iter::<Listed>()
+        .collect::<Result<Vec<Listed>, _>>()
+        .map_err(|err| err.to_string())?;
+    // Nothing at all, which `read` has an error for
+    if values.is_empty() {
+        let module = read(bytes)?;
+        return Ok(Bundle { entry: module.name.clone(), modules: vec![module] });
+    }
+    // One module on its own, errors are as `read` has them
+    let alone = matches!(values.as_slice(), [Listed::One(_)]);
+    let mut modules = vec!();
+    for value in values {
+        match value {
+            Listed::One(fields) => modules.push(fields),
+            Listed::Many(fields) => modules.extend(fields),
+        }
+    }
+    let modules = modules.into_iter().enumerate()
+        .map(|(idx, Fields(fields))| from_json(fields).and_then(checked).map_err(|err| if alone { err } else { format!("module {}: {}", idx + 1, err) }))
+        .collect::<Result<Vec<Module>, String>>()?;
+    let entry = modules.first().ok_or("no modules in the array")?.name.clone();
+    Ok(Bundle { entry, modules })
+}
+
+fn starts_with(path: &str, magic: &[u8]) -> Result<bool, String> {
+    let mut file = File::open(path).map_err(|err| err.to_string())?;
+    let mut start = vec![0; magic.len()];
+    Ok(file.read_exact(&mut start).is_ok() && start == magic)
+}
+
+// Either encoding, told apart by the magic number. A module with a checksum has to match it
+pub fn read(bytes: &[u8]) -> Result<Module, String> {
+    if bytes.starts_with(MAGIC) {
+        return checked(decode(bytes)?);
+    }
+    let Fields(fields) = serde_json::from_slice(bytes).map_err(|err| err.to_string())?;
+    checked(from_json(fields)?)
+}
+
+fn checked(module: Module) -> Result<Module, String> {
+    if let Some(expected) = &module.checksum {
+        let actual = checksum(&module);
+        if *expected != actual {
+            return Err(format!("checksum mismatch, {} was expected but it's {}: stale or modified", expected, actual));
+        }
+    }
+    Ok(module)
+}
+
+// Of everything in the module but its format version and the checksum itself, so it's the same
+// whichever encoding it's in. FNV-1a over the binary encoding, it's for telling artifacts apart
+// rather than against tampering
+pub fn checksum(module: &Module) -> String {
+    let mut writer = Writer { bytes: vec!() };
+    writer.body(module);
+    // Only when there are any, so modules from before there were conditions keep their checksum
+    if !module.conditions.is_empty() {
+        writer.conditions(&module.conditions);
+    }
+    let mut hash = Fnv::new();
+    hash.write(&writer.bytes);
+    format!("fnv1a64:{:016x}", hash.finish())
+}
+
+// FNV-1a, 64 bits. Unlike std's hashers it's the same from one build to the next, for what's
+// saved to disk
+pub(crate) struct Fnv(u64);
+
+impl Fnv {
+    pub(crate) fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Every field of a module in the current format, anything else is most likely a typo
+const FIELDS: [&str; 16] = [
+    "format_version", "name", "strings", "functions", "dependencies", "heap_reserve", "arities", "exports",
+    "dynamic_dependencies", "source_map", "local_names", "version", "compiler", "checksum", "entrypoint",
+    "conditions",
+];
+
+fn parse_version(version: &str) -> Result<(u32, u32), String> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), None) => Ok((major, minor)),
+        // Just a major is as good as .0
+        (Some(Some(major)), None, None) => Ok((major, 0)),
+        _ => Err(format!("bad format version {:?}, expected MAJOR.MINOR", version)),
+    }
+}
+
+// A module's fields as JSON, to look at before reading it. A function defined twice is an error
+// rather than the last one winning
+struct Fields(serde_json::Map<String, Value>);
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a module object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, access: A) -> Result<Fields, A::Error> {
+                fields(access)
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+fn fields<'de, A: MapAccess<'de>>(mut access: A) -> Result<Fields, A::Error> {
+    let mut fields = serde_json::Map::new();
+    while let Some(field) = access.next_key::<String>()? {
+        let value = if field == "functions" { access.next_value::<Functions>()?.0 } else { access.next_value()? };
+        fields.insert(field, value);
+    }
+    Ok(Fields(fields))
+}
+
+// What `read_bundle` takes one after the other: a module, or an array of them
+enum Listed {
+    One(Fields),
+    Many(Vec<Fields>),
+}
+
+impl<'de> Deserialize<'de> for Listed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListedVisitor;
+
+        impl<'de> Visitor<'de> for ListedVisitor {
+            type Value = Listed;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a module object or an array of them")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, access: A) -> Result<Listed, A::Error> {
+                fields(access).map(Listed::One)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut access: A) -> Result<Listed, A::Error> {
+                let mut modules = vec!();
+                while let Some(fields) = access.next_element()? {
+                    modules.push(fields);
+                }
+                Ok(Listed::Many(modules))
+            }
+        }
+
+        deserializer.deserialize_any(ListedVisitor)
+    }
+}
+
+struct Functions(Value);
+
+impl<'de> Deserialize<'de> for Functions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FunctionsVisitor;
+
+        impl<'de> Visitor<'de> for FunctionsVisitor {
+            type Value = Functions;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of function names to instructions")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Functions, A::Error> {
+                let mut functions = serde_json::Map::new();
+                while let Some(name) = access.next_key::<String>()? {
+                    if functions.contains_key(&name) {
+                        return Err(de::Error::custom(format!("function {} is defined twice", name)));
+                    }
+                    functions.insert(name, access.next_value()?);
+                }
+                Ok(Functions(Value::Object(functions)))
+            }
+        }
+
+        deserializer.deserialize_map(FunctionsVisitor)
+    }
+}
+
+// Looks at the version and fields before the rest, so a module from a newer frontend says so
+// instead of failing on whatever changed, and one from an older major gets upgraded first
+fn from_json(mut object: serde_json::Map<String, Value>) -> Result<Module, String> {
+    let format_version = match object.get("format_version") {
+        None | Some(Value::Null) => unversioned(),
+        Some(Value::String(version)) => version.clone(),
+        Some(version) => return Err(format!("bad format version {}, expected MAJOR.MINOR", version)),
+    };
+    let version = parse_version(&format_version)?;
+    if version.0 > FORMAT_VERSION.0 {
+        return Err(format!("module format version {} is newer than this VM reads ({}.x at most)", format_version, FORMAT_VERSION.0));
+    }
+    upgrade(version.0, &mut object);
+    // A newer minor may well add fields we don't know
+    if version <= FORMAT_VERSION {
+        if let Some(field) = object.keys().find(|field| !FIELDS.contains(&field.as_str())) {
+            return Err(format!("unknown field `{}`", field));
+        }
+    }
+    let mut module: Module = serde_json::from_value(Value::Object(object)).map_err(|err| err.to_string())?;
+    module.format_version = current_version();
+    Ok(module)
+}
+
+// Brings a module in an older major's layout to the current one, a step per major
+fn upgrade(major: u32, module: &mut serde_json::Map<String, Value>) {
+    // From before there was a version. VMs then ignored the fields they didn't know, where 1.0
+    // rejects them, and everything 1.0 added has a default
+    if major < 1 {
+        let unknown: Vec<String> = module.keys().filter(|field| !FIELDS.contains(&field.as_str())).cloned().collect();
+        for field in unknown {
+            module.remove(&field);
+        }
+    }
+}
+
+// Every problem with the module JSON in `bytes` rather than just the first, with where it is.
+// For when reading it failed, it's a lot slower. Nothing for the binary formats
+pub fn diagnose(bytes: &[u8]) -> Vec<String> {
+    if bytes.starts_with(MAGIC) || bytes.starts_with(BUNDLE_MAGIC) {
+        return vec!();
+    }
+    let value: Value = match serde_json::from_slice(bytes) {
+        Ok(value) => value,
+        Err(err) => return vec![err.to_string()],
+    };
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => return vec!["a module is a JSON object".to_string()],
+    };
+    let mut errors = vec!();
+    for field in &["name", "strings", "functions", "dependencies"] {
+        if !object.contains_key(*field) {
+            errors.push(format!("missing field `{}`", field));
+        }
+    }
+    for (field, value) in object {
+        let value = value.clone();
+        let checked = match field.as_str() {
+            "name" | "strings" => serde_json::from_value::<Vec<String>>(value).map(|_| ()),
+            "dependencies" | "dynamic_dependencies" => serde_json::from_value::<Vec<Vec<String>>>(value).map(|_| ()),
+            "heap_reserve" => serde_json::from_value::<usize>(value).map(|_| ()),
+            "arities" => serde_json::from_value::<Map<String, usize>>(value).map(|_| ()),
+            "exports" => serde_json::from_value::<Option<Vec<String>>>(value).map(|_| ()),
+            "source_map" => serde_json::from_value::<Map<String, Vec<Option<Location>>>>(value).map(|_| ()),
+            "local_names" => serde_json::from_value::<Map<String, Vec<String>>>(value).map(|_| ()),
+            "conditions" => serde_json::from_value::<Map<String, String>>(value).map(|_| ()),
+            "format_version" | "version" | "compiler" | "checksum" | "entrypoint" =>
+                serde_json::from_value::<Option<String>>(value).map(|_| ()),
+            "functions" => {
+                match value.as_object() {
+                    Some(functions) => for (fun, instructions) in functions {
+                        match instructions.as_array() {
+                            Some(instructions) => {
+                                let mut raw = vec!();
+                                for (ip, instruction) in instructions.iter().enumerate() {
+                                    match serde_json::from_value::<RawInstruction>(instruction.clone()) {
+                                        Ok(instruction) => raw.push(instruction),
+                                        Err(err) => errors.push(format!("function {}, instruction {}: {}", fun, ip, err)),
+                                    }
+                                }
+                                // Only if they all parsed, or the offsets are off
+                                if raw.len() == instructions.len() {
+                                    if let Err((idx, err)) = resolve_labels(raw) {
+                                        errors.push(format!("function {}, instruction {}: {}", fun, idx, err));
+                                    }
+                                }
+                            }
+                            None => errors.push(format!("function {}: expected a list of instructions", fun)),
+                        }
+                    },
+                    None => errors.push("functions: expected an object of functions".to_string()),
+                }
+                Ok(())
+            }
+            _ => {
+                errors.push(format!("unknown field `{}`", field));
+                Ok(())
+            }
+        };
+        if let Err(err) = checked {
+            errors.push(format!("{}: {}", field, err));
+        }
+    }
+    errors
+}
+
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut writer = Writer { bytes: MAGIC.to_vec() };
+    writer.bytes.extend(&VERSION.to_le_bytes());
+    writer.body(module);
+    writer.option_string(&module.checksum);
+    writer.conditions(&module.conditions);
+    writer.bytes
+}
+
+pub const BUNDLE_MAGIC: &[u8; 4] = b"UNDB";
+pub const BUNDLE_VERSION: u16 = 1;
+
+// Several modules in one file, with the one to run. Each module is in the binary encoding,
+// preceded by its length
+pub struct Bundle {
+    pub entry: Vec<String>,
+    pub modules: Vec<Module>,
+}
+
+pub fn bundle(entry: &[String], modules: &[Module]) -> Vec<u8> {
+    let mut writer = Writer { bytes: BUNDLE_MAGIC.to_vec() };
+    writer.bytes.extend(&BUNDLE_VERSION.to_le_bytes());
+    writer.name(entry);
+    writer.list(modules, |writer, module| {
+        let encoded = encode(module);
+        writer.number(encoded.len() as u64);
+        writer.bytes.extend(encoded);
+    });
+    writer.bytes
+}
+
+pub fn unbundle(bytes: &[u8]) -> Result<Bundle, String> {
+    if !bytes.starts_with(BUNDLE_MAGIC) {
+        return Err("not a bundle".to_string());
+    }
+    let mut reader = Reader { bytes, at: BUNDLE_MAGIC.len() };
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+    if version != BUNDLE_VERSION {
+        return Err(format!("bundle version {}, only {} is supported", version, BUNDLE_VERSION));
+    }
+    let entry = reader.name()?;
+    let modules = reader.list(|reader| {
+        let len = reader.number()? as usize;
+        let encoded = reader.bytes.get(reader.at..reader.at.saturating_add(len)).ok_or("truncated bundle")?;
+        reader.at += len;
+        read(encoded)
+    })?;
+    if reader.at != bytes.len() {
+        return Err(format!("{} byte(s) left over at the end", bytes.len() - reader.at));
+    }
+    if !modules.iter().any(|module| module.name == entry) {
+        return Err(format!("the entry module {} isn't in the bundle", entry.join(".")));
+    }
+    Ok(Bundle { entry, modules })
+}
+
+pub const VALUE_MAGIC: &[u8; 4] = b"UNDV";
+pub const VALUE_VERSION: u16 = 1;
+
+// A value as the host gets it, to keep and read back in a later run. A tag then the value, ints
+// zigzagged like PushInt's and functions by their module and name, which don't change from one
+// build to the next
+pub fn encode_value(value: &embed::Value) -> Vec<u8> {
+    let mut writer = Writer { bytes: VALUE_MAGIC.to_vec() };
+    writer.bytes.extend(&VALUE_VERSION.to_le_bytes());
+    match value {
+        embed::Value::Int(n) => {
+            writer.number(0);
+            writer.number(((n << 1) ^ (n >> 63)) as u64);
+        }
+        embed::Value::Str(string) => {
+            writer.number(1);
+            writer.string(string);
+        }
+        embed::Value::Function(module, name) => {
+            writer.number(2);
+            writer.name(module);
+            writer.string(name);
+        }
+    }
+    writer.bytes
+}
+
+pub fn decode_value(bytes: &[u8]) -> Result<embed::Value, String> {
+    if !bytes.starts_with(VALUE_MAGIC) {
+        return Err("not an encoded value".to_string());
+    }
+    let mut reader = Reader { bytes, at: VALUE_MAGIC.len() };
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+    if version != VALUE_VERSION {
+        return Err(format!("encoded value version {}, only {} is supported", version, VALUE_VERSION));
+    }
+    let value = match reader.number()? {
+        0 => {
+            let n = reader.number()?;
+            embed::Value::Int(((n >> 1) as i64) ^ -((n & 1) as i64))
+        }
+        1 => embed::Value::Str(reader.string()?),
+        2 => embed::Value::Function(reader.name()?, reader.string()?),
+        tag => return Err(format!("unknown value tag {}", tag)),
+    };
+    if reader.at != bytes.len() {
+        return Err(format!("{} byte(s) left over at the end", bytes.len() - reader.at));
+    }
+    Ok(value)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Module, String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("not a binary module".to_string());
+    }
+    let mut reader = Reader { bytes, at: MAGIC.len() };
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+    if version != 1 && version != VERSION {
+        return Err(format!("binary module version {}, only 1 and {} are supported", version, VERSION));
+    }
+    let name = reader.name()?;
+    let strings = reader.list(|reader| reader.string())?;
+    let dependencies = reader.list(|reader| reader.name())?;
+    let heap_reserve = reader.number()? as usize;
+    let mut arities = Map::new();
+    for _ in 0..reader.number()? {
+        arities.insert(reader.string()?, reader.number()? as usize);
+    }
+    let exports = match reader.number()? {
+        0 => None,
+        _ => Some(reader.list(|reader| reader.string())?),
+    };
+    let dynamic_dependencies = reader.list(|reader| reader.name())?;
+    let mut functions = Map::new();
+    for _ in 0..reader.number()? {
+        let fun = reader.string()?;
+        let instructions = reader.list(|reader| reader.instruction())?;
+        if functions.insert(fun.clone(), instructions).is_some() {
+            return Err(format!("function {} is defined twice", fun));
+        }
+    }
+    let mut source_map = Map::new();
+    for _ in 0..reader.number()? {
+        let fun = reader.string()?;
+        let locations = reader.list(|reader| match reader.number()? {
+            0 => Ok(None),
+            _ => Ok(Some(Location { file: reader.string()?, line: reader.number()? as usize, column: reader.number()? as usize })),
+        })?;
+        source_map.insert(fun, locations);
+    }
+    let mut local_names = Map::new();
+    for _ in 0..reader.number()? {
+        local_names.insert(reader.string()?, reader.list(|reader| reader.string())?);
+    }
+    let module_version = reader.option_string()?;
+    let compiler = reader.option_string()?;
+    let entrypoint = reader.option_string()?;
+    let checksum = reader.option_string()?;
+    let mut conditions = Map::new();
+    if version >= 2 {
+        for _ in 0..reader.number()? {
+            conditions.insert(reader.string()?, reader.string()?);
+        }
+    }
+    if reader.at != bytes.len() {
+        return Err(format!("{} byte(s) left over at the end", bytes.len() - reader.at));
+    }
+    Ok(Module {
+        format_version: current_version(),
+        name,
+        strings,
+        functions,
+        dependencies,
+        heap_reserve,
+        arities,
+        exports,
+        dynamic_dependencies,
+        source_map,
+        local_names,
+        version: module_version,
+        compiler,
+        checksum,
+        entrypoint,
+        conditions,
+        origins: Map::new(),
+    })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    // Everything but the header and checksum
+    fn body(&mut self, module: &Module) {
+        self.name(&module.name);
+        self.list(&module.strings, |writer, string| writer.string(string));
+        self.list(&module.dependencies, |writer, dep| writer.name(dep));
+        self.number(module.heap_reserve as u64);
+        self.number(module.arities.len() as u64);
+        for (fun, arity) in &module.arities {
+            self.string(fun);
+            self.number(*arity as u64);
+        }
+        match &module.exports {
+            None => self.number(0),
+            Some(exports) => {
+                self.number(1);
+                self.list(exports, |writer, export| writer.string(export));
+            }
+        }
+        self.list(&module.dynamic_dependencies, |writer, dep| writer.name(dep));
+        self.number(module.functions.len() as u64);
+        for (fun, instructions) in &module.functions {
+            self.string(fun);
+            self.list(instructions, |writer, instruction| writer.instruction(instruction));
+        }
+        self.number(module.source_map.len() as u64);
+        for (fun, locations) in &module.source_map {
+            self.string(fun);
+            self.list(locations, |writer, location| match location {
+                None => writer.number(0),
+                Some(location) => {
+                    writer.number(1);
+                    writer.string(&location.file);
+                    writer.number(location.line as u64);
+                    writer.number(location.column as u64);
+                }
+            });
+        }
+        self.number(module.local_names.len() as u64);
+        for (fun, names) in &module.local_names {
+            self.string(fun);
+            self.list(names, |writer, name| writer.string(name));
+        }
+        self.option_string(&module.version);
+        self.option_string(&module.compiler);
+        self.option_string(&module.entrypoint);
+    }
+
+    fn conditions(&mut self, conditions: &Map<String, String>) {
+        self.number(conditions.len() as u64);
+        for (fun, feature) in conditions {
+            self.string(fun);
+            self.string(feature);
+        }
+    }
+
+    fn number(&mut self, mut n: u64) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    fn string(&mut self, string: &str) {
+        self.number(string.len() as u64);
+        self.bytes.extend(string.as_bytes());
+    }
+
+    fn option_string(&mut self, string: &Option<String>) {
+        match string {
+            None => self.number(0),
+            Some(string) => {
+                self.number(1);
+                self.string(string);
+            }
+        }
+    }
+
+    fn list<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Self, &T)) {
+        self.number(items.len() as u64);
+        for it in items {
+            item(self, it);
+        }
+    }
+
+    fn name(&mut self, name: &[String]) {
+        self.list(name, |writer, part| writer.string(part));
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        self.bytes.push(opcode(instruction));
+        match instruction {
+            Instruction::PushInt(n) => self.number(((n << 1) ^ (n >> 63)) as u64),
+            Instruction::PushString(n) | Instruction::LoadLocal(n) | Instruction::StoreLocal(n)
+                | Instruction::Unless(n) | Instruction::Jump(n) | Instruction::Call(n) => self.number(*n as u64),
+            Instruction::LoadName(namespace, name) => {
+                self.name(&namespace.module);
+                self.string(name);
+            }
+            Instruction::LoadGlobal(name) => self.string(name),
+            Instruction::Checkpoint | Instruction::Rollback | Instruction::Commit => {}
+        }
+    }
+}
+
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::PushInt(_) => 0,
+        Instruction::PushString(_) => 1,
+        Instruction::LoadLocal(_) => 2,
+        Instruction::StoreLocal(_) => 3,
+        Instruction::LoadName(_, _) => 4,
+        Instruction::LoadGlobal(_) => 5,
+        Instruction::Unless(_) => 6,
+        Instruction::Jump(_) => 7,
+        Instruction::Call(_) => 8,
+        Instruction::Checkpoint => 9,
+        Instruction::Rollback => 10,
+        Instruction::Commit => 11,
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    at: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.at).ok_or("truncated binary module")?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(format!("number too long at byte {}", self.at))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.number()? as usize;
+        let bytes = self.bytes.get(self.at..self.at.saturating_add(len)).ok_or("truncated binary module")?;
+        self.at += len;
+        String::from_utf8(bytes.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn option_string(&mut self) -> Result<Option<String>, String> {
+        match self.number()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?)),
+        }
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let len = self.number()?;
+        // Not trusting the length for the allocation, it's at least a byte per item
+        let mut items = Vec::with_capacity((len as usize).min(self.bytes.len() - self.at));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn name(&mut self) -> Result<Vec<String>, String> {
+        self.list(|reader| reader.string())
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let opcode = self.byte()?;
+        Ok(match opcode {
+            0 => {
+                let n = self.number()?;
+                Instruction::PushInt(((n >> 1) as i64) ^ -((n & 1) as i64))
+            }
+            1 => Instruction::PushString(self.number()? as usize),
+            2 => Instruction::LoadLocal(self.number()? as usize),
+            3 => Instruction::StoreLocal(self.number()? as usize),
+            4 => Instruction::LoadName(ModuleName::new(self.name()?), self.string()?),
+            5 => Instruction::LoadGlobal(self.string()?),
+            6 => Instruction::Unless(self.number()? as usize),
+            7 => Instruction::Jump(self.number()? as usize),
+            8 => Instruction::Call(self.number()? as usize),
+            9 => Instruction::Checkpoint,
+            10 => Instruction::Rollback,
+            11 => Instruction::Commit,
+            _ => return Err(format!("unknown opcode {} at byte {}", opcode, self.at - 1)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+module Round.Trip
+depends Other
+export greet
+compiler undo 0.3
+version 7
+entrypoint greet
+
+fn greet 1
+locals who
+at greet.undo:2:5
+    PushString \"hello\"
+    LoadLocal 0
+    PushInt -3
+    LoadName Other.print
+    Call 2
+";
+
+    #[test]
+    fn encoding_round_trips() {
+        let module = asm::assemble(SOURCE).unwrap();
+        let decoded = decode(&encode(&module)).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&module).unwrap());
+        assert_eq!(checksum(&decoded), checksum(&module));
+    }
+
+    const OLD: &str = r#"{
+        "name": ["Old"],
+        "strings": ["hello"],
+        "functions": {"MAIN": [{"tag": "PushString", "contents": 0}]},
+        "dependencies": [],
+        "frontend_debug": {"ghc": "8.0"}
+    }"#;
+
+    #[test]
+    fn unversioned_modules_are_upgraded() {
+        let module = read(OLD.as_bytes()).unwrap();
+        assert_eq!(module.format_version, current_version());
+        assert_eq!(module.entrypoint(), "MAIN");
+        assert_eq!(module.strings, vec!("hello"));
+    }
+
+    #[test]
+    fn unknown_fields_only_go_for_old_modules() {
+        let current = OLD.replacen("{", r#"{"format_version": "1.1","#, 1);
+        assert_eq!(read(current.as_bytes()).err().unwrap(), "unknown field `frontend_debug`");
+        let newer_minor = OLD.replacen("{", r#"{"format_version": "1.9","#, 1);
+        assert!(read(newer_minor.as_bytes()).is_ok());
+        let newer_major = OLD.replacen("{", r#"{"format_version": "2.0","#, 1);
+        assert_eq!(read(newer_major.as_bytes()).err().unwrap(), "module format version 2.0 is newer than this VM reads (1.x at most)");
+    }
+
+    #[test]
+    fn functions_defined_twice_are_rejected() {
+        let twice = OLD.replacen(r#""functions": {"#, r#""functions": {"MAIN": [], "#, 1);
+        let err = read(twice.as_bytes()).err().unwrap();
+        assert!(err.starts_with("function MAIN is defined twice"), "{}", err);
+    }
+
+    #[test]
+    fn json_lines_say_which_line_is_wrong() {
+        let old = OLD.replace('\n', " ");
+        let twice = old.replacen(r#""functions": {"#, r#""functions": {"MAIN": [], "#, 1);
+        let err = read_bundle(format!("{}\n{}\n", old, twice).as_bytes()).err().unwrap();
+        assert!(err.starts_with("function MAIN is defined twice at line 2"), "{}", err);
+        let err = read_bundle(format!("{}\n{{\"name\": \n", old).as_bytes()).err().unwrap();
+        assert!(err.ends_with("at line 3 column 0"), "{}", err);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let values = [
+            embed::Value::Int(i64::MIN),
+            embed::Value::Int(-1),
+            embed::Value::Str("héllo".to_string()),
+            embed::Value::Function(vec!("Round".to_string(), "Trip".to_string()), "greet".to_string()),
+        ];
+        for value in &values {
+            assert_eq!(&decode_value(&encode_value(value)).unwrap(), value);
+        }
+        let mut bytes = encode_value(&values[0]);
+        bytes[VALUE_MAGIC.len()] = 2;
+        assert_eq!(decode_value(&bytes).err().unwrap(), "encoded value version 2, only 1 is supported");
+        assert_eq!(decode_value(&encode(&asm::assemble(SOURCE).unwrap())).err().unwrap(), "not an encoded value");
+    }
+
+    #[test]
+    fn other_binary_versions_are_rejected() {
+        let mut bytes = encode(&asm::assemble(SOURCE).unwrap());
+        bytes[MAGIC.len()] = 3;
+        assert_eq!(decode(&bytes).err().unwrap(), "binary module version 3, only 1 and 2 are supported");
+    }
+
+    #[test]
+    fn version_1_binaries_have_no_conditions() {
+        let mut module = asm::assemble(SOURCE).unwrap();
+        let mut bytes = encode(&module);
+        // The empty conditions at the end
+        bytes.pop();
+        bytes[MAGIC.len()] = 1;
+        assert!(decode(&bytes).unwrap().conditions.is_empty());
+
+        let unconditional = checksum(&module);
+        module.conditions.insert("greet".to_string(), "net".to_string());
+        assert_ne!(checksum(&module), unconditional);
+        assert_eq!(decode(&encode(&module)).unwrap().conditions, module.conditions);
+    }
+}
@@ -0,0 +1,343 @@
+// Debug Adapter Protocol server, so editors can drive the debugger.
+// Talks over TCP since stdout belongs to the program (`"debugServer": PORT` in VS Code).
+//
+// Every function is exposed as a read-only source (its instruction listing, one per line) so
+// stack frames, stepping and line breakpoints all work in terms of instruction offsets. Those
+// are after the passes, a line breakpoint stops wherever the instruction there came from runs.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use serde_json::Value as Json;
+use debugger::{describe, moved_from, qualified_name, Debugger, Resume, Stop};
+use gc::{Ptr, GC};
+use vm::{cur_fn, format_module_name, Frame, State};
+
+pub(crate) struct Dap {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    seq: u64,
+    connected: bool,
+    stopped_once: bool,
+    // Sources by sourceReference - 1, as (module, fn)
+    sources: Vec<(Vec<String>, String)>,
+    // Kept apart since the client always replaces a whole kind at once
+    function_breakpoints: Vec<String>,
+    line_breakpoints: Vec<(String, usize)>,
+}
+
+impl Dap {
+    pub(crate) fn connect(port: u16) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .unwrap_or_else(|err| panic!("Cannot listen on port {}: {}", port, err));
+        eprintln!("Waiting for a DAP client on port {}...", port);
+        let (stream, _) = listener.accept().expect("Cannot accept DAP client");
+        Dap {
+            reader: BufReader::new(stream.try_clone().expect("Cannot clone DAP stream")),
+            writer: stream,
+            seq: 0,
+            connected: true,
+            stopped_once: false,
+            sources: vec!(),
+            function_breakpoints: vec!(),
+            line_breakpoints: vec!(),
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    // Handles the initialization sequence, up to `configurationDone`
+    pub(crate) fn configure(&mut self, debugger: &mut Debugger) {
+        let mut sources: Vec<(Vec<String>, String)> = debugger.modules.values()
+            .flat_map(|module| module.functions.keys().map(move |fun| (module.name.clone(), fun.clone())))
+            .collect();
+        sources.sort();
+        self.sources = sources;
+        debugger.resume = Resume::Continue;
+
+        while let Some(request) = self.read() {
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            match command.as_str() {
+                "initialize" => {
+                    self.respond(&request, json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsStepBack": true,
+                    }));
+                    self.event("initialized", json!({}));
+                }
+                "launch" | "attach" => {
+                    if request["arguments"]["stopOnEntry"].as_bool().unwrap_or(false) {
+                        debugger.resume = Resume::Step;
+                    }
+                    self.respond(&request, json!({}));
+                }
+                "configurationDone" => {
+                    self.respond(&request, json!({}));
+                    return;
+                }
+                _ => self.handle(&request, debugger, None),
+            }
+        }
+    }
+
+    // Reports the stop, then serves requests until the client resumes us
+    pub(crate) fn stopped<'a>(&mut self, debugger: &mut Debugger<'a>, state: &mut State<'a>, stop: Stop) {
+        let (reason, description) = match stop {
+            Stop::Step if !self.stopped_once => ("entry", None),
+            Stop::Step => ("step", None),
+            Stop::Breakpoint => ("breakpoint", None),
+            Stop::Watchpoint(idx) => ("data breakpoint", Some(format!("local {} is about to be written", idx))),
+        };
+        self.stopped_once = true;
+        self.event("stopped", json!({
+            "reason": reason,
+            "description": description,
+            "threadId": 1,
+            "allThreadsStopped": true,
+        }));
+
+        while let Some(request) = self.read() {
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            let resume = match command.as_str() {
+                "continue" => Some(Resume::Continue),
+                "next" => Some(Resume::Next(state.frames.len())),
+                "stepIn" => Some(Resume::Step),
+                "stepOut" => Some(Resume::Out(state.frames.len())),
+                "disconnect" => {
+                    self.respond(&request, json!({}));
+                    self.connected = false;
+                    debugger.resume = Resume::Continue;
+                    return;
+                }
+                "stepBack" => {
+                    debugger.history.undo(state, 1);
+                    self.respond(&request, json!({}));
+                    self.event("stopped", json!({ "reason": "step", "threadId": 1, "allThreadsStopped": true }));
+                    continue;
+                }
+                _ => None,
+            };
+            match resume {
+                Some(resume) => {
+                    debugger.resume = resume;
+                    self.respond(&request, json!({ "allThreadsContinued": true }));
+                    return;
+                }
+                None => self.handle(&request, debugger, Some(state)),
+            }
+        }
+        // Client went away, let the program finish on its own
+        debugger.resume = Resume::Continue;
+    }
+
+    pub(crate) fn terminated(&mut self) {
+        if self.connected {
+            self.event("exited", json!({ "exitCode": 0 }));
+            self.event("terminated", json!({}));
+        }
+    }
+
+    // Requests that don't resume execution, `state` is None while configuring
+    fn handle(&mut self, request: &Json, debugger: &mut Debugger, state: Option<&mut State>) {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "threads" => self.respond(request, json!({ "threads": [{ "id": 1, "name": "main" }] })),
+            "setExceptionBreakpoints" => self.respond(request, json!({})),
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints.clear();
+                let mut breakpoints = vec!();
+                for breakpoint in arguments["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    let name = breakpoint["name"].as_str().unwrap_or("").to_string();
+                    let verified = self.sources.iter()
+                        .any(|(module, fun)| *fun == name || format!("{}.{}", format_module_name(module), fun) == name);
+                    self.function_breakpoints.push(name);
+                    breakpoints.push(json!({ "verified": verified }));
+                }
+                self.sync_breakpoints(debugger);
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "setBreakpoints" => {
+                let source = self.source_name(&arguments["source"]);
+                let mut breakpoints = vec!();
+                if let Some(source) = &source {
+                    self.line_breakpoints.retain(|(fun, _)| fun != source);
+                }
+                for breakpoint in arguments["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    let line = breakpoint["line"].as_u64().unwrap_or(1).max(1) as usize;
+                    match &source {
+                        Some(source) => {
+                            self.line_breakpoints.push((source.clone(), line - 1));
+                            breakpoints.push(json!({ "verified": true, "line": line }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "message": "Only function listings provided by the VM can have breakpoints",
+                        })),
+                    }
+                }
+                self.sync_breakpoints(debugger);
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "source" => {
+                let reference = arguments["sourceReference"].as_u64().unwrap_or(0) as usize;
+                match reference.checked_sub(1).and_then(|idx| self.sources.get(idx)).cloned() {
+                    Some((module, fun)) => {
+                        let instructions = cur_fn(&debugger.modules[&module], fun.clone());
+                        let mut content: Vec<String> = instructions.iter()
+                            .enumerate()
+                            .map(|(ip, instruction)| format!("{}: {:?}{}", ip, instruction, moved_from(&debugger.modules[&module], &fun, ip)))
+                            .collect();
+                        content.push(format!("{}: <return>", instructions.len()));
+                        self.respond(request, json!({ "content": content.join("\n") }));
+                    }
+                    None => self.fail(request, "No such source"),
+                }
+            }
+            "stackTrace" => match state {
+                Some(state) => {
+                    let frames: Vec<Json> = state.frames.iter().enumerate().rev()
+                        .map(|(id, frame)| json!({
+                            "id": id,
+                            "name": qualified_name(frame),
+                            "line": frame.ip + 1,
+                            "column": 1,
+                            "source": self.source(frame),
+                        }))
+                        .collect();
+                    self.respond(request, json!({ "stackFrames": frames, "totalFrames": frames.len() }));
+                }
+                None => self.fail(request, "Not stopped"),
+            },
+            "scopes" => {
+                let id = arguments["frameId"].as_u64().unwrap_or(0);
+                self.respond(request, json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": id * 2 + 1, "expensive": false },
+                    { "name": "Stack", "variablesReference": id * 2 + 2, "expensive": false },
+                ]}));
+            }
+            "variables" => match state {
+                Some(state) => {
+                    let reference = arguments["variablesReference"].as_u64().unwrap_or(0) as usize;
+                    let frame = reference.checked_sub(1).and_then(|r| state.frames.get(r / 2));
+                    let variables = match frame {
+                        Some(frame) if reference % 2 == 1 =>
+                            variables(|i| frame.module.local(&frame.fun, i), &frame.locals, &state.gc),
+                        Some(_) => variables(|i| i.to_string(), &state.stack, &state.gc),
+                        None => vec!(),
+                    };
+                    self.respond(request, json!({ "variables": variables }));
+                }
+                None => self.fail(request, "Not stopped"),
+            },
+            "disconnect" => {
+                self.respond(request, json!({}));
+                self.connected = false;
+            }
+            command => self.fail(request, &format!("Unsupported request: {}", command)),
+        }
+    }
+
+    // Line breakpoints are in the listings, the debugger's as the modules were read
+    fn sync_breakpoints(&self, debugger: &mut Debugger) {
+        let modules = debugger.modules;
+        let as_read = |(source, ip): &(String, usize)| {
+            let origin = self.sources.iter()
+                .find(|(module, fun)| format!("{}.{}", format_module_name(module), fun) == *source)
+                .and_then(|(module, fun)| modules[module].origin(fun, *ip));
+            match origin {
+                Some(origin) => (format!("{}.{}", format_module_name(&origin.module), origin.fun), origin.ips.1),
+                None => (source.clone(), *ip),
+            }
+        };
+        debugger.breakpoints = self.function_breakpoints.iter()
+            .map(|fun| (fun.clone(), 0))
+            .chain(self.line_breakpoints.iter().map(as_read))
+            .collect();
+    }
+
+    // The `Module.fn` a source object refers to, if it's one of ours
+    fn source_name(&self, source: &Json) -> Option<String> {
+        let reference = source["sourceReference"].as_u64()? as usize;
+        let (module, fun) = self.sources.get(reference.checked_sub(1)?)?;
+        Some(format!("{}.{}", format_module_name(module), fun))
+    }
+
+    fn source(&self, frame: &Frame) -> Json {
+        let reference = self.sources.iter()
+            .position(|(module, fun)| *module == frame.module.name && *fun == frame.fun)
+            .map_or(0, |idx| idx + 1);
+        json!({ "name": qualified_name(frame), "sourceReference": reference })
+    }
+
+    fn read(&mut self) -> Option<Json> {
+        if !self.connected {
+            return None;
+        }
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header).unwrap_or(0) == 0 {
+                self.connected = false;
+                return None;
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0; length?];
+        if self.reader.read_exact(&mut body).is_err() {
+            self.connected = false;
+            return None;
+        }
+        serde_json::from_slice(&body).ok()
+    }
+
+    fn send(&mut self, mut message: Json) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let sent = write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        if sent.is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+fn variables(name: impl Fn(usize) -> String, ptrs: &[Ptr], gc: &GC) -> Vec<Json> {
+    ptrs.iter().enumerate()
+        .map(|(i, ptr)| json!({
+            "name": name(i),
+            "value": describe(&gc.at(*ptr)),
+            "variablesReference": 0,
+        }))
+        .collect()
+}
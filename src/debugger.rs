@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use dap::Dap;
+use history::History;
+use link;
+use snapshot;
+use gc::{self, Ptr, GC};
+use vm::{cur_fn, format_module_name, Frame, Instruction, Module, State, VmConfig, Value};
+
+// How far to run before prompting again
+pub(crate) enum Resume {
+    Step,
+    // Stop once we're back at (or above) this frame depth
+    Next(usize),
+    // Stop once we're above this frame depth
+    Out(usize),
+    Continue,
+}
+
+pub(crate) enum Stop {
+    Step,
+    Breakpoint,
+    // About to write to this watched local
+    Watchpoint(usize),
+}
+
+pub(crate) struct Debugger<'a> {
+    pub(crate) modules: &'a HashMap<Vec<String>, Module>,
+    pub(crate) resume: Resume,
+    last_command: String,
+    // (function, local slot), function is either `fn` or `Module.fn`
+    watchpoints: Vec<(String, usize)>,
+    // (function, ip), same. The ip is as the module was read, whatever the passes did to it
+    pub(crate) breakpoints: Vec<(String, usize)>,
+    pub(crate) history: History<'a>,
+    // Drives the debugger instead of the prompt when set
+    dap: Option<Dap>,
+}
+
+const HELP: &str = "\
+step (s)        execute one instruction
+next (n)        execute one instruction, stepping over calls
+continue (c)    run until the program ends
+stack           print the value stack, top last
+locals          print the current frame's locals
+frames (bt)     print the call frames, innermost last
+heap            print every live object in the arena
+heap stats      print heap statistics
+back [N]        undo the last N instructions (default 1), output stays printed
+save FILE       write a snapshot of the VM state, to be resumed with --resume
+reload FILE     swap in new function bodies for the module in FILE, for calls from now on
+break F [IP]    stop before instruction IP (default 0) of function F (`fn` or `Module.fn`), as
+                read: where the passes moved it, and wherever F got inlined
+unbreak F [IP]  remove that breakpoint
+watch F N       stop before local N of function F is written
+unwatch F N     remove that watchpoint
+An empty line repeats the last command.";
+
+impl<'a> Debugger<'a> {
+    pub(crate) fn new(modules: &'a HashMap<Vec<String>, Module>, config: &VmConfig) -> Self {
+        let mut debugger = Debugger {
+            modules,
+            resume: Resume::Step,
+            last_command: String::new(),
+            watchpoints: vec!(),
+            breakpoints: vec!(),
+            history: History::new(modules, config.history_budget),
+            dap: None,
+        };
+        if let Some(port) = config.dap {
+            let mut dap = Dap::connect(port);
+            dap.configure(&mut debugger);
+            debugger.dap = Some(dap);
+        }
+        debugger
+    }
+
+    // Called right before the instruction executes, after `pause`
+    pub(crate) fn record(&mut self, state: &mut State<'a>) {
+        self.history.record(state);
+    }
+
+    // Called before every instruction, prompts if we should stop here
+    pub(crate) fn pause(&mut self, state: &mut State<'a>) {
+        let frame = state.frames.back().expect("No current frame?!");
+        let watched = self.watched_store(frame);
+        let at_breakpoint = self.breakpoints.iter().any(|(fun, ip)| is_at(fun, *ip, frame));
+        let stepped = match self.resume {
+            Resume::Step => true,
+            Resume::Next(depth) => state.frames.len() <= depth,
+            Resume::Out(depth) => state.frames.len() < depth,
+            Resume::Continue => false,
+        };
+        let stop = match watched {
+            Some(idx) => Stop::Watchpoint(idx),
+            None if at_breakpoint => Stop::Breakpoint,
+            None if stepped => Stop::Step,
+            None => return,
+        };
+
+        match self.dap.take() {
+            Some(mut dap) => {
+                dap.stopped(self, state, stop);
+                if dap.is_connected() {
+                    self.dap = Some(dap);
+                }
+            }
+            None => self.prompt(state, stop),
+        }
+    }
+
+    // The program is done
+    pub(crate) fn finish(&mut self) {
+        if let Some(dap) = self.dap.as_mut() {
+            dap.terminated();
+        }
+    }
+
+    fn prompt(&mut self, state: &mut State<'a>, stop: Stop) {
+        let frame = state.frames.back().expect("No current frame?!");
+        match stop {
+            Stop::Watchpoint(idx) => {
+                let (stack, gc) = (&state.stack, &state.gc);
+                let old = frame.locals.get(idx).map_or("<uninitialized>".to_string(), |ptr| describe(&gc.at(*ptr)));
+                let new = stack.last().map_or("<empty stack>".to_string(), |ptr| describe(&gc.at(*ptr)));
+                eprintln!("Watchpoint: {} local {}: {} -> {}", frame.fun, idx, old, new);
+            }
+            Stop::Breakpoint => eprintln!("Breakpoint"),
+            Stop::Step => {}
+        }
+        print_location(frame);
+        let stdin = io::stdin();
+        loop {
+            eprint!("(undo) ");
+            io::stderr().flush().expect("Cannot flush stderr");
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).expect("Cannot read stdin") == 0 {
+                // EOF: nobody's there to drive us anymore
+                self.resume = Resume::Continue;
+                return;
+            }
+            let command = match line.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_string(),
+            };
+            self.last_command = command.clone();
+
+            match command.as_str() {
+                "step" | "s" => {
+                    self.resume = Resume::Step;
+                    return;
+                }
+                "next" | "n" => {
+                    self.resume = Resume::Next(state.frames.len());
+                    return;
+                }
+                "continue" | "c" => {
+                    self.resume = Resume::Continue;
+                    return;
+                }
+                "stack" => print_ptrs(&state.stack, &state.gc),
+                "locals" => {
+                    let frame = state.frames.back().unwrap();
+                    if frame.locals.is_empty() {
+                        eprintln!("(empty)");
+                    }
+                    for (i, ptr) in frame.locals.iter().enumerate() {
+                        eprintln!("{}: {}", frame.module.local(&frame.fun, i), describe(&state.gc.at(*ptr)));
+                    }
+                }
+                "frames" | "bt" => {
+                    for frame in &state.frames {
+                        print_location(frame);
+                    }
+                }
+                "heap" => print_heap(state),
+                "heap stats" => eprintln!("{}", gc::stats(state)),
+                "help" => eprintln!("{}", HELP),
+                command if command.starts_with("save ") => {
+                    let path = command["save".len()..].trim();
+                    match snapshot::save(state, path) {
+                        Ok(()) => eprintln!("Saved to {}", path),
+                        Err(err) => eprintln!("Cannot write snapshot to {}: {}", path, err),
+                    }
+                }
+                command if command.starts_with("reload ") => {
+                    let path = command["reload".len()..].trim();
+                    let (modules, loaded) = (self.modules, &mut state.loaded);
+                    match link::reload(path, modules, loaded, state.kept, &state.features) {
+                        Ok(module) => {
+                            // Its strings may have changed, and replaying past this would mix old and new code
+                            state.interned.remove(&module.name);
+                            self.history.reset(state);
+                            eprintln!("Reloaded {}", format_module_name(&module.name));
+                        }
+                        Err(err) => eprintln!("Cannot reload {}: {}", path, err),
+                    }
+                }
+                command if command == "back" || command.starts_with("back ") => {
+                    let count = match command["back".len()..].trim() {
+                        "" => Some(1),
+                        count => count.parse::<usize>().ok(),
+                    };
+                    match count {
+                        Some(count) => {
+                            let undone = self.history.undo(state, count);
+                            if undone < count {
+                                eprintln!("Only {} instruction(s) left in history", undone);
+                            }
+                            print_location(state.frames.back().unwrap());
+                        }
+                        None => eprintln!("Usage: back [N]"),
+                    }
+                }
+                command if command.starts_with("break ") || command.starts_with("unbreak ") => {
+                    let words: Vec<&str> = command.split_whitespace().collect();
+                    let ip = match words.get(2) {
+                        Some(ip) => ip.parse::<usize>().ok(),
+                        None => Some(0),
+                    };
+                    match (words.len(), ip) {
+                        (2, Some(ip)) | (3, Some(ip)) => {
+                            let breakpoint = (words[1].to_string(), ip);
+                            if words[0] == "break" {
+                                self.breakpoints.push(breakpoint);
+                            } else {
+                                self.breakpoints.retain(|b| *b != breakpoint);
+                            }
+                        }
+                        _ => eprintln!("Usage: {} <fn> [ip]", words[0]),
+                    }
+                }
+                command if command.starts_with("watch ") || command.starts_with("unwatch ") => {
+                    let words: Vec<&str> = command.split_whitespace().collect();
+                    match (words.len(), words.get(2).and_then(|n| n.parse::<usize>().ok())) {
+                        (3, Some(idx)) => {
+                            let watchpoint = (words[1].to_string(), idx);
+                            if words[0] == "watch" {
+                                self.watchpoints.push(watchpoint);
+                            } else {
+                                self.watchpoints.retain(|w| *w != watchpoint);
+                            }
+                        }
+                        _ => eprintln!("Usage: {} <fn> <local>", words[0]),
+                    }
+                }
+                "" => {}
+                command => eprintln!("Unknown command: {} (try `help`)", command),
+            }
+        }
+    }
+
+    // Returns the slot if `frame` is about to write to a watched local
+    fn watched_store(&self, frame: &Frame) -> Option<usize> {
+        let idx = match cur_fn(frame.module, frame.fun.to_string()).get(frame.ip) {
+            Some(Instruction::StoreLocal(idx)) => *idx,
+            _ => return None,
+        };
+        self.watchpoints
+            .iter()
+            .find(|(fun, slot)| *slot == idx && is_fn(fun, frame))
+            .map(|_| idx)
+    }
+}
+
+// Whether `fun`, either `fn` or `Module.fn`, names the function `frame` is running
+fn is_fn(fun: &str, frame: &Frame) -> bool {
+    fun == frame.fun || fun == qualified_name(frame)
+}
+
+// Whether `frame` is about to run instruction `ip` of `fun` as it was read. When the passes
+// rewrote it that may be anywhere, in every function it got inlined in too
+pub(crate) fn is_at(fun: &str, ip: usize, frame: &Frame) -> bool {
+    match frame.module.origin(&frame.fun, frame.ip) {
+        Some(origin) => (fun == origin.fun || fun == format!("{}.{}", format_module_name(&origin.module), origin.fun))
+            && origin.ips.0 <= ip && ip <= origin.ips.1,
+        None => ip == frame.ip && is_fn(fun, frame),
+    }
+}
+
+// ` (was Module.fn@ip)` if the passes moved the instruction at `ip` of `fun` from there
+pub(crate) fn moved_from(module: &Module, fun: &str, ip: usize) -> String {
+    match module.origin(fun, ip) {
+        Some(origin) if origin.module != module.name || origin.fun != fun || origin.ips.1 != ip =>
+            format!(" (was {}.{}@{})", format_module_name(&origin.module), origin.fun, origin.ips.1),
+        _ => String::new(),
+    }
+}
+
+pub(crate) fn qualified_name(frame: &Frame) -> String {
+    format!("{}.{}", format_module_name(&frame.module.name), frame.fun)
+}
+
+fn print_location(frame: &Frame) {
+    let fun = cur_fn(frame.module, frame.fun.to_string());
+    let mut location = format!("{}.{}@{}{}", format_module_name(&frame.module.name), frame.fun, frame.ip, moved_from(frame.module, &frame.fun, frame.ip));
+    if let Some(source) = frame.module.location(&frame.fun, frame.ip) {
+        location = format!("{} ({})", location, source);
+    }
+    match fun.get(frame.ip) {
+        Some(instruction) => eprintln!("{}: {:?}", location, instruction),
+        None => eprintln!("{}: <return>", location),
+    }
+}
+
+fn print_ptrs(ptrs: &[Ptr], gc: &GC) {
+    if ptrs.is_empty() {
+        eprintln!("(empty)");
+    }
+    for (i, ptr) in ptrs.iter().enumerate() {
+        eprintln!("{}: {}", i, describe(&gc.at(*ptr)));
+    }
+}
+
+// Values don't point to each other yet, so there are no outgoing pointers to show.
+// Small ints live in their pointers, so they never show up here
+fn print_heap(state: &State) {
+    let live = gc::reachable(state);
+    for (i, value) in state.gc.iter().filter(|(i, _)| live[*i]) {
+        eprintln!("#{}: {} {}", i, value.kind(), describe(value));
+    }
+    let count = live.iter().filter(|live| **live).count();
+    eprintln!("{} live object(s), {} in the arena", count, state.gc.len());
+}
+
+pub(crate) fn describe(value: &Value) -> String {
+    match value {
+        Value::IntVal(i) => i.to_string(),
+        Value::StrVal(s) => format!("{:?}", s),
+        Value::ModuleFnRef(ns, name) => format!("&{}.{}", format_module_name(ns), name),
+        Value::ThwartPtr(i) => format!("<thwart {}>", i),
+        Value::WeakRef(Some(ptr)) => match ptr.heap() {
+            Some(i) => format!("<weak #{}>", i),
+            None => "<weak int>".to_string(),
+        },
+        Value::WeakRef(None) => "<weak, collected>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm;
+    use opt;
+    use super::*;
+
+    #[test]
+    fn breakpoints_go_by_the_instructions_as_read() {
+        let mut modules = asm::modules(&["
+module Main
+
+fn MAIN
+    PushInt 0
+    Unless skip
+    PushInt 4
+skip:
+    LoadGlobal twice
+    Call 1
+
+fn twice 1
+    LoadLocal 0
+    LoadLocal 0
+    LoadName Prelude.+
+    Call 2
+"]);
+        opt::inline(&mut modules);
+        opt::peephole(&mut modules);
+        let main = &modules[&vec!("Main".to_string())];
+        // Jump, PushInt, StoreLocal, then twice's body
+        assert_eq!(main.functions["MAIN"].len(), 7);
+        let frame = |ip| Frame { module: main, fun: "MAIN".to_string(), ip, locals: vec!() };
+
+        // MAIN@1 went, the Jump it became the one the PushInt before was folded into
+        assert!(is_at("MAIN", 0, &frame(0)) && is_at("Main.MAIN", 1, &frame(0)));
+        assert!(is_at("MAIN", 2, &frame(1)));
+        assert!(is_at("MAIN", 3, &frame(2)) && is_at("MAIN", 4, &frame(2)));
+        assert!(is_at("twice", 0, &frame(3)) && is_at("Main.twice", 3, &frame(6)));
+        assert!(!is_at("MAIN", 3, &frame(3)) && !is_at("twice", 0, &frame(2)));
+        assert_eq!(moved_from(main, "MAIN", 4), " (was Main.twice@1)");
+        assert_eq!(moved_from(main, "MAIN", 0), " (was Main.MAIN@1)");
+        assert_eq!(moved_from(main, "twice", 0), "");
+
+        // Functions nothing rewrote are as they were
+        let twice = Frame { module: main, fun: "twice".to_string(), ip: 2, locals: vec!() };
+        assert!(is_at("twice", 2, &twice) && !is_at("twice", 1, &twice));
+    }
+}
@@ -0,0 +1,74 @@
+// Errors as JSON for tools, with `--error-format json`. Each is one line on stderr:
+//
+//     {"code":"stack-underflow","message":"Main.MAIN@2: needs 2 value(s) on the stack, only 1 there",
+//      "module":["Main"],"function":"MAIN","ip":2,"span":{"file":"main.undo","line":3,"column":5}}
+//
+// Anything not known is null: load errors have no module, a module without a source map no span
+use std::collections::VecDeque;
+use serde::Serialize;
+use link::LinkError;
+use vm::{Frame, Location};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diagnostic {
+    // What kind of error, stable for tools to match on, unlike the message
+    pub code: &'static str,
+    pub message: String,
+    pub module: Option<Vec<String>>,
+    pub function: Option<String>,
+    pub ip: Option<usize>,
+    pub span: Option<Location>,
+}
+
+impl Diagnostic {
+    // Not about any module in particular, like a file that couldn't be read, the message says which
+    pub fn new(code: &'static str, message: String) -> Self {
+        Diagnostic { code, message, module: None, function: None, ip: None, span: None }
+    }
+
+    // Where the innermost frame is when the program stops
+    pub(crate) fn runtime(code: &'static str, message: String, frames: &VecDeque<Frame>) -> Self {
+        let frame = frames.back();
+        Diagnostic {
+            code,
+            message,
+            module: frame.map(|frame| frame.module.name.clone()),
+            function: frame.map(|frame| frame.fun.to_string()),
+            ip: frame.map(|frame| frame.ip),
+            span: frame.and_then(|frame| frame.module.location(&frame.fun, frame.ip)).cloned(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl From<&LinkError> for Diagnostic {
+    fn from(err: &LinkError) -> Self {
+        Diagnostic {
+            code: err.kind.code(),
+            message: err.to_string(),
+            module: Some(err.module.clone()),
+            function: err.function.clone(),
+            ip: err.ip,
+            span: err.span.clone(),
+        }
+    }
+}
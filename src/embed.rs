@@ -0,0 +1,591 @@
+// Running modules from a Rust program, as a scripting engine. Unlike `vm::run` it doesn't say what
+// it's doing, warnings go to the output and a trap comes back as an error instead of unwinding:
+//
+//     let vm = VmBuilder::new()
+//         .module(bc::load("main.bc.json")?)
+//         .max_frames(1000)
+//         .heap_limit(64 << 20)
+//         .build()?;
+//     let status = vm.run()?;
+//
+// The first module added is the one to run, unless `entry` says otherwise.
+//
+// The program calls back into Rust through the Host namespace. After
+// `.register("MyApp::query", |args| ...)` it can `LoadName Host.MyApp.query` and call that like any
+// function, the first argument being the last pushed. With `register_fn` the arguments and result
+// are converted, a tuple standing for the arguments:
+//
+//     .register_fn("MyApp::repeat", |(text, times): (String, Option<usize>)| {
+//         Ok(text.repeat(times.unwrap_or(2)))
+//     })
+//
+// The other way round, `vm.call::<_, String>("Main::greet", ("world",))` runs one function of the
+// program to what it returns.
+//
+// Only ints, strings and function refs convert. The VM has no lists, tuples, options or ADTs, so
+// there's no value a Vec, tuple, Option or enum could become: tuples and Vecs only stand for a
+// whole argument list, and Option for an argument at the end that may be left out.
+//
+// What the program prints goes to stdout and warnings to stderr, unless `.output(...)` says where
+// instead. Only what a config asks for, like `trace` or `timings`, is still written to stderr
+//
+// examples/host.rs has it all together, with a plugin calling back into its host and traps
+// handled
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use bc::{self, Bundle};
+use diagnostic::Diagnostic;
+use gc::{Ptr, GC};
+use link::{LinkError, LinkErrorKind};
+use mangle;
+use vm::{self, format_module_name, Module, VmConfig};
+
+// What host functions take and return. Weak refs stay in the VM
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    // A function's module and name, to hand back to the program
+    Function(Vec<String>, String),
+}
+
+impl Value {
+    // As the VM's type errors say it
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "Int",
+            Value::Str(_) => "Str",
+            Value::Function(..) => "FnRef",
+        }
+    }
+
+    // To keep on disk and read back in a later run, see `bc::encode_value`
+    pub fn encode(&self) -> Vec<u8> {
+        bc::encode_value(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+        bc::decode_value(bytes)
+    }
+
+    pub(crate) fn from_vm(value: &vm::Value) -> Option<Self> {
+        match value {
+            vm::Value::IntVal(n) => Some(Value::Int(*n)),
+            vm::Value::StrVal(s) => Some(Value::Str(s.clone())),
+            vm::Value::ModuleFnRef(module, name) => Some(Value::Function(module.clone(), name.clone())),
+            vm::Value::ThwartPtr(_) | vm::Value::WeakRef(_) => None,
+        }
+    }
+
+    pub(crate) fn into_vm(self, gc: &mut GC) -> Ptr {
+        match self {
+            Value::Int(n) => gc.int(n),
+            Value::Str(s) => gc.alloc(vm::Value::StrVal(s)),
+            Value::Function(module, name) => gc.alloc(vm::Value::ModuleFnRef(module, name)),
+        }
+    }
+}
+
+// Fails for what doesn't fit, like a u64 past i64::MAX
+pub trait IntoUndoValue {
+    fn into_undo_value(self) -> Result<Value, String>;
+}
+
+pub trait FromUndoValue: Sized {
+    fn from_undo_value(value: &Value) -> Result<Self, String>;
+}
+
+impl IntoUndoValue for Value {
+    fn into_undo_value(self) -> Result<Value, String> {
+        Ok(self)
+    }
+}
+
+impl FromUndoValue for Value {
+    fn from_undo_value(value: &Value) -> Result<Self, String> {
+        Ok(value.clone())
+    }
+}
+
+fn expected<T>(kind: &str, value: &Value) -> Result<T, String> {
+    Err(format!("expected {}, got {}", kind, value.kind()))
+}
+
+// Ints of any width, those that don't fit are an error rather than wrapping
+macro_rules! int_conversions {
+    ($($int:ty),*) => {
+        $(
+            impl IntoUndoValue for $int {
+                fn into_undo_value(self) -> Result<Value, String> {
+                    i64::try_from(self).map(Value::Int).map_err(|_| format!("{} doesn't fit in an Int", self))
+                }
+            }
+
+            impl FromUndoValue for $int {
+                fn from_undo_value(value: &Value) -> Result<Self, String> {
+                    match value {
+                        Value::Int(n) => <$int>::try_from(*n)
+                            .map_err(|_| format!("{} doesn't fit in {}", n, stringify!($int))),
+                        _ => expected("Int", value),
+                    }
+                }
+            }
+        )*
+    }
+}
+
+int_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+// 1 and 0, any other int is true too, as for `Unless`
+impl IntoUndoValue for bool {
+    fn into_undo_value(self) -> Result<Value, String> {
+        Ok(Value::Int(self as i64))
+    }
+}
+
+impl FromUndoValue for bool {
+    fn from_undo_value(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::Int(n) => Ok(*n != 0),
+            _ => expected("Int", value),
+        }
+    }
+}
+
+impl IntoUndoValue for String {
+    fn into_undo_value(self) -> Result<Value, String> {
+        Ok(Value::Str(self))
+    }
+}
+
+impl IntoUndoValue for &str {
+    fn into_undo_value(self) -> Result<Value, String> {
+        Ok(Value::Str(self.to_string()))
+    }
+}
+
+impl FromUndoValue for String {
+    fn from_undo_value(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::Str(s) => Ok(s.clone()),
+            _ => expected("Str", value),
+        }
+    }
+}
+
+// What a host function that's only there for what it does returns
+impl IntoUndoValue for () {
+    fn into_undo_value(self) -> Result<Value, String> {
+        Ok(Value::Int(0))
+    }
+}
+
+// One argument, None if it was left out
+pub trait FromUndoArg: Sized {
+    fn from_undo_arg(arg: Option<&Value>) -> Result<Self, String>;
+}
+
+impl<T: FromUndoValue> FromUndoArg for T {
+    fn from_undo_arg(arg: Option<&Value>) -> Result<Self, String> {
+        arg.ok_or_else(|| "missing".to_string()).and_then(T::from_undo_value)
+    }
+}
+
+// Arguments at the end may be left out
+impl<T: FromUndoValue> FromUndoArg for Option<T> {
+    fn from_undo_arg(arg: Option<&Value>) -> Result<Self, String> {
+        arg.map(T::from_undo_value).transpose()
+    }
+}
+
+// All the arguments: a tuple with one element per argument, or a Vec for any number of them
+pub trait FromUndoArgs: Sized {
+    fn from_undo_args(args: &[Value]) -> Result<Self, String>;
+}
+
+impl<T: FromUndoValue> FromUndoArgs for Vec<T> {
+    fn from_undo_args(args: &[Value]) -> Result<Self, String> {
+        args.iter().enumerate()
+            .map(|(i, arg)| T::from_undo_value(arg).map_err(|err| format!("argument {}: {}", i + 1, err)))
+            .collect()
+    }
+}
+
+macro_rules! tuple_args {
+    ($len:expr; $($arg:ident $idx:tt),*) => {
+        impl<$($arg: FromUndoArg),*> FromUndoArgs for ($($arg,)*) {
+            #[allow(unused_variables)]
+            fn from_undo_args(args: &[Value]) -> Result<Self, String> {
+                if args.len() > $len {
+                    return Err(format!("takes at most {} argument(s), got {}", $len, args.len()));
+                }
+                Ok(($(
+                    $arg::from_undo_arg(args.get($idx)).map_err(|err| format!("argument {}: {}", $idx + 1, err))?,
+                )*))
+            }
+        }
+    }
+}
+
+tuple_args!(0;);
+tuple_args!(1; A 0);
+tuple_args!(2; A 0, B 1);
+tuple_args!(3; A 0, B 1, C 2);
+tuple_args!(4; A 0, B 1, C 2, D 3);
+tuple_args!(5; A 0, B 1, C 2, D 3, E 4);
+tuple_args!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+
+// Arguments to pass, like FromUndoArgs
+pub trait IntoUndoArgs {
+    fn into_undo_args(self) -> Result<Vec<Value>, String>;
+}
+
+impl<T: IntoUndoValue> IntoUndoArgs for Vec<T> {
+    fn into_undo_args(self) -> Result<Vec<Value>, String> {
+        self.into_iter().enumerate()
+            .map(|(i, arg)| arg.into_undo_value().map_err(|err| format!("argument {}: {}", i + 1, err)))
+            .collect()
+    }
+}
+
+macro_rules! tuple_into_args {
+    ($($arg:ident $idx:tt),*) => {
+        impl<$($arg: IntoUndoValue),*> IntoUndoArgs for ($($arg,)*) {
+            fn into_undo_args(self) -> Result<Vec<Value>, String> {
+                Ok(vec!($(
+                    self.$idx.into_undo_value().map_err(|err| format!("argument {}: {}", $idx + 1, err))?
+                ),*))
+            }
+        }
+    }
+}
+
+tuple_into_args!();
+tuple_into_args!(A 0);
+tuple_into_args!(A 0, B 1);
+tuple_into_args!(A 0, B 1, C 2);
+tuple_into_args!(A 0, B 1, C 2, D 3);
+tuple_into_args!(A 0, B 1, C 2, D 3, E 4);
+tuple_into_args!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+// Where `print` goes, each value printed being a line. Warnings are what goes wrong without
+// stopping the program, like a snapshot that couldn't be written
+pub trait Output: Send + Sync {
+    fn print(&self, line: &str);
+
+    fn warn(&self, line: &str) {
+        eprintln!("{}", line);
+    }
+}
+
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn print(&self, line: &str) {
+        println!("{}", line);
+    }
+}
+
+// Keeps the lines and warnings, for whoever ran the program to look at
+#[derive(Default)]
+pub struct Captured {
+    lines: Mutex<Vec<String>>,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl Captured {
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+
+    // The lines so far, leaving none
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.lines.lock().unwrap())
+    }
+}
+
+impl Output for Captured {
+    fn print(&self, line: &str) {
+        self.lines.lock().unwrap().push(line.to_string());
+    }
+
+    fn warn(&self, line: &str) {
+        self.warnings.lock().unwrap().push(line.to_string());
+    }
+}
+
+// The error traps, with the function's name in front
+pub type HostFunction = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+// By module, always in the Host namespace, and name
+pub type HostFunctions = HashMap<(Vec<String>, String), HostFunction>;
+
+#[derive(Debug)]
+pub enum VmError {
+    Link(LinkError),
+    // The program trapped or went over a limit, and where it was
+    Trap(Diagnostic),
+    // An argument to a called function that doesn't convert
+    Argument(String),
+    // A called function returned nothing, or not what it was asked for
+    Return(String),
+    // The config's snapshot to resume from can't be
+    Resume(String),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Link(err) => write!(f, "Cannot link: {}", err),
+            VmError::Trap(diagnostic) => write!(f, "{}", diagnostic.message),
+            VmError::Argument(err) | VmError::Return(err) | VmError::Resume(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<LinkError> for VmError {
+    fn from(err: LinkError) -> Self {
+        VmError::Link(err)
+    }
+}
+
+pub struct VmBuilder {
+    modules: HashMap<Vec<String>, Module>,
+    entry: Option<Vec<String>>,
+    config: VmConfig,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        VmBuilder::new()
+    }
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        VmBuilder {
+            modules: HashMap::new(),
+            entry: None,
+            config: VmConfig { quiet: true, ..VmConfig::default() },
+        }
+    }
+
+    // One with the same name as a module already added replaces it
+    pub fn module(mut self, module: Module) -> Self {
+        self.entry.get_or_insert_with(|| module.name.clone());
+        self.modules.insert(module.name.clone(), module);
+        self
+    }
+
+    pub fn modules<I: IntoIterator<Item = Module>>(self, modules: I) -> Self {
+        modules.into_iter().fold(self, VmBuilder::module)
+    }
+
+    // Its modules, the bundle's entry being the one to run if none was added before
+    pub fn bundle(mut self, bundle: Bundle) -> Self {
+        self.entry.get_or_insert(bundle.entry);
+        self.modules(bundle.modules)
+    }
+
+    // The module to run
+    pub fn entry(mut self, module: &[String]) -> Self {
+        self.entry = Some(module.to_vec());
+        self
+    }
+
+    // The function to run in it, instead of the one it says
+    pub fn entrypoint(mut self, function: &str) -> Self {
+        self.config.entrypoint = Some(function.to_string());
+        self
+    }
+
+    // Bytes the heap may take once collected
+    pub fn heap_limit(mut self, bytes: usize) -> Self {
+        self.config.heap_limit = Some(bytes);
+        self
+    }
+
+    // Objects the heap may hold once collected
+    pub fn max_heap(mut self, objects: usize) -> Self {
+        self.config.max_heap = Some(objects);
+        self
+    }
+
+    pub fn max_frames(mut self, frames: usize) -> Self {
+        self.config.max_frames = Some(frames);
+        self
+    }
+
+    // Instructions the at_exit hooks get to run
+    pub fn exit_fuel(mut self, instructions: usize) -> Self {
+        self.config.exit_fuel = instructions;
+        self
+    }
+
+    // Lets functions that need `feature` link as they are, rather than as stubs that trap
+    pub fn feature(mut self, feature: &str) -> Self {
+        self.config.features.push(feature.to_string());
+        self
+    }
+
+    // Everything else there is to tune, as the command line would. Replaces the limits and host
+    // functions set so far. `eliminate_dead_code` is ignored, `call` may name any function later
+    pub fn config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // `name` is the function's path under Host, like `MyApp::query` for `Host.MyApp.query`.
+    // Registering a name again replaces the function
+    pub fn register<F>(mut self, name: &str, function: F) -> Self
+        where F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static
+    {
+        let mut path: Vec<String> = name.split("::").map(String::from).collect();
+        let fun = path.pop().unwrap();
+        let module = Some("Host".to_string()).into_iter().chain(path).collect();
+        self.config.host.insert((module, fun), Arc::new(function));
+        self
+    }
+
+    // Like `register`, with the arguments and result converted
+    pub fn register_fn<A, R, F>(self, name: &str, function: F) -> Self
+        where A: FromUndoArgs, R: IntoUndoValue, F: Fn(A) -> Result<R, String> + Send + Sync + 'static
+    {
+        self.register(name, move |args| function(A::from_undo_args(args)?).and_then(R::into_undo_value))
+    }
+
+    pub fn output(mut self, output: Arc<dyn Output>) -> Self {
+        self.config.output = output;
+        self
+    }
+
+    // Links the modules, so they're ready to run
+    pub fn build(self) -> Result<Vm, LinkError> {
+        let VmBuilder { mut modules, entry, config } = self;
+        let config = VmConfig { eliminate_dead_code: false, ..config };
+        let main = entry.unwrap_or_default();
+        vm::prepare(&main, &mut modules, &config, &mut |_| Ok(None))?;
+        Ok(Vm { main, modules: Arc::new(modules), config })
+    }
+}
+
+// Linked modules, to run as many times as need be. Each run starts from a fresh heap, nothing one
+// leaves behind is there for the next
+pub struct Vm {
+    main: Vec<String>,
+    // Shared with forks, linked modules never change
+    modules: Arc<HashMap<Vec<String>, Module>>,
+    config: VmConfig,
+}
+
+impl Vm {
+    // What the entrypoint returns, if it's an int, as for `vm::run`
+    pub fn run(&self) -> Result<Option<i64>, VmError> {
+        self.trapping(|| vm::run_main(self.main.clone(), &self.modules, &self.config))?.map_err(VmError::Resume)
+    }
+
+    // `function` is `Module::Path::fn`, or just `fn` in the entry module. It's picked among the
+    // overloads by how many arguments there are
+    pub fn call<A: IntoUndoArgs, T: FromUndoValue>(&self, function: &str, args: A) -> Result<T, VmError> {
+        let mut module: Vec<String> = function.split("::").map(String::from).collect();
+        let name = module.pop().unwrap();
+        if module.is_empty() {
+            module = self.main.clone();
+        }
+        let args = args.into_undo_args().map_err(|err| VmError::Argument(format!("{}: {}", function, err)))?;
+        let missing = |module: &[String], name: String| LinkError {
+            module: module.to_vec(),
+            function: None,
+            ip: None,
+            span: None,
+            kind: LinkErrorKind::NoSuchFunction(module.to_vec(), name),
+        };
+        let target = self.modules.get(&module).ok_or_else(|| missing(&module, name.clone()))?;
+        let fun = mangle::resolve(target, &name, args.len()).ok_or_else(|| missing(&module, mangle::mangle(&name, args.len())))?;
+        if let Some(arity) = target.arities.get(fun).filter(|arity| **arity != args.len()) {
+            let callee = format!("{}.{}", format_module_name(&module), fun);
+            return Err(VmError::Link(LinkError {
+                module: module.clone(),
+                function: None,
+                ip: None,
+                span: None,
+                kind: LinkErrorKind::ArityMismatch(callee, *arity, args.len()),
+            }));
+        }
+        let returned = self.trapping(|| vm::call(target, fun, args, &self.modules, &self.config))?;
+        let returned = returned.ok_or_else(|| VmError::Return(format!("{}.{} returned nothing", format_module_name(&module), fun)))?;
+        T::from_undo_value(&returned).map_err(|err| VmError::Return(format!("{}.{}: {}", format_module_name(&module), fun, err)))
+    }
+
+    // Traps as errors
+    fn trapping<T, F: FnOnce() -> T>(&self, run: F) -> Result<T, VmError> {
+        panic::catch_unwind(AssertUnwindSafe(run)).map_err(|payload| {
+            let diagnostic = payload.downcast::<Diagnostic>().map(|diagnostic| *diagnostic).unwrap_or_else(|payload| {
+                // Panicking before it ever got to an instruction, like with an unknown GC strategy
+                let message = payload.downcast_ref::<String>().cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|message| message.to_string()))
+                    .unwrap_or_else(|| "trap".to_string());
+                Diagnostic::new("trap", message)
+            });
+            VmError::Trap(diagnostic)
+        })
+    }
+
+    // Another Vm with the same modules, without copying them, to speculatively run things in and
+    // throw away. Since runs don't share a heap either, all it needs of its own is the config, like
+    // what the program prints going somewhere else with `set_output`
+    pub fn fork(&self) -> Vm {
+        Vm { main: self.main.clone(), modules: self.modules.clone(), config: self.config.clone() }
+    }
+
+    pub fn set_output(&mut self, output: Arc<dyn Output>) {
+        self.config.output = output;
+    }
+
+    pub fn entry(&self) -> &[String] {
+        &self.main
+    }
+
+    pub fn module(&self, name: &[String]) -> Option<&Module> {
+        self.modules.get(name)
+    }
+
+    // As linked, after whichever passes the config has on
+    pub fn modules(&self) -> &HashMap<Vec<String>, Module> {
+        &self.modules
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ints_that_dont_fit_are_errors() {
+        assert_eq!(u64::MAX.into_undo_value(), Err("18446744073709551615 doesn't fit in an Int".to_string()));
+        assert_eq!((i64::MAX as u64).into_undo_value(), Ok(Value::Int(i64::MAX)));
+        assert_eq!(u8::from_undo_value(&Value::Int(256)), Err("256 doesn't fit in u8".to_string()));
+        assert_eq!((1, usize::MAX).into_undo_args(), Err(format!("argument 2: {} doesn't fit in an Int", usize::MAX)));
+    }
+
+    #[test]
+    fn arguments_convert_in_order() {
+        let args = vec!(Value::Str("ab".to_string()), Value::Int(3));
+        assert_eq!(<(String, Option<u8>)>::from_undo_args(&args), Ok(("ab".to_string(), Some(3))));
+        assert_eq!(<(String, Option<u8>)>::from_undo_args(&args[..1]), Ok(("ab".to_string(), None)));
+        assert_eq!(<(String, u8)>::from_undo_args(&args[..1]), Err("argument 2: missing".to_string()));
+        assert_eq!(<(i64,)>::from_undo_args(&args), Err("takes at most 1 argument(s), got 2".to_string()));
+        assert_eq!(Vec::<i64>::from_undo_args(&args), Err("argument 1: expected Int, got Str".to_string()));
+    }
+}
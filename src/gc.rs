@@ -0,0 +1,439 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::mem::{self, size_of};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use vm::{Checkpoint, Frame, State, Value};
+
+// TODO we shouldn't have a single value type
+pub(crate) struct GC {
+    arena: Vec<Value>,
+    // Slots a non-moving collector freed, for `alloc` to reuse
+    free: Vec<usize>,
+    // Objects allocated since the last collection
+    allocated: usize,
+    // What the objects take, in bytes. Counted again on collecting, in between it only grows, so
+    // it's more than there is once an incremental collector freed some
+    size: usize,
+    strategy: Box<dyn GcStrategy>,
+    // Collections so far, and how many bytes the last one freed
+    collections: usize,
+    last_reclaimed: usize,
+    // Every time the program stopped for the collector, a whole collection or an incremental slice
+    pauses: usize,
+    longest_pause: Duration,
+    total_pause: Duration,
+}
+
+// Snapshots only hold the arena
+impl Serialize for GC {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.arena.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GC {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(GC::from_arena)
+    }
+}
+
+// TODO 2nd arena
+// A single tagged word: ints that fit in 63 bits are stored inline, shifted left with the low bit
+// set, and never touch the arena. Otherwise it's an arena slot, shifted left with the low bit clear
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Ptr(u64);
+
+const INT_MIN: i64 = i64::MIN >> 1;
+const INT_MAX: i64 = i64::MAX >> 1;
+
+impl Ptr {
+    fn slot(i: usize) -> Ptr {
+        Ptr((i as u64) << 1)
+    }
+
+    fn inline_int(self) -> Option<i64> {
+        if self.0 & 1 == 1 { Some(self.0 as i64 >> 1) } else { None }
+    }
+
+    pub(crate) fn heap(self) -> Option<usize> {
+        if self.0 & 1 == 0 { Some((self.0 >> 1) as usize) } else { None }
+    }
+}
+
+impl GC {
+    pub(crate) fn at(&self, ptr: Ptr) -> Cow<'_, Value> {
+        match ptr.heap() {
+            Some(i) => Cow::Borrowed(self.raw_at(i)),
+            None => Cow::Owned(Value::IntVal(ptr.inline_int().unwrap())),
+        }
+    }
+
+    fn raw_at(&self, i: usize) -> &Value {
+        self.arena.get(i).unwrap()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.arena.len()
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.arena.truncate(len);
+        self.free.retain(|i| *i < len);
+        self.size = self.bytes();
+    }
+
+    // Objects in the arena, minus the free slots
+    pub(crate) fn occupied(&self) -> usize {
+        self.arena.len() - self.free.len()
+    }
+
+    pub(crate) fn set_strategy(&mut self, strategy: Box<dyn GcStrategy>) {
+        self.strategy = strategy;
+    }
+
+    pub(crate) fn collections(&self) -> usize {
+        self.collections
+    }
+
+    pub(crate) fn reserve(&mut self, objects: usize) {
+        self.arena.reserve(objects);
+    }
+
+    pub(crate) fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, &Value)> {
+        self.arena.iter().enumerate()
+    }
+
+    // Only the ints too big for a tagged word get allocated
+    pub(crate) fn int(&mut self, n: i64) -> Ptr {
+        if (INT_MIN..=INT_MAX).contains(&n) {
+            Ptr(((n << 1) | 1) as u64)
+        } else {
+            self.alloc(Value::IntVal(n))
+        }
+    }
+
+    pub(crate) fn alloc(&mut self, v: Value) -> Ptr {
+        self.allocated += 1;
+        self.size += value_size(&v);
+        if let Some(i) = self.free.pop() {
+            self.arena[i] = v;
+            return Ptr::slot(i);
+        }
+        self.arena.push(v);
+        Ptr::slot(self.arena.len() - 1)
+    }
+
+    pub(crate) fn new() -> Self {
+        GC::from_arena(Vec::new())
+    }
+
+    fn from_arena(arena: Vec<Value>) -> Self {
+        GC {
+            size: arena.iter().map(value_size).sum(),
+            arena,
+            free: vec!(),
+            allocated: 0,
+            strategy: Box::new(NoGc),
+            collections: 0,
+            last_reclaimed: 0,
+            pauses: 0,
+            longest_pause: Duration::default(),
+            total_pause: Duration::default(),
+        }
+    }
+
+    // Free slots hold a placeholder int, so they only count for its size
+    fn bytes(&self) -> usize {
+        self.arena.iter().map(value_size).sum::<usize>() - self.free.len() * size_of::<Value>()
+    }
+
+    fn paused(&mut self, since: Instant) {
+        let pause = since.elapsed();
+        self.pauses += 1;
+        self.longest_pause = self.longest_pause.max(pause);
+        self.total_pause += pause;
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    size_of::<Value>() + match value {
+        Value::StrVal(s) => s.len(),
+        Value::ModuleFnRef(ns, name) => ns.iter().map(String::len).sum::<usize>() + name.len(),
+        Value::IntVal(_) | Value::ThwartPtr(_) | Value::WeakRef(_) => 0,
+    }
+}
+
+pub(crate) struct HeapStats {
+    live: usize,
+    live_bytes: BTreeMap<&'static str, usize>,
+    capacity: usize,
+    collections: usize,
+    last_reclaimed: usize,
+    pauses: usize,
+    longest_pause: Duration,
+    total_pause: Duration,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "live objects: {}", self.live)?;
+        for (kind, bytes) in &self.live_bytes {
+            writeln!(f, "  {}: {} bytes", kind, bytes)?;
+        }
+        writeln!(f, "arena capacity: {} objects", self.capacity)?;
+        writeln!(f, "collections: {}", self.collections)?;
+        writeln!(f, "reclaimed by the last one: {} bytes", self.last_reclaimed)?;
+        write!(f, "pauses: {}, longest {:?}, total {:?}", self.pauses, self.longest_pause, self.total_pause)
+    }
+}
+
+pub(crate) fn stats(state: &State) -> HeapStats {
+    let live = reachable(state);
+    let mut live_bytes = BTreeMap::new();
+    for (_, value) in state.gc.iter().filter(|(i, _)| live[*i]) {
+        *live_bytes.entry(value.kind()).or_insert(0) += value_size(value);
+    }
+    HeapStats {
+        live: live.iter().filter(|live| **live).count(),
+        live_bytes,
+        capacity: state.gc.arena.capacity(),
+        collections: state.gc.collections,
+        last_reclaimed: state.gc.last_reclaimed,
+        pauses: state.gc.pauses,
+        longest_pause: state.gc.longest_pause,
+        total_pause: state.gc.total_pause,
+    }
+}
+
+// A way of reclaiming the arena, picked with `--gc`
+pub(crate) trait GcStrategy {
+    // Frees whatever `roots` can't reach, rewriting them if anything moves
+    // An incremental collector may leave part of the work for `slice`
+    fn collect(&mut self, gc: &mut GC, roots: Vec<&mut Ptr>);
+
+    // Does a bounded amount of leftover work, returns whether there was any
+    fn slice(&mut self, _gc: &mut GC) -> bool {
+        false
+    }
+}
+
+pub const STRATEGIES: &[&str] = &["copying", "mark-sweep", "incremental", "none"];
+
+// `slice` is how many slots the incremental collector sweeps per instruction
+pub(crate) fn strategy(name: &str, slice: usize) -> Option<Box<dyn GcStrategy>> {
+    match name {
+        "copying" => Some(Box::new(Copying)),
+        "mark-sweep" => Some(Box::new(MarkSweep)),
+        "incremental" => Some(Box::new(Incremental { live: vec!(), swept: 0, slice: slice.max(1) })),
+        "none" => Some(Box::new(NoGc)),
+        _ => None,
+    }
+}
+
+// Every pointer the program can still reach: the value stack, locals, what checkpoints saved,
+// and interned strings. Takes the state apart so the interpreter can call it mid-instruction
+pub(crate) fn roots<'s>(
+    stack: &'s mut [Ptr],
+    frames: &'s mut VecDeque<Frame>,
+    checkpoints: &'s mut [Checkpoint],
+    interned: &'s mut HashMap<Vec<String>, Vec<Option<Ptr>>>,
+) -> Vec<&'s mut Ptr> {
+    let mut roots: Vec<&mut Ptr> = stack.iter_mut().collect();
+    roots.extend(frames.iter_mut().flat_map(|frame| frame.locals.iter_mut()));
+    for checkpoint in checkpoints.iter_mut() {
+        roots.extend(checkpoint.stack.iter_mut());
+        roots.extend(checkpoint.frames.iter_mut().flat_map(|frame| frame.locals.iter_mut()));
+    }
+    roots.extend(interned.values_mut().flat_map(|slots| slots.iter_mut().flatten()));
+    roots
+}
+
+// Runs the GC's strategy, keeping count for `stats`. Returns how many objects it freed.
+// Unless `full`, an incremental collector only starts here and finishes over later slices
+pub(crate) fn collect(gc: &mut GC, roots: Vec<&mut Ptr>, full: bool) -> usize {
+    let started = Instant::now();
+    let (bytes, objects) = (gc.bytes(), gc.occupied());
+    let mut strategy = mem::replace(&mut gc.strategy, Box::new(NoGc));
+    strategy.collect(gc, roots);
+    while full && strategy.slice(gc) {}
+    gc.strategy = strategy;
+    gc.allocated = 0;
+    gc.collections += 1;
+    gc.size = gc.bytes();
+    gc.last_reclaimed = bytes.saturating_sub(gc.size);
+    gc.paused(started);
+    objects.saturating_sub(gc.occupied())
+}
+
+pub(crate) fn collect_state(state: &mut State, full: bool) -> usize {
+    let State { gc, stack, frames, checkpoints, interned, .. } = state;
+    collect(gc, roots(stack, frames, checkpoints, interned), full)
+}
+
+// Lets an incremental collector get on with its current cycle, returns whether it did anything
+pub(crate) fn slice(gc: &mut GC) -> bool {
+    let started = Instant::now();
+    let mut strategy = mem::replace(&mut gc.strategy, Box::new(NoGc));
+    let worked = strategy.slice(gc);
+    gc.strategy = strategy;
+    if worked {
+        gc.paused(started);
+    }
+    worked
+}
+
+// Shrinks the arena back to `len` objects, for the history.
+// Constants interned since then will need allocating again
+pub(crate) fn truncate(state: &mut State, len: usize) {
+    state.gc.truncate(len);
+    for slot in state.interned.values_mut().flat_map(|slots| slots.iter_mut()) {
+        if slot.and_then(Ptr::heap).is_some_and(|i| i >= len) {
+            *slot = None;
+        }
+    }
+}
+
+// Which arena slots `roots` can reach
+pub(crate) fn reachable(state: &State) -> Vec<bool> {
+    let frames = state.frames.iter().chain(state.checkpoints.iter().flat_map(|checkpoint| checkpoint.frames.iter()));
+    let stacks = state.stack.iter().chain(state.checkpoints.iter().flat_map(|checkpoint| checkpoint.stack.iter()));
+    let interned = state.interned.values().flat_map(|slots| slots.iter().flatten());
+    mark(state.gc.len(), stacks.chain(frames.flat_map(|frame| frame.locals.iter())).chain(interned))
+}
+
+fn mark<'p>(arena_len: usize, roots: impl Iterator<Item = &'p Ptr>) -> Vec<bool> {
+    let mut live = vec![false; arena_len];
+    for ptr in roots {
+        if let Some(i) = ptr.heap() {
+            live[i] = true;
+        }
+    }
+    live
+}
+
+// Never frees anything, the arena only grows
+struct NoGc;
+
+impl GcStrategy for NoGc {
+    fn collect(&mut self, _gc: &mut GC, _roots: Vec<&mut Ptr>) {}
+}
+
+// Copies everything reachable to a fresh arena, leaving a ThwartPtr behind so values reachable
+// from several roots are only copied once
+struct Copying;
+
+impl GcStrategy for Copying {
+    fn collect(&mut self, gc: &mut GC, roots: Vec<&mut Ptr>) {
+        let mut to_space: Vec<Value> = vec!();
+        for ptr in roots {
+            let i = match ptr.heap() {
+                Some(i) => i,
+                None => continue,
+            };
+            match gc.raw_at(i) {
+                Value::ThwartPtr(new) => *ptr = Ptr::slot(*new), // Rewrite ptr
+                _ => {
+                    // TODO traverse into the value once values can point to others
+                    let value = mem::replace(&mut gc.arena[i], Value::ThwartPtr(to_space.len()));
+                    to_space.push(value);
+                    *ptr = Ptr::slot(to_space.len() - 1);
+                }
+            }
+        }
+        let from_space = mem::replace(&mut gc.arena, to_space);
+        gc.free.clear();
+        // Weak refs follow their target if it got copied, and are cleared otherwise
+        for value in gc.arena.iter_mut() {
+            if let Value::WeakRef(Some(target)) = value {
+                if let Some(i) = target.heap() {
+                    *value = match from_space[i] {
+                        Value::ThwartPtr(new) => Value::WeakRef(Some(Ptr::slot(new))),
+                        _ => Value::WeakRef(None),
+                    };
+                }
+            }
+        }
+    }
+}
+
+// Frees unreachable slots in place for `alloc` to reuse, so pointers stay valid across collections
+struct MarkSweep;
+
+impl GcStrategy for MarkSweep {
+    fn collect(&mut self, gc: &mut GC, roots: Vec<&mut Ptr>) {
+        let live = mark(gc.len(), roots.iter().map(|ptr| &**ptr));
+        clear_weak_refs(gc, &live);
+        gc.free.clear();
+        // Backwards, so the lowest slots get reused first
+        for (i, live) in live.iter().enumerate().rev() {
+            if !live {
+                gc.arena[i] = Value::IntVal(0);
+                gc.free.push(i);
+            }
+        }
+    }
+}
+
+// Weak refs to anything unmarked are cleared before it's freed, so `deref` can't bring it back
+fn clear_weak_refs(gc: &mut GC, live: &[bool]) {
+    for value in gc.arena.iter_mut() {
+        if let Value::WeakRef(Some(target)) = value {
+            if target.heap().is_some_and(|i| !live[i]) {
+                *value = Value::WeakRef(None);
+            }
+        }
+    }
+}
+
+// Mark-sweep, but sweeping `slice` slots per instruction instead of the whole arena at once.
+// Marking is only as much work as there are roots, since values don't point to each other.
+//
+// Values are immutable and unmarked ones can't be reached anymore, so nothing the program does
+// mid-sweep can make an unswept garbage slot live again. Allocations go to slots already swept
+// or past the end of what was marked, and the history never truncates below that since it's
+// reset when marking.
+struct Incremental {
+    live: Vec<bool>,
+    // Slots below this have been swept
+    swept: usize,
+    slice: usize,
+}
+
+impl GcStrategy for Incremental {
+    fn collect(&mut self, gc: &mut GC, roots: Vec<&mut Ptr>) {
+        // Finish the previous cycle, so its garbage doesn't outlive this one's marks
+        while self.slice(gc) {}
+        self.live = mark(gc.len(), roots.iter().map(|ptr| &**ptr));
+        clear_weak_refs(gc, &self.live);
+        // Slots freed before are garbage again for this sweep, don't list them twice
+        gc.free.clear();
+        self.swept = 0;
+    }
+
+    fn slice(&mut self, gc: &mut GC) -> bool {
+        let end = self.live.len().min(gc.len());
+        if self.swept >= end {
+            return false;
+        }
+        let stop = end.min(self.swept + self.slice);
+        for i in self.swept..stop {
+            if !self.live[i] {
+                let value = mem::replace(&mut gc.arena[i], Value::IntVal(0));
+                gc.last_reclaimed += value_size(&value);
+                gc.free.push(i);
+            }
+        }
+        self.swept = stop;
+        true
+    }
+}
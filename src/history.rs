@@ -0,0 +1,354 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use embed;
+use gc::{self, Ptr};
+use vm::{cur_fn, step, Checkpoint, Frame, Instruction, Module, State};
+
+// Instructions between two keyframes, i.e. the longest the journal gets
+const KEYFRAME_INTERVAL: usize = 1000;
+
+// Everything needed to take back a single instruction
+struct Undo<'a> {
+    depth: usize,
+    ip: usize,
+    // Stack height once the instruction popped its operands, and what it popped
+    stack_base: usize,
+    popped: Vec<Ptr>,
+    // Previous value of the local it's going to store to, None if it's initializing it
+    local: Option<(usize, Option<Ptr>)>,
+    arena_len: usize,
+    // The whole frame, if the instruction is a return
+    returned: Option<Frame<'a>>,
+    // Guest checkpoints: how many there were, and the one a Rollback/Commit discards
+    checkpoints_len: usize,
+    discarded: Option<Checkpoint<'a>>,
+    // Stack and frames wholesale, if the instruction is a Rollback
+    rolled_back: Option<Checkpoint<'a>>,
+    at_exit_len: usize,
+    host_calls_len: usize,
+}
+
+// The machine state right before instruction number `step` ran
+struct Keyframe<'a> {
+    step: usize,
+    stack: Vec<Ptr>,
+    frames: VecDeque<Frame<'a>>,
+    checkpoints: Vec<Checkpoint<'a>>,
+    at_exit: Vec<(Vec<String>, String)>,
+    arena_len: usize,
+    host_calls_len: usize,
+}
+
+// What host functions returned, in the order they were called, so replaying gets the same results
+// instead of calling them again. Only kept while the debugger is, back to its oldest keyframe
+#[derive(Default)]
+pub(crate) struct HostCalls {
+    results: VecDeque<embed::Value>,
+    // How many were forgotten from the front, positions count from the first call
+    forgotten: usize,
+    // Where a replay is at, None when running for real
+    replaying: Option<usize>,
+}
+
+impl HostCalls {
+    fn len(&self) -> usize {
+        self.forgotten + self.results.len()
+    }
+
+    // The next result, when replaying calls that were made before
+    pub(crate) fn replayed(&mut self) -> Option<embed::Value> {
+        let at = self.replaying?;
+        let result = self.results.get(at - self.forgotten).cloned();
+        self.replaying = result.as_ref().map(|_| at + 1);
+        result
+    }
+
+    pub(crate) fn record(&mut self, result: &embed::Value) {
+        self.results.push_back(result.clone());
+    }
+
+    // Drops the calls from position `len` on, they've been taken back
+    fn truncate(&mut self, len: usize) {
+        self.results.truncate(len - self.forgotten);
+    }
+
+    // Drops the ones before `len`, there's no going back that far
+    fn forget(&mut self, len: usize) {
+        while self.forgotten < len && self.results.pop_front().is_some() {
+            self.forgotten += 1;
+        }
+    }
+}
+
+fn host_calls_len(state: &State) -> usize {
+    state.host_calls.as_ref().map_or(0, HostCalls::len)
+}
+
+// Reverse execution support: a keyframe every KEYFRAME_INTERVAL instructions, plus a journal
+// of undo entries since the last one. Stepping back within the journal just applies the entries,
+// going further restores an older keyframe and replays forward from it.
+// The oldest keyframes are dropped to stay within `budget` bytes.
+pub(crate) struct History<'a> {
+    modules: &'a HashMap<Vec<String>, Module>,
+    journal: Vec<Undo<'a>>,
+    keyframes: VecDeque<Keyframe<'a>>,
+    // Number of instructions executed so far
+    step: usize,
+    budget: usize,
+    used: usize,
+}
+
+impl<'a> History<'a> {
+    pub(crate) fn new(modules: &'a HashMap<Vec<String>, Module>, budget: usize) -> Self {
+        History {
+            modules,
+            journal: vec!(),
+            keyframes: VecDeque::new(),
+            step: 0,
+            budget,
+            used: 0,
+        }
+    }
+
+    // Must be called right before the current instruction executes
+    pub(crate) fn record(&mut self, state: &mut State<'a>) {
+        if self.step.is_multiple_of(KEYFRAME_INTERVAL) {
+            self.keyframe(state);
+        }
+
+        let frame = state.frames.back().expect("No current frame?!");
+        let instruction = cur_fn(frame.module, frame.fun.to_string()).get(frame.ip);
+        let pops = match instruction {
+            Some(Instruction::StoreLocal(_)) | Some(Instruction::Unless(_)) => 1,
+            Some(Instruction::Call(n)) => n + 1,
+            _ => 0,
+        };
+        let stack_base = state.stack.len().saturating_sub(pops);
+        let local = match instruction {
+            Some(Instruction::StoreLocal(idx)) => Some((*idx, frame.locals.get(*idx).cloned())),
+            _ => None,
+        };
+        let undo = Undo {
+            depth: state.frames.len(),
+            ip: frame.ip,
+            stack_base,
+            popped: state.stack[stack_base..].to_vec(),
+            local,
+            arena_len: state.gc.len(),
+            returned: if instruction.is_none() { Some(frame.clone()) } else { None },
+            checkpoints_len: state.checkpoints.len(),
+            discarded: match instruction {
+                Some(Instruction::Rollback) | Some(Instruction::Commit) => state.checkpoints.last().cloned(),
+                _ => None,
+            },
+            rolled_back: match instruction {
+                Some(Instruction::Rollback) => Some(Checkpoint {
+                    stack: state.stack.clone(),
+                    frames: state.frames.clone(),
+                }),
+                _ => None,
+            },
+            at_exit_len: state.at_exit.len(),
+            host_calls_len: host_calls_len(state),
+        };
+        self.used += undo_size(&undo);
+        self.journal.push(undo);
+        self.step += 1;
+    }
+
+    // Rewinds the state by up to `count` instructions, returns how many were actually undone
+    pub(crate) fn undo(&mut self, state: &mut State<'a>, count: usize) -> usize {
+        if count <= self.journal.len() {
+            for _ in 0..count {
+                let undo = self.journal.pop().unwrap();
+                self.used -= undo_size(&undo);
+                apply(undo, state);
+            }
+            self.step -= count;
+            return count;
+        }
+
+        let oldest = match self.keyframes.front() {
+            Some(keyframe) => keyframe.step,
+            None => return 0,
+        };
+        let target = self.step.saturating_sub(count).max(oldest);
+        let undone = self.step - target;
+        self.restore(state, target);
+        while self.step < target {
+            self.record(state);
+            step(state, self.modules, true);
+        }
+        // Calls past here only happen again if the program gets there again
+        if let Some(host_calls) = state.host_calls.as_mut() {
+            if let Some(at) = host_calls.replaying.take() {
+                host_calls.truncate(at);
+            }
+        }
+        undone
+    }
+
+    // Forgets everything before the current instruction, e.g. once the collector moved things
+    pub(crate) fn reset(&mut self, state: &mut State<'a>) {
+        self.keyframes.clear();
+        self.keyframe(state);
+    }
+
+    fn keyframe(&mut self, state: &mut State<'a>) {
+        let keyframe = Keyframe {
+            step: self.step,
+            stack: state.stack.clone(),
+            frames: state.frames.clone(),
+            checkpoints: state.checkpoints.clone(),
+            at_exit: state.at_exit.clone(),
+            arena_len: state.gc.len(),
+            host_calls_len: host_calls_len(state),
+        };
+        // The journal only ever covers the latest keyframe
+        self.journal.clear();
+        self.used = self.keyframes.iter().map(keyframe_size).sum::<usize>() + keyframe_size(&keyframe);
+        self.keyframes.push_back(keyframe);
+        while self.used > self.budget && self.keyframes.len() > 1 {
+            let dropped = self.keyframes.pop_front().unwrap();
+            self.used -= keyframe_size(&dropped);
+        }
+        if let (Some(host_calls), Some(oldest)) = (state.host_calls.as_mut(), self.keyframes.front()) {
+            host_calls.forget(oldest.host_calls_len);
+        }
+    }
+
+    // Goes back to the latest keyframe at or before `target`, dropping everything after it
+    fn restore(&mut self, state: &mut State<'a>, target: usize) {
+        while self.keyframes.back().is_some_and(|keyframe| keyframe.step > target) {
+            self.keyframes.pop_back();
+        }
+        // It'll be taken again as soon as we replay its first instruction
+        let keyframe = self.keyframes.pop_back().expect("No keyframe to restore");
+        state.stack = keyframe.stack;
+        state.frames = keyframe.frames;
+        state.checkpoints = keyframe.checkpoints;
+        state.at_exit = keyframe.at_exit;
+        // NOTE: only sound since we're reset whenever the collector runs
+        gc::truncate(state, keyframe.arena_len);
+        // Replayed up to the target rather than called again
+        if let Some(host_calls) = state.host_calls.as_mut() {
+            host_calls.replaying = Some(keyframe.host_calls_len);
+        }
+        self.step = keyframe.step;
+        self.journal.clear();
+        self.used = self.keyframes.iter().map(keyframe_size).sum();
+    }
+}
+
+fn apply<'a>(undo: Undo<'a>, state: &mut State<'a>) {
+    if let Some(rolled_back) = undo.rolled_back {
+        state.stack = rolled_back.stack;
+        state.frames = rolled_back.frames;
+    } else {
+        // Drop the frame a call pushed, or bring back the one a return popped
+        state.frames.truncate(undo.depth);
+        if let Some(frame) = undo.returned {
+            state.frames.push_back(frame);
+        }
+        let frame = state.frames.back_mut().expect("No current frame?!");
+        frame.ip = undo.ip;
+        match undo.local {
+            Some((idx, Some(ptr))) => frame.locals[idx] = ptr,
+            Some((idx, None)) => frame.locals.truncate(idx),
+            None => {}
+        }
+        state.stack.truncate(undo.stack_base);
+        state.stack.extend(undo.popped);
+    }
+    state.checkpoints.truncate(undo.checkpoints_len);
+    if let Some(checkpoint) = undo.discarded {
+        state.checkpoints.push(checkpoint);
+    }
+    state.at_exit.truncate(undo.at_exit_len);
+    if let Some(host_calls) = state.host_calls.as_mut() {
+        host_calls.truncate(undo.host_calls_len);
+    }
+    gc::truncate(state, undo.arena_len);
+}
+
+fn frame_size(frame: &Frame) -> usize {
+    size_of::<Frame>() + frame.fun.len() + frame.locals.len() * size_of::<Ptr>()
+}
+
+fn checkpoint_size(checkpoint: &Checkpoint) -> usize {
+    checkpoint.stack.len() * size_of::<Ptr>() + checkpoint.frames.iter().map(frame_size).sum::<usize>()
+}
+
+fn undo_size(undo: &Undo) -> usize {
+    size_of::<Undo>()
+        + undo.popped.len() * size_of::<Ptr>()
+        + undo.returned.as_ref().map_or(0, frame_size)
+        + undo.discarded.as_ref().map_or(0, checkpoint_size)
+        + undo.rolled_back.as_ref().map_or(0, checkpoint_size)
+}
+
+fn keyframe_size(keyframe: &Keyframe) -> usize {
+    size_of::<Keyframe>()
+        + keyframe.stack.len() * size_of::<Ptr>()
+        + keyframe.frames.iter().map(frame_size).sum::<usize>()
+        + keyframe.checkpoints.iter().map(checkpoint_size).sum::<usize>()
+        + keyframe.at_exit.iter().map(|(module, fun)| size_of::<(Vec<String>, String)>() + fun.len()
+            + module.iter().map(String::len).sum::<usize>()).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use asm;
+    use embed::Value;
+    use link::Kept;
+    use vm::{self, VmConfig};
+    use super::*;
+
+    #[test]
+    fn replays_reuse_what_host_functions_returned() {
+        let modules = asm::modules(&["
+module Main
+
+fn MAIN
+loop:
+    LoadName Host.App.tick
+    Call 0
+    StoreLocal 0
+    Jump loop
+"]);
+        let ticks = Arc::new(AtomicI64::new(0));
+        let mut config = VmConfig::default();
+        let counted = ticks.clone();
+        let tick = move |_: &[Value]| Ok(Value::Int(counted.fetch_add(1, Ordering::SeqCst)));
+        config.host.insert((vec!("Host".to_string(), "App".to_string()), "tick".to_string()), Arc::new(tick));
+        let kept = Kept::default();
+        let mut state = vm::start(&modules[&vec!("Main".to_string())], "MAIN".to_string(), &kept, &config);
+        state.host_calls = Some(HostCalls::default());
+        let mut history = History::new(&modules, usize::MAX);
+
+        let mut seen = vec!();
+        for _ in 0..1500 {
+            seen.push(state.frames.back().unwrap().locals.first().cloned().map(|ptr| state.gc.at(ptr).to_string()));
+            history.record(&mut state);
+            step(&mut state, &modules, false);
+        }
+        let called = ticks.load(Ordering::SeqCst);
+        // Past the journal, so from the keyframe at 1000 on
+        assert_eq!(history.undo(&mut state, 400), 400);
+        assert_eq!(ticks.load(Ordering::SeqCst), called);
+        let local = state.frames.back().unwrap().locals.first().map(|ptr| state.gc.at(*ptr).to_string());
+        assert_eq!(local, seen[1100]);
+
+        // Going on from there calls them for real again, from where it got back to
+        assert_eq!(history.undo(&mut state, 2), 2);
+        for _ in 0..8 {
+            history.record(&mut state);
+            step(&mut state, &modules, false);
+        }
+        assert_eq!(ticks.load(Ordering::SeqCst), called + 2);
+        let local = state.frames.back().unwrap().locals.first().map(|ptr| state.gc.at(*ptr).to_string());
+        assert_eq!(local, Some(called.to_string()));
+    }
+}
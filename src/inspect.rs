@@ -0,0 +1,136 @@
+// A read-only view of linked modules, for tooling that wants to look at a program without
+// re-parsing module JSON or knowing how the linker resolves names
+use std::collections::HashMap;
+use asm;
+use link::{self, LinkError};
+use mangle;
+use vm::{format_module_name, Instruction, Module};
+
+pub struct Program<'a> {
+    modules: &'a HashMap<Vec<String>, Module>,
+    // Dependencies first, as they're linked
+    order: Vec<Vec<String>>,
+}
+
+impl<'a> Program<'a> {
+    // Links `modules` the way running them would, `main` being the module to run if there's one
+    pub fn link(main: Option<&[String]>, modules: &'a HashMap<Vec<String>, Module>) -> Result<Self, LinkError> {
+        link::link(main, modules)?;
+        Ok(Program { modules, order: link::order(modules)? })
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = ModuleView<'a>> + '_ {
+        let modules = self.modules;
+        self.order.iter().map(move |name| ModuleView { module: &modules[name] })
+    }
+
+    pub fn module(&self, name: &[String]) -> Option<ModuleView<'a>> {
+        self.modules.get(name).map(|module| ModuleView { module })
+    }
+
+    // Every module as assembly, in link order
+    pub fn disassemble(&self) -> String {
+        let listings: Vec<String> = self.order.iter()
+            .map(|name| asm::disassemble(&self.modules[name], Some(self.modules)))
+            .collect();
+        listings.join("\n")
+    }
+
+    // The function a call to `Module.name` with `arguments` arguments would run, picking the
+    // overload if need be
+    pub fn resolve(&self, module: &[String], name: &str, arguments: usize) -> Option<FunctionView<'a>> {
+        let module = self.modules.get(module)?;
+        let name = mangle::resolve(module, name, arguments)?;
+        Some(FunctionView { module, name })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ModuleView<'a> {
+    module: &'a Module,
+}
+
+impl<'a> ModuleView<'a> {
+    pub fn name(&self) -> &'a [String] {
+        &self.module.name
+    }
+
+    pub fn dependencies(&self) -> &'a [Vec<String>] {
+        &self.module.dependencies
+    }
+
+    pub fn dynamic_dependencies(&self) -> &'a [Vec<String>] {
+        &self.module.dynamic_dependencies
+    }
+
+    // Which build of the module it is, and what built it, if it says
+    pub fn version(&self) -> Option<&'a str> {
+        self.module.version.as_deref()
+    }
+
+    pub fn compiler(&self) -> Option<&'a str> {
+        self.module.compiler.as_deref()
+    }
+
+    // Where running it starts, MAIN unless it says otherwise
+    pub fn entrypoint(&self) -> &'a str {
+        self.module.entrypoint()
+    }
+
+    // What PushString indexes into
+    pub fn strings(&self) -> &'a [String] {
+        &self.module.strings
+    }
+
+    // By name, overloads under their mangled one
+    pub fn functions(&self) -> impl Iterator<Item = FunctionView<'a>> {
+        let module = self.module;
+        module.functions.keys().map(move |name| FunctionView { module, name })
+    }
+
+    pub fn function(&self, name: &str) -> Option<FunctionView<'a>> {
+        let module = self.module;
+        module.functions.get_key_value(name).map(|(name, _)| FunctionView { module, name })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FunctionView<'a> {
+    module: &'a Module,
+    name: &'a String,
+}
+
+impl<'a> FunctionView<'a> {
+    pub fn module(&self) -> ModuleView<'a> {
+        ModuleView { module: self.module }
+    }
+
+    // As defined, mangled for overloads
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    // What LoadName refers to it by
+    pub fn base_name(&self) -> &'a str {
+        mangle::demangle(self.name).0
+    }
+
+    // `Module.name`, as errors and the debugger print it
+    pub fn qualified_name(&self) -> String {
+        format!("{}.{}", format_module_name(&self.module.name), self.name)
+    }
+
+    // None if it isn't declared or mangled, and so not checked
+    pub fn arity(&self) -> Option<usize> {
+        mangle::arity(self.module, self.name)
+    }
+
+    // Whether other modules may LoadName it
+    pub fn is_exported(&self) -> bool {
+        self.module.exports.as_ref().is_none_or(|exports| exports.iter().any(|export| export == self.base_name()))
+    }
+
+    pub fn instructions(&self) -> &'a [Instruction] {
+        &self.module.functions[self.name]
+    }
+}
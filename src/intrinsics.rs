@@ -0,0 +1,71 @@
+use gc::{Ptr, GC};
+
+// What a Prelude function accepts, checked before it runs
+struct Signature {
+    name: &'static str,
+    min_args: usize,
+    // None if it takes any number of arguments
+    max_args: Option<usize>,
+    // Value kind each argument must have, None for any. The last one goes for any further arguments
+    arg_kinds: &'static [Option<&'static str>],
+}
+
+const fn variadic(name: &'static str, min_args: usize, arg_kinds: &'static [Option<&'static str>]) -> Signature {
+    Signature { name, min_args, max_args: None, arg_kinds }
+}
+
+const SIGNATURES: &[Signature] = &[
+    variadic("print", 0, &[None]),
+    Signature { name: "at_exit", min_args: 1, max_args: Some(1), arg_kinds: &[Some("FnRef")] },
+    // Traps with the message
+    Signature { name: "trap", min_args: 1, max_args: Some(1), arg_kinds: &[Some("Str")] },
+    Signature { name: "gc", min_args: 0, max_args: Some(0), arg_kinds: &[None] },
+    // Path to the module's JSON, returns its name
+    Signature { name: "load_module", min_args: 1, max_args: Some(1), arg_kinds: &[Some("Str")] },
+    // A loaded module's name, returns the version it was compiled as, empty if it doesn't say
+    Signature { name: "version", min_args: 1, max_args: Some(1), arg_kinds: &[Some("Str")] },
+    Signature { name: "weak", min_args: 1, max_args: Some(1), arg_kinds: &[None] },
+    // The weak ref, then what to return if its target got collected
+    Signature { name: "deref", min_args: 2, max_args: Some(2), arg_kinds: &[Some("WeakRef"), None] },
+    variadic("+", 1, &[Some("Int")]),
+    variadic("-", 1, &[Some("Int")]),
+    variadic("/", 1, &[Some("Int")]),
+    variadic("*", 1, &[Some("Int")]),
+    variadic(">", 1, &[Some("Int")]),
+    variadic("<", 1, &[Some("Int")]),
+    variadic("==", 1, &[Some("Int")]),
+    variadic(">=", 1, &[Some("Int")]),
+    variadic("<=", 1, &[Some("Int")]),
+    variadic("!=", 1, &[Some("Int")]),
+];
+
+pub(crate) fn exists(name: &str) -> bool {
+    SIGNATURES.iter().any(|signature| signature.name == name)
+}
+
+// Panics unless the `arg_num` arguments on top of `stack` fit `name`'s signature.
+// Arguments are numbered from 1, in the order they're popped
+pub(crate) fn check(name: &str, arg_num: usize, stack: &[Ptr], gc: &GC) {
+    let signature = SIGNATURES.iter()
+        .find(|signature| signature.name == name)
+        .unwrap_or_else(|| panic!("No such prelude fn: {}", name));
+
+    let arity = match signature.max_args {
+        Some(max) if max == signature.min_args => format!("{}", max),
+        Some(max) => format!("{} to {}", signature.min_args, max),
+        None => format!("at least {}", signature.min_args),
+    };
+    if arg_num < signature.min_args || signature.max_args.is_some_and(|max| arg_num > max) {
+        panic!("{:?} expects {} argument(s), got {}", name, arity, arg_num);
+    }
+
+    let args = stack.iter().rev().take(arg_num);
+    for (i, ptr) in args.enumerate() {
+        let expected = signature.arg_kinds.get(i).or(signature.arg_kinds.last()).cloned().flatten();
+        let got = gc.at(*ptr).kind();
+        match expected {
+            Some(kind) if got != kind => panic!("{:?} expects {} arguments, got {} at arg {}", name, kind, got, i + 1),
+            _ => {}
+        }
+    }
+}
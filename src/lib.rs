@@ -1,2 +1,30 @@
+// serde_derive's generated impls trip these with current rustc
+#![allow(non_local_definitions, unexpected_cfgs)]
+// LinkError says where it is down to the source, and it's only built once linking fails
+#![allow(clippy::result_large_err)]
+
+pub mod asm;
+pub mod bc;
+pub mod diagnostic;
+pub mod embed;
+pub mod inspect;
+pub mod link;
+pub mod lint;
+pub mod manifest;
+pub mod profile;
+pub mod stress;
 pub mod vm;
+mod dap;
+mod debugger;
+mod gc;
+mod history;
+mod intrinsics;
+mod link_cache;
+mod mangle;
+mod opt;
+mod report;
+mod snapshot;
+mod verify;
 extern crate serde;
+#[macro_use]
+extern crate serde_json;
@@ -0,0 +1,538 @@
+// Checks every loaded module before anything runs, so a broken reference fails up front with
+// where it is instead of as a panic halfway through the program
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use bc;
+use embed::HostFunctions;
+use intrinsics;
+use mangle::{self, Overloads};
+use verify;
+use vm::{find_module, format_module_name, is_host, is_prelude, Instruction, Location, Module, ModuleName};
+
+#[derive(Debug)]
+pub enum LinkErrorKind {
+    // A dependency that wasn't loaded
+    MissingModule(Vec<String>),
+    // Files both defining the module
+    DuplicateModule(String, String),
+    // Each module depends on the next, the last one is the first again
+    DependencyCycle(Vec<Vec<String>>),
+    // A module the resolver found but couldn't load, and why
+    CannotLoad(Vec<String>, String),
+    // The entrypoint it doesn't have
+    MissingEntrypoint(String),
+    NoSuchFunction(Vec<String>, String),
+    // Function another module doesn't export
+    NotExported(Vec<String>, String),
+    NoSuchPrelude(String),
+    // In the Host namespace, which has only what's registered
+    NoSuchHostFunction(Vec<String>, String),
+    // Index into the module's string table
+    NoSuchString(usize),
+    // Past the end of the function, jumping right to the end returns
+    BadJumpTarget(usize),
+    // Values needed, values the frame has
+    StackUnderflow(usize, usize),
+    // Stack depth from one path, then another
+    InconsistentDepth(usize, usize),
+    // Local loaded (as `local 3 (name)`), locals initialized by then on some path
+    UninitializedLocal(String, usize),
+    // Local stored, the first one not initialized by then on some path
+    OutOfOrderLocal(String, String),
+    // The callee as `Module.fn`, arguments it takes, arguments passed
+    ArityMismatch(String, usize, usize),
+    // Arity declared for a mangled function, and the one in its name
+    DeclaredArity(usize, usize),
+    // Locations in a function's source map, instructions it has
+    SourceMapTooLong(usize, usize),
+    // What the lints find, only errors when denied
+    UnusedFunction,
+    UnusedString(usize),
+    Unreachable,
+    UnusedDependency(Vec<String>),
+}
+
+impl LinkErrorKind {
+    // For tools, see `diagnostic`
+    pub fn code(&self) -> &'static str {
+        match self {
+            LinkErrorKind::MissingModule(_) => "missing-module",
+            LinkErrorKind::DuplicateModule(..) => "duplicate-module",
+            LinkErrorKind::DependencyCycle(_) => "dependency-cycle",
+            LinkErrorKind::CannotLoad(..) => "cannot-load",
+            LinkErrorKind::MissingEntrypoint(_) => "missing-entrypoint",
+            LinkErrorKind::NoSuchFunction(..) => "no-such-function",
+            LinkErrorKind::NotExported(..) => "not-exported",
+            LinkErrorKind::NoSuchPrelude(_) => "no-such-prelude",
+            LinkErrorKind::NoSuchHostFunction(..) => "no-such-host-function",
+            LinkErrorKind::NoSuchString(_) => "no-such-string",
+            LinkErrorKind::BadJumpTarget(_) => "bad-jump-target",
+            LinkErrorKind::StackUnderflow(..) => "stack-underflow",
+            LinkErrorKind::InconsistentDepth(..) => "inconsistent-depth",
+            LinkErrorKind::UninitializedLocal(..) => "uninitialized-local",
+            LinkErrorKind::OutOfOrderLocal(..) => "out-of-order-local",
+            LinkErrorKind::ArityMismatch(..) => "arity-mismatch",
+            LinkErrorKind::DeclaredArity(..) => "declared-arity",
+            LinkErrorKind::SourceMapTooLong(..) => "source-map-too-long",
+            LinkErrorKind::UnusedFunction => "unused-function",
+            LinkErrorKind::UnusedString(_) => "unused-string",
+            LinkErrorKind::Unreachable => "unreachable",
+            LinkErrorKind::UnusedDependency(_) => "unused-dependency",
+        }
+    }
+}
+
+// Where it went wrong: the module, and function and instruction offset if it's in one
+#[derive(Debug)]
+pub struct LinkError {
+    pub module: Vec<String>,
+    pub function: Option<String>,
+    pub ip: Option<usize>,
+    // What the instruction was compiled from, when the module has a source map
+    pub span: Option<Location>,
+    pub kind: LinkErrorKind,
+}
+
+impl LinkError {
+    // Where in the source it is, once it's known which module the error came from
+    pub(crate) fn locate(mut self, modules: &HashMap<Vec<String>, Module>) -> Self {
+        if let (Some(module), Some(function), Some(ip)) = (modules.get(&self.module), &self.function, self.ip) {
+            self.span = module.location(function, ip).cloned();
+        }
+        self
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_module_name(&self.module))?;
+        if let Some(function) = &self.function {
+            write!(f, ".{}", function)?;
+        }
+        if let Some(ip) = self.ip {
+            write!(f, "@{}", ip)?;
+        }
+        match &self.kind {
+            LinkErrorKind::MissingModule(name) => write!(f, ": depends on {}, which isn't loaded", format_module_name(name)),
+            LinkErrorKind::DependencyCycle(cycle) => {
+                let cycle: Vec<String> = cycle.iter().map(|name| format_module_name(name)).collect();
+                write!(f, ": circular dependencies {}", cycle.join(" -> "))
+            }
+            LinkErrorKind::CannotLoad(name, err) => write!(f, ": cannot load {}: {}", format_module_name(name), err),
+            LinkErrorKind::DuplicateModule(first, second) => write!(f, ": defined in both {} and {}", first, second),
+            LinkErrorKind::MissingEntrypoint(fun) => write!(f, ": no {} function to run", fun),
+            LinkErrorKind::NoSuchFunction(module, name) =>
+                write!(f, ": no function {} in {}", name, format_module_name(module)),
+            LinkErrorKind::NotExported(module, name) =>
+                write!(f, ": {} doesn't export {}", format_module_name(module), name),
+            LinkErrorKind::NoSuchPrelude(name) => write!(f, ": no prelude function {}", name),
+            LinkErrorKind::NoSuchHostFunction(module, name) =>
+                write!(f, ": no host function {}.{} registered", format_module_name(module), name),
+            LinkErrorKind::NoSuchString(idx) => write!(f, ": no string {} in the module's table", idx),
+            LinkErrorKind::BadJumpTarget(target) => write!(f, ": jumps to {}, past the end of the function", target),
+            LinkErrorKind::StackUnderflow(needs, has) =>
+                write!(f, ": needs {} value(s) on the stack, only {} there", needs, has),
+            LinkErrorKind::InconsistentDepth(one, other) =>
+                write!(f, ": reached with {} value(s) on the stack one way, {} another", one, other),
+            LinkErrorKind::UninitializedLocal(local, initialized) =>
+                write!(f, ": loads {}, but only {} local(s) are initialized on every path there", local, initialized),
+            LinkErrorKind::OutOfOrderLocal(local, first) =>
+                write!(f, ": stores {} before {}", local, first),
+            LinkErrorKind::ArityMismatch(callee, arity, passed) =>
+                write!(f, ": calls {} with {} argument(s), it takes {}", callee, passed, arity),
+            LinkErrorKind::DeclaredArity(declared, mangled) =>
+                write!(f, ": declared to take {} argument(s), its name says {}", declared, mangled),
+            LinkErrorKind::SourceMapTooLong(locations, instructions) =>
+                write!(f, ": source map has {} location(s) for {} instruction(s)", locations, instructions),
+            LinkErrorKind::UnusedFunction => write!(f, ": never referenced"),
+            LinkErrorKind::UnusedString(idx) => write!(f, ": string {} is never pushed", idx),
+            LinkErrorKind::Unreachable => write!(f, ": unreachable"),
+            LinkErrorKind::UnusedDependency(name) =>
+                write!(f, ": depends on {} without referring to it", format_module_name(name)),
+        }
+    }
+}
+
+// Locates a module that isn't loaded yet, Ok(None) if there's no such module
+pub type Resolver<'r> = dyn FnMut(&[String]) -> Result<Option<Module>, String> + 'r;
+
+// Loads whatever `main` refers to, directly or not, that's missing, as dependencies or LoadNames.
+// Only what's missing gets located, and only modules reached from `main`
+pub(crate) fn resolve(
+    main: &[String],
+    modules: &mut HashMap<Vec<String>, Module>,
+    resolver: &mut Resolver,
+) -> Result<(), LinkError> {
+    let mut work = vec![main.to_vec()];
+    let mut seen = HashSet::new();
+    while let Some(name) = work.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let module = match modules.get(&name) {
+            Some(module) => module,
+            // The linker reports it missing
+            None => continue,
+        };
+        let names = module.functions.values().flatten().filter_map(|instruction| match instruction {
+            Instruction::LoadName(namespace, _) if !is_prelude(namespace) && !is_host(namespace) => Some(&namespace.module),
+            _ => None,
+        });
+        // The program loads its dynamic dependencies itself, whenever it wants
+        let referenced: HashSet<Vec<String>> = module.dependencies.iter().chain(names)
+            .filter(|dep| !module.dynamic_dependencies.contains(dep))
+            .cloned()
+            .collect();
+        for dep in referenced {
+            if !modules.contains_key(&dep) {
+                match resolver(&dep) {
+                    Ok(Some(loaded)) if loaded.name == dep => {
+                        modules.insert(dep.clone(), loaded);
+                    }
+                    Ok(Some(loaded)) => {
+                        let err = format!("found {} instead", format_module_name(&loaded.name));
+                        return Err(LinkError { module: name, function: None, ip: None, span: None, kind: LinkErrorKind::CannotLoad(dep, err) });
+                    }
+                    Ok(None) => {}
+                    Err(err) =>
+                        return Err(LinkError { module: name, function: None, ip: None, span: None, kind: LinkErrorKind::CannotLoad(dep, err) }),
+                }
+            }
+            work.push(dep);
+        }
+    }
+    Ok(())
+}
+
+// `main` is None when resuming, the snapshot says where to start instead
+pub(crate) fn link(main: Option<&[String]>, modules: &HashMap<Vec<String>, Module>) -> Result<(), LinkError> {
+    if let Some(main) = main {
+        let entrypoint = modules.get(main).map_or("MAIN", Module::entrypoint);
+        if !modules.get(main).is_some_and(|module| module.functions.contains_key(entrypoint)) {
+            let kind = LinkErrorKind::MissingEntrypoint(entrypoint.to_string());
+            return Err(LinkError { module: main.to_vec(), function: None, ip: None, span: None, kind });
+        }
+    }
+
+    let symbols = Symbols::new(modules);
+    for name in order(modules)? {
+        link_module(&name, modules, &symbols)?;
+    }
+    verify::verify(modules, None)
+}
+
+// Every function in the Host namespace the modules load has to be one of `host`
+pub(crate) fn host_functions(modules: &HashMap<Vec<String>, Module>, host: &HostFunctions) -> Result<(), LinkError> {
+    let mut names: Vec<&Vec<String>> = modules.keys().collect();
+    names.sort();
+    for name in names {
+        for (fun, instructions) in &modules[name].functions {
+            for (ip, instruction) in instructions.iter().enumerate() {
+                match instruction {
+                    Instruction::LoadName(namespace, host_fn) if is_host(namespace)
+                        && !host.contains_key(&(namespace.module.clone(), host_fn.clone())) => return Err(LinkError {
+                        module: name.to_vec(),
+                        function: Some(fun.clone()),
+                        ip: Some(ip),
+                        span: None,
+                        kind: LinkErrorKind::NoSuchHostFunction(namespace.module.clone(), host_fn.clone()),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Swaps every function that needs a feature not in `features` for a stub that traps saying so,
+// before anything else looks at them. What they'd have called (Host functions that aren't
+// registered, say) doesn't have to be there then
+pub(crate) fn conditions(modules: &mut HashMap<Vec<String>, Module>, features: &[String]) {
+    for module in modules.values_mut() {
+        stub_conditional(module, features);
+    }
+}
+
+fn stub_conditional(module: &mut Module, features: &[String]) {
+    let missing: Vec<(String, String)> = module.conditions.iter()
+        .filter(|(fun, feature)| !features.contains(feature) && module.functions.contains_key(*fun))
+        .map(|(fun, feature)| (fun.clone(), feature.clone()))
+        .collect();
+    for (fun, feature) in missing {
+        let message = format!("{}.{} needs the {} feature", format_module_name(&module.name), fun, feature);
+        let idx = module.strings.iter().position(|string| *string == message).unwrap_or_else(|| {
+            module.strings.push(message);
+            module.strings.len() - 1
+        });
+        let stub = vec!(
+            Instruction::PushString(idx),
+            Instruction::LoadName(ModuleName::new(vec!("Prelude".to_string())), "trap".to_string()),
+            Instruction::Call(1),
+        );
+        module.source_map.remove(&fun);
+        module.functions.insert(fun, stub);
+    }
+}
+
+// Dependencies before the modules depending on them, by name where that leaves a choice. It's the
+// order modules get linked in, so a broken dependency is what gets reported rather than its
+// dependents. Cycles have no such order, and are an error
+pub(crate) fn order(modules: &HashMap<Vec<String>, Module>) -> Result<Vec<Vec<String>>, LinkError> {
+    let mut names: Vec<&Vec<String>> = modules.keys().collect();
+    names.sort();
+    let mut order = vec!();
+    let mut done = HashSet::new();
+    for name in names {
+        let mut path = vec!();
+        visit(name, modules, &mut path, &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+
+fn visit(
+    name: &[String],
+    modules: &HashMap<Vec<String>, Module>,
+    // Modules we're in the middle of, from the outermost
+    path: &mut Vec<Vec<String>>,
+    done: &mut HashSet<Vec<String>>,
+    order: &mut Vec<Vec<String>>,
+) -> Result<(), LinkError> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|module| *module == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name.to_vec());
+        return Err(LinkError { module: name.to_vec(), function: None, ip: None, span: None, kind: LinkErrorKind::DependencyCycle(cycle) });
+    }
+    // Missing ones are reported when linking
+    let module = match modules.get(name) {
+        Some(module) => module,
+        None => return Ok(()),
+    };
+    path.push(name.to_vec());
+    let mut dependencies: Vec<&Vec<String>> = module.dependencies.iter().collect();
+    dependencies.sort();
+    for dep in dependencies {
+        visit(dep, modules, path, done, order)?;
+    }
+    path.pop();
+    done.insert(name.to_vec());
+    order.push(name.to_vec());
+    Ok(())
+}
+
+fn link_module(name: &[String], modules: &HashMap<Vec<String>, Module>, symbols: &Symbols) -> Result<(), LinkError> {
+    let module = &modules[name];
+    if let Some(dep) = module.dependencies.iter().find(|dep| !modules.contains_key(*dep)) {
+        return Err(LinkError {
+            module: name.to_vec(),
+            function: None,
+            ip: None,
+            span: None,
+            kind: LinkErrorKind::MissingModule(dep.clone()),
+        });
+    }
+    let mut declared = module.arities.keys().chain(module.exports.iter().flatten());
+    if let Some(fun) = declared.find(|fun| symbols.overloads.of(name, fun).is_empty()) {
+        return Err(LinkError {
+            module: name.to_vec(),
+            function: None,
+            ip: None,
+            span: None,
+            kind: LinkErrorKind::NoSuchFunction(name.to_vec(), fun.clone()),
+        });
+    }
+    for (fun, locations) in &module.source_map {
+        let kind = match module.functions.get(fun) {
+            None => LinkErrorKind::NoSuchFunction(name.to_vec(), fun.clone()),
+            Some(instructions) if locations.len() > instructions.len() =>
+                LinkErrorKind::SourceMapTooLong(locations.len(), instructions.len()),
+            Some(_) => continue,
+        };
+        return Err(LinkError { module: name.to_vec(), function: Some(fun.clone()), ip: None, span: None, kind });
+    }
+    if let Some(fun) = module.local_names.keys().find(|fun| !module.functions.contains_key(*fun)) {
+        return Err(LinkError {
+            module: name.to_vec(),
+            function: None,
+            ip: None,
+            span: None,
+            kind: LinkErrorKind::NoSuchFunction(name.to_vec(), fun.clone()),
+        });
+    }
+    // A mangled name already says how many arguments it takes
+    for (fun, arity) in &module.arities {
+        match mangle::demangle(fun) {
+            (_, Some(mangled)) if mangled != *arity => return Err(LinkError {
+                module: name.to_vec(),
+                function: Some(fun.clone()),
+                ip: None,
+                span: None,
+                kind: LinkErrorKind::DeclaredArity(*arity, mangled),
+            }),
+            _ => {}
+        }
+    }
+    for (fun, instructions) in &module.functions {
+        for (ip, instruction) in instructions.iter().enumerate() {
+            let located = |kind| LinkError { module: name.to_vec(), function: Some(fun.clone()), ip: Some(ip), span: None, kind };
+            check(instruction, module, modules, symbols).map_err(located)?;
+        }
+    }
+    Ok(())
+}
+
+// For `load_module`: parses the module at `path` and links it against everything loaded so far.
+// A module that's already loaded is just returned, replays in the debugger load it again
+pub(crate) fn load<'a>(
+    path: &str,
+    modules: &'a HashMap<Vec<String>, Module>,
+    loaded: &mut HashMap<Vec<String>, &'a Module>,
+    kept: &'a Kept,
+    features: &[String],
+) -> Result<&'a Module, String> {
+    let module = bc::load(path)?;
+    if let Some(existing) = find_module(modules, loaded, &module.name) {
+        return Ok(existing);
+    }
+    link_loaded(module, modules, loaded, kept, features)
+}
+
+// Swaps in new function bodies for a loaded module, from the module at `path`. It has to keep
+// every function (refs to them may be anywhere on the heap) with the same arity. Frames already
+// running the old ones finish with the old code, calls from then on get the new one
+pub(crate) fn reload<'a>(
+    path: &str,
+    modules: &'a HashMap<Vec<String>, Module>,
+    loaded: &mut HashMap<Vec<String>, &'a Module>,
+    kept: &'a Kept,
+    features: &[String],
+) -> Result<&'a Module, String> {
+    let module = bc::load(path)?;
+    let old = find_module(modules, loaded, &module.name)
+        .ok_or(format!("{} isn't loaded", format_module_name(&module.name)))?;
+    for fun in old.functions.keys() {
+        if !module.functions.contains_key(fun) {
+            return Err(format!("{} is gone", fun));
+        }
+        if mangle::arity(old, fun).is_some_and(|arity| mangle::arity(&module, fun) != Some(arity)) {
+            return Err(format!("{} changed arity", fun));
+        }
+    }
+    link_loaded(module, modules, loaded, kept, features)
+}
+
+// Links `module` against everything loaded so far, replacing any module with the same name
+fn link_loaded<'a>(
+    mut module: Module,
+    modules: &'a HashMap<Vec<String>, Module>,
+    loaded: &mut HashMap<Vec<String>, &'a Module>,
+    kept: &'a Kept,
+    features: &[String],
+) -> Result<&'a Module, String> {
+    stub_conditional(&mut module, features);
+    // NOTE: clones every module, loading should be rare enough
+    let mut linked: HashMap<Vec<String>, Module> = modules.clone();
+    linked.extend(loaded.iter().map(|(name, module)| (name.clone(), (*module).clone())));
+    let name = module.name.clone();
+    linked.insert(name.clone(), module);
+    link_module(&name, &linked, &Symbols::new(&linked)).map_err(|err| err.to_string())?;
+    verify::verify(&linked, Some(&name)).map_err(|err| err.to_string())?;
+    let module = kept.keep(linked.remove(&name).unwrap());
+    loaded.insert(name, module);
+    Ok(module)
+}
+
+// The modules linked while a program runs, owned by the run. Frames borrow their module for as
+// long as the program runs, a reloaded one included, so nothing goes before the run does. It only
+// ever grows, through a shared reference, as a chain of cells that are each set once
+#[derive(Default)]
+pub(crate) struct Kept {
+    module: OnceCell<Module>,
+    next: OnceCell<Box<Kept>>,
+}
+
+impl Kept {
+    fn keep(&self, mut module: Module) -> &Module {
+        let mut last = self;
+        loop {
+            match last.module.set(module) {
+                Ok(()) => return last.module.get().unwrap(),
+                Err(taken) => {
+                    module = taken;
+                    last = last.next.get_or_init(Box::default);
+                }
+            }
+        }
+    }
+}
+
+fn check(
+    instruction: &Instruction,
+    module: &Module,
+    modules: &HashMap<Vec<String>, Module>,
+    symbols: &Symbols,
+) -> Result<(), LinkErrorKind> {
+    match instruction {
+        Instruction::PushString(idx) if *idx >= module.strings.len() => Err(LinkErrorKind::NoSuchString(*idx)),
+        Instruction::LoadName(namespace, name) if is_prelude(namespace) => {
+            if intrinsics::exists(name) { Ok(()) } else { Err(LinkErrorKind::NoSuchPrelude(name.clone())) }
+        }
+        // Only whoever runs it knows, see `host_functions`
+        Instruction::LoadName(namespace, _) if is_host(namespace) => Ok(()),
+        Instruction::LoadName(namespace, name) => match modules.get(&namespace.module) {
+            Some(_) if symbols.overloads.of(&namespace.module, name).is_empty() =>
+                Err(LinkErrorKind::NoSuchFunction(namespace.module.clone(), name.clone())),
+            Some(target) if target.name != module.name && !symbols.exported(&target.name, name) =>
+                Err(LinkErrorKind::NotExported(namespace.module.clone(), name.clone())),
+            Some(_) => Ok(()),
+            None if module.dynamic_dependencies.contains(&namespace.module) => Ok(()),
+            None => Err(LinkErrorKind::MissingModule(namespace.module.clone())),
+        },
+        Instruction::LoadGlobal(name) if symbols.overloads.of(&module.name, name).is_empty() =>
+            Err(LinkErrorKind::NoSuchFunction(module.name.clone(), name.clone())),
+        _ => Ok(()),
+    }
+}
+
+// What LoadNames and LoadGlobals resolve against, indexed once per link so checking each one
+// is a lookup
+struct Symbols<'m> {
+    overloads: Overloads<'m>,
+    // Modules that export everything aren't in here
+    exports: HashMap<&'m [String], HashSet<&'m str>>,
+}
+
+impl<'m> Symbols<'m> {
+    fn new(modules: &'m HashMap<Vec<String>, Module>) -> Self {
+        let exports = modules.iter()
+            .filter_map(|(name, module)| module.exports.as_ref().map(|exports| (name.as_slice(), exports.iter().map(String::as_str).collect())))
+            .collect();
+        Symbols { overloads: Overloads::new(modules), exports }
+    }
+
+    fn exported(&self, module: &[String], fun: &str) -> bool {
+        self.exports.get(module).is_none_or(|exports| exports.contains(fun))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm;
+    use super::Kept;
+
+    #[test]
+    fn kept_modules_stay_where_they_are() {
+        let kept = Kept::default();
+        let first = kept.keep(asm::assemble("module First\nfn MAIN\n    PushInt 1\n").unwrap());
+        let others: Vec<_> = (0..3)
+            .map(|n| kept.keep(asm::assemble(&format!("module Other{}\nfn MAIN\n    PushInt 1\n", n)).unwrap()))
+            .collect();
+        assert_eq!(first.name, ["First"]);
+        let names: Vec<_> = others.iter().map(|module| module.name.join("::")).collect();
+        assert_eq!(names, ["Other0", "Other1", "Other2"]);
+    }
+}
@@ -0,0 +1,150 @@
+// Linked (and optimized) modules saved to a file, so an unchanged program skips linking next time.
+// Only the modules the passes rewrote are in it, the rest are used as they were read
+use std::collections::{BTreeMap as Map, HashMap};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter};
+use serde::{Serialize, Deserialize};
+use bc::{self, Fnv};
+use vm::{Module, Origin, VmConfig};
+
+// Each module's origins by its index, they aren't part of the module
+#[derive(Serialize)]
+struct Cache<'a> {
+    key: &'a str,
+    modules: Vec<&'a Module>,
+    origins: Vec<&'a Map<String, Vec<Origin>>>,
+}
+
+#[derive(Deserialize)]
+struct SavedCache {
+    key: String,
+    modules: Vec<Module>,
+    origins: Vec<Map<String, Vec<Origin>>>,
+}
+
+// What the cache is looked up by, and each module's checksum from before linking to tell which
+// ones the passes rewrote
+pub(crate) struct Key {
+    key: String,
+    checksums: HashMap<Vec<String>, String>,
+}
+
+// Hash of this VM's version, the entry module and function, every module's checksum, the passes
+// that would run, the lint levels, the Host functions there are and the features granted
+pub(crate) fn key(main: &[String], modules: &HashMap<Vec<String>, Module>, config: &VmConfig) -> Key {
+    // The one a module comes with was checked when it was read
+    let checksums: HashMap<Vec<String>, String> = modules.iter()
+        .map(|(name, module)| (name.clone(), module.checksum.clone().unwrap_or_else(|| bc::checksum(module))))
+        .collect();
+    let mut names: Vec<&Vec<String>> = modules.keys().collect();
+    names.sort();
+    let mut hasher = Fnv::new();
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.write(main.join(".").as_bytes());
+    // `--entry` may have changed it since the checksum the module came with, and DCE goes by it
+    hasher.write(modules.get(main).map_or("", Module::entrypoint).as_bytes());
+    for name in names {
+        hasher.write(name.join(".").as_bytes());
+        hasher.write(checksums[name].as_bytes());
+    }
+    let passes = [config.resume.is_some(), config.inline, config.peephole, config.eliminate_dead_code];
+    for pass in &passes {
+        hasher.write_u8(*pass as u8);
+    }
+    // A denied lint may fail a program that linked before
+    let mut lints: Vec<String> = config.lints.iter().map(|(lint, level)| format!("{}={:?}", lint.name(), level)).collect();
+    lints.sort();
+    hasher.write(lints.join(",").as_bytes());
+    // Linking checks the Host functions the modules load are there
+    let mut host: Vec<String> = config.host.keys().map(|(module, fun)| format!("{}.{}", module.join("."), fun)).collect();
+    host.sort();
+    hasher.write(host.join(",").as_bytes());
+    // Which conditional functions are stubs
+    let mut features = config.features.clone();
+    features.sort();
+    hasher.write(features.join(",").as_bytes());
+    Key { key: format!("fnv1a64:{:016x}", hasher.finish()), checksums }
+}
+
+// The modules to use instead of the ones read, None if there's no cache there or it's for another
+// program
+pub(crate) fn load(path: &str, key: &Key) -> Option<Vec<Module>> {
+    let file = File::open(path).ok()?;
+    let cache: SavedCache = serde_json::from_reader(BufReader::new(file)).ok()?;
+    if cache.key != key.key {
+        return None;
+    }
+    let modules = cache.modules.into_iter().zip(cache.origins)
+        .map(|(module, origins)| Module { origins, ..module })
+        .collect();
+    Some(modules)
+}
+
+// Through a temporary file like snapshots, so a crash mid-write doesn't leave half a cache
+pub(crate) fn save(path: &str, key: &Key, modules: &HashMap<Vec<String>, Module>) -> Result<(), String> {
+    let rewritten: Vec<&Module> = modules.values()
+        .filter(|module| key.checksums.get(&module.name).is_none_or(|before| *before != bc::checksum(module)))
+        .collect();
+    let origins = rewritten.iter().map(|module| &module.origins).collect();
+    let cache = Cache { key: &key.key, modules: rewritten, origins };
+    let tmp_path = format!("{}.tmp", path);
+    let file = File::create(&tmp_path).map_err(|err| err.to_string())?;
+    serde_json::to_writer(BufWriter::new(file), &cache).map_err(|err| err.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use asm;
+    use vm;
+    use super::*;
+
+    #[test]
+    fn only_rewritten_modules_are_saved() {
+        let mut modules = asm::modules(&["module A\nfn MAIN\n    PushInt 1\n", "module B\nfn f\n    PushInt 2\n"]);
+        let config = VmConfig::default();
+        let before = key(&["A".to_string()], &modules, &config);
+        assert_eq!(before.key, key(&["A".to_string()], &modules, &config).key);
+
+        let b = modules.get_mut(&vec!("B".to_string())).unwrap();
+        b.strings.push("rewritten".to_string());
+        let origin = Origin { module: vec!("A".to_string()), fun: "g".to_string(), ips: (1, 2) };
+        b.origins.insert("f".to_string(), vec!(origin.clone()));
+        let path = env::temp_dir().join(format!("undo-link-cache-{}.json", std::process::id())).display().to_string();
+        save(&path, &before, &modules).unwrap();
+        let saved = load(&path, &before).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.iter().map(|module| module.name.clone()).collect::<Vec<_>>(), vec!(vec!("B".to_string())));
+        assert_eq!(saved[0].origins["f"], vec!(origin));
+        assert_ne!(before.key, key(&["A".to_string()], &modules, &config).key);
+    }
+
+    #[test]
+    fn other_entrypoints_miss() {
+        let path = env::temp_dir().join(format!("undo-link-cache-entry-{}.json", std::process::id())).display().to_string();
+        let main = ["M".to_string()];
+        let run = |entrypoint: &str| {
+            let mut modules = asm::modules(&["module M\nfn MAIN\n    PushInt 1\nfn other\n    PushInt 2\n"]);
+            let module = modules.get_mut(&main[..]).unwrap();
+            module.checksum = Some(bc::checksum(module));
+            let config = VmConfig {
+                entrypoint: Some(entrypoint.to_string()),
+                eliminate_dead_code: true,
+                link_cache: Some(path.clone()),
+                quiet: true,
+                ..VmConfig::default()
+            };
+            vm::prepare(&main, &mut modules, &config, &mut |_| Ok(None)).unwrap();
+            modules.remove(&main[..]).unwrap()
+        };
+        let other = run("other");
+        assert_eq!(other.entrypoint(), "other");
+        assert_eq!(other.functions.keys().collect::<Vec<_>>(), vec!("other"));
+        let main = run("MAIN");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(main.entrypoint(), "MAIN");
+        assert_eq!(main.functions.keys().collect::<Vec<_>>(), vec!("MAIN"));
+    }
+}
@@ -0,0 +1,213 @@
+// Link-time warnings, for things that link and run fine but point at a frontend emitting more
+// than it needs to. Each lint is allowed unless asked for, and can be denied to fail linking
+use std::collections::{HashMap, HashSet};
+use embed::Output;
+use link::{LinkError, LinkErrorKind};
+use mangle::Overloads;
+use vm::{is_prelude, Instruction, Module};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Lint {
+    // Nothing refers to it, the entrypoint aside
+    UnusedFunction,
+    // Nothing pushes it
+    UnusedString,
+    // No path from the start of the function gets there
+    Unreachable,
+    // Declared, but nothing refers to anything in it
+    UnusedDependency,
+}
+
+pub const LINTS: [(&str, Lint); 4] = [
+    ("unused-function", Lint::UnusedFunction),
+    ("unused-string", Lint::UnusedString),
+    ("unreachable", Lint::Unreachable),
+    ("unused-dependency", Lint::UnusedDependency),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Lint {
+    pub fn from_name(name: &str) -> Option<Lint> {
+        LINTS.iter().find(|(lint_name, _)| *lint_name == name).map(|(_, lint)| *lint)
+    }
+
+    pub fn name(self) -> &'static str {
+        LINTS.iter().find(|(_, lint)| *lint == self).unwrap().0
+    }
+}
+
+// Warns `output` about each lint that's warned about, the first denied one is an error instead
+pub(crate) fn check(modules: &HashMap<Vec<String>, Module>, levels: &HashMap<Lint, Level>, output: &dyn Output) -> Result<(), LinkError> {
+    if levels.values().all(|level| *level == Level::Allow) {
+        return Ok(());
+    }
+    for (lint, warning) in lint(modules) {
+        match levels.get(&lint).cloned().unwrap_or(Level::Allow) {
+            Level::Allow => {}
+            Level::Warn => output.warn(&format!("warning: {} [{}]", warning, lint.name())),
+            Level::Deny => return Err(warning),
+        }
+    }
+    Ok(())
+}
+
+// Everything any lint finds, module by module
+fn lint(modules: &HashMap<Vec<String>, Module>) -> Vec<(Lint, LinkError)> {
+    let overloads = Overloads::new(modules);
+    let mut referenced: HashSet<(&Vec<String>, &String)> = HashSet::new();
+    for module in modules.values() {
+        for instruction in module.functions.values().flatten() {
+            let (target, name) = match instruction {
+                Instruction::LoadName(namespace, name) if !is_prelude(namespace) => (&namespace.module, name),
+                Instruction::LoadGlobal(name) => (&module.name, name),
+                _ => continue,
+            };
+            if let Some(target) = modules.get(target) {
+                referenced.extend(overloads.of(&target.name, name).iter().map(|fun| (&target.name, *fun)));
+            }
+        }
+    }
+
+    let mut names: Vec<&Vec<String>> = modules.keys().collect();
+    names.sort();
+    let mut warnings = vec!();
+    for name in names {
+        let module = &modules[name];
+        let warning = |function: Option<&String>, ip, kind| LinkError { module: name.clone(), function: function.cloned(), ip, span: None, kind };
+
+        let mut used_dependencies = HashSet::new();
+        let mut used_strings = vec![false; module.strings.len()];
+        for (fun, instructions) in &module.functions {
+            if fun != module.entrypoint() && !referenced.contains(&(name, fun)) {
+                warnings.push((Lint::UnusedFunction, warning(Some(fun), None, LinkErrorKind::UnusedFunction)));
+            }
+            for ip in unreachable(instructions) {
+                warnings.push((Lint::Unreachable, warning(Some(fun), Some(ip), LinkErrorKind::Unreachable)));
+            }
+            for instruction in instructions {
+                match instruction {
+                    Instruction::PushString(idx) => used_strings[*idx] = true,
+                    Instruction::LoadName(namespace, _) => {
+                        used_dependencies.insert(&namespace.module);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (idx, _) in used_strings.iter().enumerate().filter(|(_, used)| !**used) {
+            warnings.push((Lint::UnusedString, warning(None, None, LinkErrorKind::UnusedString(idx))));
+        }
+        for dep in module.dependencies.iter().filter(|dep| !used_dependencies.contains(dep)) {
+            warnings.push((Lint::UnusedDependency, warning(None, None, LinkErrorKind::UnusedDependency(dep.clone()))));
+        }
+    }
+    warnings
+}
+
+// Where each run of instructions nothing reaches starts
+fn unreachable(instructions: &[Instruction]) -> Vec<usize> {
+    let mut reached = vec![false; instructions.len()];
+    let mut work = vec![0];
+    while let Some(ip) = work.pop() {
+        if ip >= instructions.len() || reached[ip] {
+            continue;
+        }
+        reached[ip] = true;
+        match &instructions[ip] {
+            Instruction::Jump(target) => work.push(*target),
+            Instruction::Unless(target) => work.extend(&[ip + 1, *target]),
+            Instruction::Rollback => {}
+            _ => work.push(ip + 1),
+        }
+    }
+    (0..instructions.len()).filter(|ip| !reached[*ip] && (*ip == 0 || reached[ip - 1])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use asm;
+    use embed::Captured;
+    use super::*;
+
+    const LIB: &str = "module Lib\nfn used/0\n    PushInt 1\nfn used/1 1\n    LoadLocal 0\n";
+
+    // Which lint found what, in order
+    fn found(modules: &HashMap<Vec<String>, Module>) -> Vec<(Lint, Option<String>, Option<usize>, String)> {
+        lint(modules).into_iter().map(|(lint, err)| (lint, err.function, err.ip, err.kind.code().to_string())).collect()
+    }
+
+    #[test]
+    fn clean_programs_have_nothing_to_say() {
+        let modules = asm::modules(&[
+            "module Main\ndepends Lib\nfn MAIN\n    PushString \"hi\"\n    LoadName Lib.used\n    Call 1\n    LoadGlobal helper\n    Call 0\nfn helper\n    PushInt 1\n",
+            LIB,
+        ]);
+        // Referring to `used` by its base name counts for every overload of it
+        assert!(lint(&modules).is_empty(), "{:?}", found(&modules));
+    }
+
+    #[test]
+    fn finds_unused_functions() {
+        let modules = asm::modules(&["module Main\nfn MAIN\n    PushInt 1\nfn helper\n    PushInt 2\n"]);
+        assert_eq!(found(&modules), vec!((Lint::UnusedFunction, Some("helper".to_string()), None, LinkErrorKind::UnusedFunction.code().to_string())));
+    }
+
+    #[test]
+    fn finds_unused_strings() {
+        let mut modules = asm::modules(&["module Main\nfn MAIN\n    PushString \"used\"\n"]);
+        modules.get_mut(&vec!("Main".to_string())).unwrap().strings.push("unused".to_string());
+        let lints = lint(&modules);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].0, Lint::UnusedString);
+        assert!(matches!(lints[0].1.kind, LinkErrorKind::UnusedString(1)), "{:?}", lints[0].1.kind);
+    }
+
+    #[test]
+    fn finds_where_unreachable_code_starts() {
+        let modules = asm::modules(&["module Main\nfn MAIN\n    Jump end\n    PushInt 1\n    PushInt 2\nend:\n    PushInt 3\n    Jump done\n    PushInt 4\ndone:\n"]);
+        let ips: Vec<_> = found(&modules).into_iter().map(|(lint, _, ip, _)| (lint, ip)).collect();
+        assert_eq!(ips, vec!((Lint::Unreachable, Some(1)), (Lint::Unreachable, Some(5))));
+    }
+
+    #[test]
+    fn finds_unused_dependencies() {
+        let modules = asm::modules(&["module Main\ndepends Lib\nfn MAIN\n    PushInt 1\n", LIB]);
+        let lints: Vec<_> = lint(&modules).into_iter().filter(|(lint, _)| *lint == Lint::UnusedDependency).collect();
+        assert_eq!(lints.len(), 1);
+        assert!(matches!(&lints[0].1.kind, LinkErrorKind::UnusedDependency(dep) if dep == &["Lib"]), "{:?}", lints[0].1.kind);
+    }
+
+    #[test]
+    fn levels_decide_what_fails() {
+        let modules = asm::modules(&["module Main\nfn MAIN\n    PushInt 1\nfn helper\n    PushInt 2\n"]);
+        let levels = |level| vec!((Lint::UnusedFunction, level)).into_iter().collect::<HashMap<_, _>>();
+        let output = Captured::default();
+        assert!(check(&modules, &HashMap::new(), &output).is_ok());
+        assert!(check(&modules, &levels(Level::Allow), &output).is_ok());
+        assert!(output.warnings().is_empty());
+        assert!(check(&modules, &levels(Level::Warn), &output).is_ok());
+        assert_eq!(output.warnings(), vec!("warning: Main.helper: never referenced [unused-function]"));
+        let err = check(&modules, &levels(Level::Deny), &output).unwrap_err();
+        assert!(matches!(err.kind, LinkErrorKind::UnusedFunction), "{:?}", err.kind);
+        assert_eq!(err.function.as_deref(), Some("helper"));
+        // Denying some other lint doesn't fail on this one
+        assert!(check(&modules, &vec!((Lint::UnusedString, Level::Deny)).into_iter().collect(), &output).is_ok());
+        assert_eq!(output.warnings().len(), 1);
+        assert!(output.lines().is_empty());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for (name, lint) in &LINTS {
+            assert_eq!(Lint::from_name(name), Some(*lint));
+            assert_eq!(lint.name(), *name);
+        }
+        assert_eq!(Lint::from_name("unused"), None);
+    }
+}
@@ -1,37 +1,745 @@
 use std::env;
-use std::fs::File;
+use std::fs;
+use std::process;
+use std::thread;
 use std::io::Read;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 use std::collections::HashMap;
-use lib::vm::Module;
+use lib::asm;
+use lib::bc::{self, Bundle};
+use lib::diagnostic::{Diagnostic, ErrorFormat};
+use lib::inspect::Program;
+use lib::link::{LinkError, LinkErrorKind};
+use lib::lint::{Level, Lint, LINTS};
+use lib::manifest;
+use lib::profile;
+use lib::stress::{self, StressOptions};
+use lib::vm::{Module, RunError, VmConfig, GC_STRATEGIES};
 
 extern crate lib;
 
+// What went wrong: the command line itself, which exits with 2, or carrying it out, with 1.
+// Diagnosed is the latter with `--error-format json`
+enum Error {
+    Usage(String),
+    Failed(String),
+    Diagnosed(Vec<Diagnostic>),
+}
+
+// Failed or Diagnosed, as `format` says
+fn failure(format: ErrorFormat, code: &'static str, message: String) -> Error {
+    match format {
+        ErrorFormat::Human => Error::Failed(message),
+        ErrorFormat::Json => Error::Diagnosed(vec!(Diagnostic::new(code, message))),
+    }
+}
+
+const USAGE: &str = "\
+Usage: bin COMMAND ARG...
+       bin [OPTION]... FILE...     same as `bin run`
+
+Commands:
+  run [OPTION]... FILE...      run the modules in each FILE, the first one's entrypoint
+  check [OPTION]... FILE...    link and verify them without running anything
+  disasm FILE...               list the modules as assembly
+  symbols FILE...              list the functions in each module, with their arity
+  repl [FILE]...               run lines of instructions, `;` between them, against the modules
+  encode IN OUT                write the module IN to OUT in the binary encoding
+  bundle OUT FILE...           pack the modules into OUT, to run the first one
+  checksum FILE...             print what each module's checksum should be
+  gen-stress [--functions N] [--strings N] [--depth N]
+                               write a generated module to stdout
+  help                         print this
+
+Modules are JSON, binary, bundles or .undoasm, and a directory has every module file in it
+loaded. `-` reads stdin, where JSON modules may also come one per line or in an array. The exit status of `run` is the int the entrypoint returns, if it does, 1 if it traps and 3 if it goes over --max-heap, --heap-limit or --max-frames.
+
+Options for run and check:
+  --entry [MODULE::PATH::]FN   module and function to start in, instead of the first module's
+                               entrypoint
+  --module-path DIR            where to look for modules nobody passed, may be repeated
+  --all PATTERN                load every file matching PATTERN, `*` and `?` as wildcards
+  --manifest FILE              modules, entry, module path and limits from an undo.json, options
+                               after it override it
+  --check                      link and verify the modules without running them
+  --watch                      run again whenever a module file changes, with how the output did
+  --all-errors                 every problem with a module that won't load, not just the first
+  --no-trace                   don't print every instruction as it runs
+  --debug                      run in the debugger
+  --dap PORT                   serve the debugger over the Debug Adapter Protocol instead
+  -W LINT, -D LINT, -A LINT    warn about, deny or allow a lint, `all` for every one
+  --heap-reserve N             objects to make room for up front
+  --max-heap N                 most objects the heap may hold once collected
+  --heap-limit SIZE            most bytes they may take, with K, M or G for larger units
+  --max-frames N               most calls there may be in progress at once
+  --gc STRATEGY                copying, mark-sweep, incremental or none
+  --gc-threshold N             objects to allocate between collections, at least
+  --gc-slice N                 slots the incremental collector sweeps per instruction
+  --heap-stats                 print heap statistics once done
+  --dce, --inline, --peephole  passes to run over the linked modules
+  --link-cache FILE            keep the linked modules there, to skip linking next time
+  --feature NAME               grant a VM feature, functions that need one that isn't link as
+                               stubs that trap, may be repeated
+  --error-format FORMAT        human, or json for one object per error, for tools
+  --timings                    print how long loading, linking and running took
+  --profile                    print per-function timings once done
+  --opcode-counts              print how many times each instruction ran once done
+  --coverage FILE              write which instructions ran, as an LCOV tracefile
+  --edges FILE                 write the branch edges taken
+  --flamegraph FILE            write folded stacks, sampled every --sample-every N instructions
+  --snapshot FILE              write snapshots, every --snapshot-every N instructions
+  --resume FILE                resume from a snapshot
+  --history-budget BYTES       memory the debugger may spend on history
+  --exit-fuel N                instructions the at_exit hooks get to run
+  -h, --help                   print this
+  -V, --version                print the version
+";
+
+// The argument after `flag`, parsed
+fn value<I: Iterator<Item = String>, T: FromStr>(args: &mut I, flag: &str, what: &str) -> Result<T, Error> {
+    let value = args.next().ok_or_else(|| Error::Usage(format!("{} needs {}", flag, what)))?;
+    value.parse().map_err(|_| Error::Usage(format!("{} needs {}, not {:?}", flag, what, value)))
+}
+
+// `64M` and the like to bytes, K being 1024
+fn size(size: &str) -> Option<usize> {
+    let (number, unit) = match size.char_indices().last()? {
+        (at, 'K') | (at, 'k') => (&size[..at], 1 << 10),
+        (at, 'M') | (at, 'm') => (&size[..at], 1 << 20),
+        (at, 'G') | (at, 'g') => (&size[..at], 1 << 30),
+        _ => (size, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn gc_strategy(name: String) -> Result<String, Error> {
+    if !GC_STRATEGIES.contains(&name.as_str()) {
+        return Err(Error::Usage(format!("unknown GC strategy {}, expected one of: {}", name, GC_STRATEGIES.join(", "))));
+    }
+    Ok(name)
+}
+
+// A bundle's modules, or the one module in any other file
+fn load_modules(path: &str) -> Result<Bundle, String> {
+    if path != "-" {
+        return bc::load_bundle(path);
+    }
+    let mut content = vec!();
+    std::io::stdin().read_to_end(&mut content).map_err(|err| err.to_string())?;
+    bc::read_bundle(&content)
+}
+
+// `bin bundle OUT FILE...`, packs the modules into OUT, to run the first one
+fn bundle<I: Iterator<Item = String>>(mut args: I) -> Result<(), Error> {
+    let output = args.next().ok_or_else(|| Error::Usage("bundle needs where to write it, then the modules".to_string()))?;
+    let mut modules: Vec<Module> = vec!();
+    for path in args {
+        let loaded = load_modules(&path).map_err(|err| Error::Failed(format!("Cannot open module {}: {}", path, err)))?;
+        modules.extend(loaded.modules);
+    }
+    let entry = modules.first().ok_or_else(|| Error::Usage("bundle needs at least one module".to_string()))?.name.clone();
+    fs::write(&output, bc::bundle(&entry, &modules)).map_err(|err| Error::Failed(format!("Cannot write {}: {}", output, err)))
+}
+
+// JSON, binary or assembly, whichever it turns out to be
 fn load_module(path: String) -> Result<Module, String> {
-    let mut content = String::new();
-    if path == "-" {
-        std::io::stdin().read_to_string(&mut content).expect("Cannot read stdin");
-    } else {
-        let mut file = File::open(&path).map_err(|err| err.to_string())?;
-        file.read_to_string(&mut content).expect(format!("Cannot read the file {path}").as_str());
+    if path != "-" {
+        return bc::load(&path);
+    }
+    let mut content = vec!();
+    std::io::stdin().read_to_end(&mut content).map_err(|err| err.to_string())?;
+    bc::read(&content)
+}
+
+fn open_module(path: String) -> Result<Module, Error> {
+    load_module(path.clone()).map_err(|err| Error::Failed(format!("Cannot open module {}: {}", path, err)))
+}
+
+// `bin encode IN OUT`, writes the module at IN to OUT in the binary encoding
+fn encode<I: Iterator<Item = String>>(mut args: I) -> Result<(), Error> {
+    let (input, output) = match (args.next(), args.next(), args.next()) {
+        (Some(input), Some(output), None) => (input, output),
+        _ => return Err(Error::Usage("encode needs a module and where to write it".to_string())),
+    };
+    let module = open_module(input)?;
+    fs::write(&output, bc::encode(&module)).map_err(|err| Error::Failed(format!("Cannot write {}: {}", output, err)))
+}
+
+// `bin gen-stress [--functions N] [--strings N] [--depth N]`, writes the module to stdout
+fn gen_stress<I: Iterator<Item = String>>(mut args: I) -> Result<(), Error> {
+    let mut options = StressOptions::default();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--functions" => &mut options.functions,
+            "--strings" => &mut options.strings,
+            "--depth" => &mut options.depth,
+            _ => return Err(Error::Usage(format!("unknown gen-stress option {}", arg))),
+        };
+        *target = value(&mut args, &arg, "a count")?;
+    }
+    let module = stress::generate(&options);
+    serde_json::to_writer(std::io::stdout(), &module).map_err(|err| Error::Failed(format!("Cannot write module: {}", err)))
+}
+
+// `bin checksum FILE...`, prints what each module's `checksum` should be
+fn checksum<I: Iterator<Item = String>>(args: I) -> Result<(), Error> {
+    for path in args {
+        let module = open_module(path.clone())?;
+        println!("{}  {}", bc::checksum(&module), path);
+    }
+    Ok(())
+}
+
+fn open_modules<I: Iterator<Item = String>>(args: I) -> Result<HashMap<Vec<String>, Module>, Error> {
+    let mut modules = HashMap::new();
+    for path in args {
+        let bundle = load_modules(&path).map_err(|err| Error::Failed(format!("Cannot open module {}: {}", path, err)))?;
+        modules.extend(bundle.modules.into_iter().map(|module| (module.name.clone(), module)));
+    }
+    Ok(modules)
+}
+
+// `bin disasm FILE...`, lists the modules as assembly to stdout
+fn disassemble<I: Iterator<Item = String>>(args: I) -> Result<(), Error> {
+    let modules = open_modules(args)?;
+    match Program::link(None, &modules) {
+        Ok(program) => print!("{}", program.disassemble()),
+        // Still worth a look, it's probably why
+        Err(err) => {
+            eprintln!("Cannot link: {}, listing the modules as they are", err);
+            let mut names: Vec<&Vec<String>> = modules.keys().collect();
+            names.sort();
+            let listings: Vec<String> = names.iter().map(|name| asm::disassemble(&modules[*name], None)).collect();
+            print!("{}", listings.join("\n"));
+        }
+    }
+    Ok(())
+}
+
+// `bin symbols FILE...`, lists each module's functions, dependencies first
+fn symbols<I: Iterator<Item = String>>(args: I) -> Result<(), Error> {
+    let modules = open_modules(args)?;
+    let program = Program::link(None, &modules).map_err(|err| Error::Failed(format!("Cannot link: {}", err)))?;
+    for module in program.modules() {
+        println!("{}", module.name().join("."));
+        for function in module.functions() {
+            let arity = function.arity().map_or("-".to_string(), |arity| arity.to_string());
+            let mut notes = vec!();
+            if function.is_exported() {
+                notes.push("exported");
+            }
+            if function.name() == module.entrypoint() {
+                notes.push("entrypoint");
+            }
+            println!("  {:<24} {:>3}  {}", function.name(), arity, notes.join(", "));
+        }
+    }
+    Ok(())
+}
+
+// `bin repl [FILE]...`, runs each line from stdin as the MAIN of a module depending on the ones
+// given. Nothing carries over from one line to the next
+fn repl<I: Iterator<Item = String>>(args: I) -> Result<(), Error> {
+    let modules = open_modules(args)?;
+    let mut names: Vec<&Vec<String>> = modules.keys().collect();
+    names.sort();
+    let header: String = names.iter().map(|name| format!("depends {}\n", name.join("."))).collect();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().map_err(|err| Error::Failed(err.to_string()))?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(|err| Error::Failed(err.to_string()))? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let source = format!("module Repl\n{}fn MAIN\n{}\n", header, line.replace(';', "\n"));
+        let module = match asm::assemble(&source) {
+            Ok(module) => module,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let mut program = modules.clone();
+        program.insert(module.name.clone(), module);
+        let config = VmConfig::default();
+        // A trap is reported as it happens, then it's on to the next line
+        match panic::catch_unwind(AssertUnwindSafe(|| {
+            lib::vm::run(vec!["Repl".to_string()], program, config).map_err(|err| err.to_string())
+        })) {
+            Ok(Ok(Some(result))) => println!("{}", result),
+            Ok(Ok(None)) | Err(_) => {}
+            Ok(Err(err)) => eprintln!("Cannot link: {}", err),
+        }
+    }
+}
+
+// What the command line asks for, when it's to run modules
+struct Options {
+    config: VmConfig,
+    // Module files to run, in the order given
+    files: Vec<String>,
+    // Ones found in directories or with `--all`, to link along but never the entry
+    found: Vec<String>,
+    // Where to look for modules nobody passed, as `a.b.bc.json` or `a.b.undoc` for module a.b
+    module_path: Vec<String>,
+    // Every problem with a module that won't load, not just the first
+    all_errors: bool,
+    // Module to run instead of the first one
+    entry: Option<Vec<String>>,
+    // Run again whenever one of `watched` changes
+    watch: bool,
+    // Directories and manifests named, on top of the module files
+    watched: Vec<String>,
+}
+
+// `Module::Path::fn` to the module, if it says, and the function
+fn parse_entry(entry: String) -> Result<(Option<Vec<String>>, String), Error> {
+    let mut path: Vec<String> = entry.split("::").map(String::from).collect();
+    if path.iter().any(String::is_empty) {
+        return Err(Error::Usage(format!("--entry needs a function, optionally after its module, not {:?}", entry)));
+    }
+    let fun = path.pop().unwrap();
+    Ok((if path.is_empty() { None } else { Some(path) }, fun))
+}
+
+fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+    // The command line traces unless `--no-trace`, library callers have to ask
+    let mut options = Options { config: VmConfig { trace: true, ..VmConfig::default() }, files: vec!(), found: vec!(), module_path: vec!(), all_errors: false, entry: None,
+        watch: false, watched: vec!() };
+    let config = &mut options.config;
+    while let Some(arg) = args.next() {
+        // `-W lint` warns, `-D lint` fails linking, `-A lint` allows it again, `all` for every lint
+        let level = match arg.get(..2) {
+            Some("-W") => Some(Level::Warn),
+            Some("-D") => Some(Level::Deny),
+            Some("-A") => Some(Level::Allow),
+            _ => None,
+        };
+        if let Some(level) = level {
+            let name = match &arg[2..] {
+                "" => value(&mut args, &arg, "a lint name")?,
+                name => name.to_string(),
+            };
+            if name == "all" {
+                config.lints.extend(LINTS.iter().map(|(_, lint)| (*lint, level)));
+            } else {
+                let lint = Lint::from_name(&name).ok_or_else(|| Error::Usage(format!("unknown lint {}", name)))?;
+                config.lints.insert(lint, level);
+            }
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("undo-vm {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            "--debug" => config.debug = true,
+            "--check" => config.check = true,
+            "--watch" => options.watch = true,
+            "--all-errors" => options.all_errors = true,
+            "--no-trace" => config.trace = false,
+            "--heap-reserve" => config.arena_capacity = value(&mut args, &arg, "an object count")?,
+            "--max-heap" => config.max_heap = Some(value(&mut args, &arg, "an object count")?),
+            "--heap-limit" => {
+                let limit: String = value(&mut args, &arg, "a size")?;
+                config.heap_limit = Some(size(&limit).ok_or_else(|| Error::Usage(format!("--heap-limit needs a size like 64M, not {:?}", limit)))?);
+            }
+            "--max-frames" => config.max_frames = Some(value(&mut args, &arg, "a frame count")?),
+            "--gc" => config.gc = gc_strategy(value(&mut args, &arg, "a strategy name")?)?,
+            _ if arg.starts_with("--gc=") => config.gc = gc_strategy(arg["--gc=".len()..].to_string())?,
+            "--gc-threshold" => config.gc_threshold = value(&mut args, &arg, "an object count")?,
+            "--gc-slice" => config.gc_slice = value(&mut args, &arg, "a slot count")?,
+            "--heap-stats" => config.heap_stats = true,
+            "--dce" => config.eliminate_dead_code = true,
+            "--inline" => config.inline = true,
+            "--peephole" => config.peephole = true,
+            "--link-cache" => config.link_cache = Some(value(&mut args, &arg, "a file")?),
+            "--feature" => config.features.push(value(&mut args, &arg, "a feature name")?),
+            "--module-path" => options.module_path.push(value(&mut args, &arg, "a directory")?),
+            "--all" => options.found.extend(matching(&value::<_, String>(&mut args, &arg, "a pattern")?)?),
+            "--manifest" => {
+                let path: String = value(&mut args, &arg, "a file")?;
+                let manifest = manifest::load(&path).map_err(|err| Error::Failed(format!("Cannot read manifest {}: {}", path, err)))?;
+                options.watched.push(path);
+                for module in manifest.modules {
+                    if Path::new(&module).is_dir() {
+                        options.found.extend(in_directory(&module)?);
+                        options.watched.push(module);
+                    } else if module.contains('*') || module.contains('?') {
+                        options.found.extend(matching(&module)?);
+                    } else {
+                        options.files.push(module);
+                    }
+                }
+                if let Some(entry) = manifest.entry {
+                    let (module, fun) = parse_entry(entry)?;
+                    options.entry = module;
+                    config.entrypoint = Some(fun);
+                }
+                options.module_path.extend(manifest.module_path);
+                manifest.limits.apply(config);
+            }
+            "--profile" => config.profile = true,
+            "--error-format" => {
+                let format: String = value(&mut args, &arg, "a format")?;
+                config.error_format = ErrorFormat::from_name(&format)
+                    .ok_or_else(|| Error::Usage(format!("--error-format needs human or json, not {:?}", format)))?;
+            }
+            "--timings" => config.timings = true,
+            "--opcode-counts" => config.opcode_counts = true,
+            "--coverage" => config.coverage = Some(value(&mut args, &arg, "a file")?),
+            "--edges" => config.edges = Some(value(&mut args, &arg, "a file")?),
+            "--flamegraph" => config.flamegraph = Some(value(&mut args, &arg, "a file")?),
+            "--sample-every" => config.sample_every = value(&mut args, &arg, "an instruction count")?,
+            "--dap" => config.dap = Some(value(&mut args, &arg, "a port")?),
+            "--snapshot" => config.snapshot = Some(value(&mut args, &arg, "a file")?),
+            "--snapshot-every" => config.snapshot_every = value(&mut args, &arg, "an instruction count")?,
+            "--resume" => config.resume = Some(value(&mut args, &arg, "a snapshot file")?),
+            "--entry" => {
+                let (module, fun) = parse_entry(value(&mut args, &arg, "a function name")?)?;
+                options.entry = module;
+                config.entrypoint = Some(fun);
+            }
+            "--history-budget" => config.history_budget = value(&mut args, &arg, "a size in bytes")?,
+            "--exit-fuel" => config.exit_fuel = value(&mut args, &arg, "an instruction count")?,
+            // Files from here on, even if they look like options
+            "--" => {
+                options.files.extend(args);
+                break;
+            }
+            _ if arg.starts_with('-') && arg != "-" => return Err(Error::Usage(format!("unknown option {}", arg))),
+            _ if Path::new(&arg).is_dir() => {
+                options.found.extend(in_directory(&arg)?);
+                options.watched.push(arg);
+            }
+            _ => options.files.push(arg),
+        }
+    }
+    if options.files.is_empty() && options.found.is_empty() {
+        return Err(Error::Usage("no modules to run".to_string()));
+    }
+    if options.files.is_empty() && options.entry.is_none() {
+        return Err(Error::Usage("which module to run? pass its file first or name it with --entry".to_string()));
+    }
+    Ok(options)
+}
+
+fn in_directory(dir: &str) -> Result<Vec<String>, Error> {
+    directory(Path::new(dir)).map_err(|err| Error::Failed(format!("Cannot read {}: {}", dir, err)))
+}
+
+fn matching(pattern: &str) -> Result<Vec<String>, Error> {
+    let matched = glob(pattern);
+    if matched.is_empty() {
+        return Err(Error::Failed(format!("Nothing matches {}", pattern)));
+    }
+    Ok(matched)
+}
+
+const MODULE_EXTENSIONS: [&str; 4] = [".json", ".undoc", ".undob", ".undoasm"];
+
+// Every module file under `dir`, sorted
+fn directory(dir: &Path) -> io::Result<Vec<String>> {
+    let mut found = vec!();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(directory(&path)?);
+        } else if MODULE_EXTENSIONS.iter().any(|extension| path.to_string_lossy().ends_with(extension)) {
+            found.push(path.display().to_string());
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+// The files `pattern` matches, sorted. Wildcards may be in any component, and like in shells they
+// don't match a leading `.`
+fn glob(pattern: &str) -> Vec<String> {
+    let mut paths = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !part.contains('*') && !part.contains('?') {
+            for path in &mut paths {
+                path.push(component);
+            }
+            continue;
+        }
+        let wildcards: Vec<char> = part.chars().collect();
+        let mut matched = vec!();
+        for dir in &paths {
+            let entries = match fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }) {
+                Ok(entries) => entries,
+                // Not a directory, so nothing's in it
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+                if (name.first() != Some(&'.') || wildcards.first() == Some(&'.')) && matches(&wildcards, &name) {
+                    matched.push(dir.join(entry.file_name()));
+                }
+            }
+        }
+        paths = matched;
+    }
+    let mut found: Vec<String> = paths.into_iter().filter(|path| path.is_file()).map(|path| path.display().to_string()).collect();
+    found.sort();
+    found
+}
+
+// `*` is any run of characters, `?` any one
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(expected), Some(got)) if expected == got => matches(&pattern[1..], &name[1..]),
+        _ => false,
     }
-    serde_json::from_str(&content).map_err(|err| err.to_string())
 }
 
 fn main() {
-    let mut main: Vec<String> = Vec::new();
+    let result = match env::args().nth(1).as_deref() {
+        Some("run") => parse_options(env::args().skip(2)).and_then(run_or_watch),
+        Some("check") => parse_options(env::args().skip(2)).and_then(|mut options| {
+            options.config.check = true;
+            run_or_watch(options)
+        }),
+        Some("disasm") | Some("disassemble") => disassemble(env::args().skip(2)),
+        Some("symbols") => symbols(env::args().skip(2)),
+        Some("repl") => repl(env::args().skip(2)),
+        Some("encode") => encode(env::args().skip(2)),
+        Some("bundle") => bundle(env::args().skip(2)),
+        Some("checksum") => checksum(env::args().skip(2)),
+        Some("gen-stress") => gen_stress(env::args().skip(2)),
+        Some("help") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        // Files to run, like before there were commands
+        _ => parse_options(env::args().skip(1)).and_then(run_or_watch),
+    };
+    match result {
+        Ok(()) => {}
+        Err(Error::Usage(err)) => {
+            eprintln!("error: {}", err);
+            eprintln!("Run with --help for usage");
+            process::exit(2);
+        }
+        Err(Error::Failed(err)) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        Err(Error::Diagnosed(diagnostics)) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.to_json());
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn run_or_watch(options: Options) -> Result<(), Error> {
+    if options.watch {
+        return watch(options, env::args().skip(1).collect());
+    }
+    run(options)
+}
+
+// How often `--watch` looks for changes
+const WATCH_EVERY: Duration = Duration::from_millis(300);
+
+// `--watch`: runs the program with `args` minus that, in a process of its own, then again whenever
+// a file it's made of changes, with how the output changed. Polls modification times, which works
+// wherever the program does
+fn watch(options: Options, args: Vec<String>) -> Result<(), Error> {
+    let Options { files, found, watched, .. } = options;
+    if files.iter().any(|file| file == "-") {
+        return Err(Error::Usage("--watch can't watch stdin".to_string()));
+    }
+    let paths: Vec<String> = files.into_iter().chain(found).chain(watched).collect();
+    let modified = || -> Vec<Option<SystemTime>> {
+        paths.iter().map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok()).collect()
+    };
+    let args: Vec<String> = args.into_iter().filter(|arg| arg != "--watch").collect();
+    let exe = env::current_exe().map_err(|err| Error::Failed(format!("Cannot find this program to run it again: {}", err)))?;
+    let mut previous: Option<String> = None;
+    loop {
+        let seen = modified();
+        let ran = process::Command::new(&exe).args(&args).stderr(process::Stdio::inherit()).output()
+            .map_err(|err| Error::Failed(format!("Cannot run {}: {}", exe.display(), err)))?;
+        let output = String::from_utf8_lossy(&ran.stdout).into_owned();
+        match &previous {
+            None => print!("{}", output),
+            Some(previous) if *previous == output => println!("[same output]"),
+            Some(previous) => {
+                println!("[output changed]");
+                for line in diff(previous, &output) {
+                    println!("{}", line);
+                }
+            }
+        }
+        match ran.status.code() {
+            Some(code) => println!("[exited with {}, watching for changes]", code),
+            None => println!("[killed, watching for changes]"),
+        }
+        previous = Some(output);
+        while modified() == seen {
+            thread::sleep(WATCH_EVERY);
+        }
+    }
+}
+
+// `old` to `new` line by line, ` ` for lines in both, `-` and `+` for the rest
+fn diff(old: &str, new: &str) -> Vec<String> {
+    let (old, new): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    // Longest common subsequence of what's left of each, from the end
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec!();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(format!("  {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+// What `run` exits with when the program runs out of heap or frames, rather than trapping
+const LIMIT_EXIT_STATUS: i32 = 3;
+
+fn run(options: Options) -> Result<(), Error> {
+    let Options { config, mut files, found, module_path, all_errors, entry, .. } = options;
+    let format = config.error_format;
+    let mut main: Vec<String> = entry.unwrap_or_default();
     let mut modules: HashMap<Vec<String>, Module> = HashMap::new();
+    // Which file each module came from, and where that came from, for link errors
+    let mut paths: HashMap<Vec<String>, String> = HashMap::new();
+    let mut provenance: HashMap<Vec<String>, String> = HashMap::new();
 
-    // XXX this means `./undo-frontend` just errors, instead of behaving like `./undo-frontend -`
-    for arg in env::args().skip(1) {
-        eprintln!("Loading {}", arg);
+    let started = Instant::now();
+    // Only named ones may be the entry, and named ones aren't loaded again
+    let named = files.len();
+    let mut canonical: Vec<PathBuf> = files.iter().filter_map(|file| fs::canonicalize(file).ok()).collect();
+    for file in found {
+        let path = fs::canonicalize(&file).unwrap_or_else(|_| PathBuf::from(&file));
+        if !canonical.contains(&path) {
+            canonical.push(path);
+            files.push(file);
+        }
+    }
+    for file in &files {
+        eprintln!("Loading {}", file);
+    }
+    // Each on its own thread, big modules take a while to parse
+    let loaded: Vec<Result<Bundle, String>> = thread::scope(|scope| {
+        let parsing: Vec<_> = files.iter().map(|path| scope.spawn(move || load_modules(path))).collect();
+        parsing.into_iter().map(|parsing| parsing.join().unwrap()).collect()
+    });
+    for (idx, (arg, bundle)) in files.into_iter().zip(loaded).enumerate() {
+        let bundle = bundle.map_err(|err| {
+            let errors = match fs::read(&arg) {
+                Ok(bytes) if all_errors && !arg.ends_with(".undoasm") => bc::diagnose(&bytes),
+                _ => vec!(),
+            };
+            match (format, errors.is_empty()) {
+                (_, true) => failure(format, "load", format!("Cannot open module {}: {}", arg, err)),
+                (ErrorFormat::Human, false) => Error::Failed(format!("Cannot open module {}:\n  {}", arg, errors.join("\n  "))),
+                (ErrorFormat::Json, false) => Error::Diagnosed(errors.iter()
+                    .map(|err| Diagnostic::new("load", format!("Cannot open module {}: {}", arg, err)))
+                    .collect()),
+            }
+        })?;
+        if main.is_empty() && idx < named {
+            main = bundle.entry;
+        }
+        for module in bundle.modules {
+            let module_name = module.name.clone();
+            if let Some(first) = paths.insert(module_name.clone(), arg.clone()) {
+                let kind = LinkErrorKind::DuplicateModule(first, arg);
+                link_failed(LinkError { module: module_name, function: None, ip: None, span: None, kind }, &provenance, format);
+            }
+            let from = match module.provenance() {
+                Some(built) => format!("{}, {}", arg, built),
+                None => arg.clone(),
+            };
+            provenance.insert(module_name.clone(), from);
+            modules.insert(module_name, module);
+        }
+    }
 
-        let module = load_module(arg.clone()).expect(format!("Cannot open module {arg}").as_str());
-        let module_name = module.name.clone();
-        if main.is_empty() {
-            main = module_name.clone();
+    let mut resolver = |name: &[String]| -> Result<Option<Module>, String> {
+        let files = [format!("{}.bc.json", name.join(".")), format!("{}.undoc", name.join("."))];
+        let mut paths = module_path.iter().flat_map(|dir| files.iter().map(move |file| Path::new(dir).join(file)));
+        match paths.find(|path| path.is_file()) {
+            Some(path) => {
+                eprintln!("Loading {}", path.display());
+                load_module(path.display().to_string()).map(Some)
+            }
+            None => Ok(None),
         }
-        modules.insert(module_name, module);
+    };
+    // `--entry` may name one that's only on the module path
+    if !modules.contains_key(&main) {
+        let module = resolver(&main).map_err(|err| failure(format, "load", format!("Cannot open module {}: {}", main.join("."), err)))?
+            .filter(|module| module.name == main)
+            .ok_or_else(|| failure(format, "no-entry-module", format!("No module {} to start in", main.join("."))))?;
+        modules.insert(main.clone(), module);
+    }
+    if config.timings {
+        eprintln!("timings: loading {:.3} ms", profile::millis(started.elapsed()));
     }
+    // A trap or going over a limit is reported by the time it unwinds to here
+    let ran = panic::catch_unwind(AssertUnwindSafe(|| match lib::vm::run_with_resolver(main, modules, config, &mut resolver) {
+        // The program's result is its exit status
+        Ok(Some(status)) if (0..=255).contains(&status) => process::exit(status as i32),
+        Ok(Some(status)) => Err(failure(format, "exit-status", format!("The program returned {}, exit statuses go from 0 to 255", status))),
+        Ok(None) => Ok(()),
+        Err(RunError::Link(err)) => link_failed(err, &provenance, format),
+        Err(err) => Err(failure(format, err.code(), err.to_string())),
+    }));
+    ran.unwrap_or_else(|payload| match payload.downcast::<Diagnostic>() {
+        Ok(diagnostic) if ["frame-limit", "heap-limit"].contains(&diagnostic.code) => process::exit(LIMIT_EXIT_STATUS),
+        Ok(_) => process::exit(1),
+        Err(payload) => panic::resume_unwind(payload),
+    })
+}
 
-    lib::vm::run(main, modules);
+fn link_failed(err: LinkError, provenance: &HashMap<Vec<String>, String>, format: ErrorFormat) -> ! {
+    if format == ErrorFormat::Json {
+        eprintln!("{}", Diagnostic::from(&err).to_json());
+        process::exit(1);
+    }
+    eprintln!("Cannot link: {}", err);
+    if let Some(from) = provenance.get(&err.module) {
+        eprintln!("  {} is from {}", err.module.join("."), from);
+    }
+    process::exit(1);
 }
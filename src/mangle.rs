@@ -0,0 +1,59 @@
+// Overloads share a base name and are told apart by how many arguments they take, mangled as
+// `name/arity`. LoadName and LoadGlobal refer to the base name, calls pick the overload
+use std::collections::HashMap;
+use vm::Module;
+
+pub(crate) fn mangle(base: &str, arity: usize) -> String {
+    format!("{}/{}", base, arity)
+}
+
+// The base name, and the arity if it's mangled
+pub(crate) fn demangle(name: &str) -> (&str, Option<usize>) {
+    match name.rfind('/') {
+        Some(slash) => match name[slash + 1..].parse() {
+            Ok(arity) => (&name[..slash], Some(arity)),
+            Err(_) => (name, None),
+        },
+        None => (name, None),
+    }
+}
+
+// The function a call to `name` with `args` arguments runs: `name` itself if there's such a
+// function, otherwise its overload for that many arguments
+pub(crate) fn resolve<'m>(module: &'m Module, name: &str, args: usize) -> Option<&'m String> {
+    module.functions.get_key_value(name)
+        .or_else(|| module.functions.get_key_value(&mangle(name, args)))
+        .map(|(name, _)| name)
+}
+
+// Every function each base name may refer to, module by module: the function of that name and
+// all its overloads. Built once per link, so every LoadName and LoadGlobal is a lookup
+// rather than a pass over the target's functions
+pub(crate) struct Overloads<'m>(HashMap<&'m [String], HashMap<&'m str, Vec<&'m String>>>);
+
+impl<'m> Overloads<'m> {
+    pub(crate) fn new(modules: &'m HashMap<Vec<String>, Module>) -> Self {
+        Overloads(modules.iter().map(|(name, module)| (name.as_slice(), index(module))).collect())
+    }
+
+    // Empty if there's no such module or function
+    pub(crate) fn of(&self, module: &[String], name: &str) -> &[&'m String] {
+        self.0.get(module).and_then(|names| names.get(name)).map_or(&[], Vec::as_slice)
+    }
+}
+
+fn index(module: &Module) -> HashMap<&str, Vec<&String>> {
+    let mut names: HashMap<&str, Vec<&String>> = HashMap::new();
+    for fun in module.functions.keys() {
+        names.entry(fun).or_default().push(fun);
+        if let (base, Some(_)) = demangle(fun) {
+            names.entry(base).or_default().push(fun);
+        }
+    }
+    names
+}
+
+// How many arguments `fun` takes, if declared or mangled into its name
+pub(crate) fn arity(module: &Module, fun: &str) -> Option<usize> {
+    module.arities.get(fun).cloned().or(demangle(fun).1)
+}
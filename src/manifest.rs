@@ -0,0 +1,72 @@
+// `undo.json`, what to run and how, so it doesn't have to be spelled out on the command line:
+//
+//     {
+//         "modules": ["main.bc.json", "lib", "gen/*.undoc"],
+//         "entry": "Main::start",
+//         "module_path": ["vendor"],
+//         "limits": { "max_heap": 100000, "exit_fuel": 1000 }
+//     }
+//
+// Modules are files, directories or patterns as on the command line, and the first file is the
+// entry module unless `entry` says otherwise, written like `--entry`. Relative paths are from the
+// manifest's directory.
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use serde::Deserialize;
+use vm::VmConfig;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub modules: Vec<String>,
+    pub entry: Option<String>,
+    // Where to look for modules nobody listed
+    #[serde(default)]
+    pub module_path: Vec<String>,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+// Like `--heap-reserve`, `--max-heap`, `--heap-limit` (in bytes), `--max-frames` and
+// `--exit-fuel`, any left out are the defaults
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub heap_reserve: Option<usize>,
+    pub max_heap: Option<usize>,
+    pub heap_limit: Option<usize>,
+    pub max_frames: Option<usize>,
+    pub exit_fuel: Option<usize>,
+}
+
+impl Limits {
+    pub fn apply(&self, config: &mut VmConfig) {
+        if let Some(reserve) = self.heap_reserve {
+            config.arena_capacity = reserve;
+        }
+        if self.max_heap.is_some() {
+            config.max_heap = self.max_heap;
+        }
+        if self.heap_limit.is_some() {
+            config.heap_limit = self.heap_limit;
+        }
+        if self.max_frames.is_some() {
+            config.max_frames = self.max_frames;
+        }
+        if let Some(fuel) = self.exit_fuel {
+            config.exit_fuel = fuel;
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<Manifest, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut manifest: Manifest = serde_json::from_reader(BufReader::new(file)).map_err(|err| err.to_string())?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    for relative in manifest.modules.iter_mut().chain(&mut manifest.module_path) {
+        *relative = dir.join(&relative).display().to_string();
+    }
+    Ok(manifest)
+}
@@ -0,0 +1,572 @@
+// Optional passes over linked modules, before anything runs
+use std::collections::{HashMap, HashSet};
+use mangle::{self, Overloads};
+use vm::{is_prelude, Instruction, Location, Module, ModuleName, Origin};
+
+// Drops every function the entrypoint can't reach through LoadName/LoadGlobal, then the strings nothing
+// pushes anymore. Any reference counts, not just calls, since function refs can be passed around,
+// and keeps every overload of the name.
+// Returns how many functions and strings went
+pub(crate) fn eliminate_dead_code(main: &[String], modules: &mut HashMap<Vec<String>, Module>) -> (usize, usize) {
+    let overloads = Overloads::new(modules);
+    let mut live: HashSet<(Vec<String>, String)> = HashSet::new();
+    let mut work = vec![(main.to_vec(), modules[main].entrypoint().to_string())];
+    while let Some((module, fun)) = work.pop() {
+        if !live.insert((module.clone(), fun.clone())) {
+            continue;
+        }
+        // Dynamic dependencies aren't loaded yet
+        let instructions = match modules.get(&module) {
+            Some(loaded) => &loaded.functions[&fun],
+            None => continue,
+        };
+        for instruction in instructions {
+            match instruction {
+                Instruction::LoadName(namespace, name) if !is_prelude(namespace) => match modules.get(&namespace.module) {
+                    Some(_) => work.extend(overloads.of(&namespace.module, name).iter().map(|fun| (namespace.module.clone(), fun.to_string()))),
+                    None => work.push((namespace.module.clone(), name.clone())),
+                },
+                Instruction::LoadGlobal(name) =>
+                    work.extend(overloads.of(&module, name).iter().map(|fun| (module.clone(), fun.to_string()))),
+                _ => {}
+            }
+        }
+    }
+
+    let (mut functions, mut strings) = (0, 0);
+    for module in modules.values_mut() {
+        let before = module.functions.len();
+        let name = module.name.clone();
+        module.functions.retain(|fun, _| live.contains(&(name.clone(), fun.clone())));
+        functions += before - module.functions.len();
+        let kept = |fun: &String| live.contains(&(name.clone(), fun.clone()));
+        module.arities.retain(|fun, _| kept(fun));
+        module.source_map.retain(|fun, _| kept(fun));
+        module.local_names.retain(|fun, _| kept(fun));
+        module.origins.retain(|fun, _| kept(fun));
+        if let Some(exports) = module.exports.as_mut() {
+            exports.retain(kept);
+        }
+        strings += compact_strings(module);
+    }
+    (functions, strings)
+}
+
+// Renumbers PushStrings so the string table only has what they use, returns how many were dropped
+fn compact_strings(module: &mut Module) -> usize {
+    let mut used = vec![false; module.strings.len()];
+    for instruction in module.functions.values().flatten() {
+        if let Instruction::PushString(idx) = instruction {
+            used[*idx] = true;
+        }
+    }
+    let mut renumbered = vec![None; module.strings.len()];
+    let mut strings = vec!();
+    for (idx, string) in module.strings.drain(..).enumerate() {
+        if used[idx] {
+            renumbered[idx] = Some(strings.len());
+            strings.push(string);
+        }
+    }
+    let dropped = used.len() - strings.len();
+    module.strings = strings;
+    for instruction in module.functions.values_mut().flatten() {
+        if let Instruction::PushString(idx) = instruction {
+            *idx = renumbered[*idx].unwrap();
+        }
+    }
+    dropped
+}
+
+// Longest callee body worth inlining
+const INLINE_LIMIT: usize = 8;
+
+// Replaces calls to small straight-line functions with their body, where the callee is pushed
+// right before the call. Arguments get stored to fresh locals past the caller's, so it only
+// happens where the caller has the same number of locals initialized whichever way it got there,
+// which needs knowing its arity (MAIN has none). Jumps are renumbered around the longer code.
+// Profilers see the inlined version, the debugger goes by where each instruction came from.
+// Returns how many calls were inlined
+pub(crate) fn inline(modules: &mut HashMap<Vec<String>, Module>) -> usize {
+    let mut inlined = 0;
+    // Worked out against the original modules, then swapped in
+    let mut rewritten = vec!();
+    for module in modules.values() {
+        let mut strings = module.strings.clone();
+        let mut functions = vec!();
+        for (fun, instructions) in &module.functions {
+            let arguments = match mangle::arity(module, fun) {
+                Some(arguments) => arguments,
+                None if fun == module.entrypoint() => 0,
+                None => continue,
+            };
+            let (body, locations, origins, count) = inline_calls(module, fun, instructions, arguments, &mut strings, modules);
+            if count > 0 {
+                functions.push((fun.clone(), body, locations, origins));
+                inlined += count;
+            }
+        }
+        rewritten.push((module.name.clone(), strings, functions));
+    }
+    for (name, strings, functions) in rewritten {
+        let module = modules.get_mut(&name).unwrap();
+        module.strings = strings;
+        for (fun, body, locations, origins) in functions {
+            if locations.iter().any(Option::is_some) {
+                module.source_map.insert(fun.clone(), locations);
+            }
+            module.origins.insert(fun.clone(), origins);
+            module.functions.insert(fun, body);
+        }
+    }
+    inlined
+}
+
+fn inline_calls(
+    module: &Module,
+    fun: &str,
+    instructions: &[Instruction],
+    arguments: usize,
+    strings: &mut Vec<String>,
+    modules: &HashMap<Vec<String>, Module>,
+) -> (Vec<Instruction>, Vec<Option<Location>>, Vec<Origin>, usize) {
+    let locals = locals_range(instructions, arguments);
+    let targets: HashSet<usize> = instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Jump(target) | Instruction::Unless(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+
+    let mut body = vec!();
+    // Inlined instructions keep where they came from in the callee, the argument stores are the call
+    let mut locations = vec!();
+    let mut origins = vec!();
+    // A call inlined without arguments to store has nothing left of it, the next instruction
+    // stands for it
+    let mut dropped = None;
+    // Where each original instruction ended up, plus the end
+    let mut moved = vec!();
+    let mut inlined = 0;
+    let mut ip = 0;
+    while ip < instructions.len() {
+        moved.push(body.len());
+        let callee = match (&instructions[ip], instructions.get(ip + 1)) {
+            (Instruction::LoadName(namespace, name), Some(Instruction::Call(n))) if !is_prelude(namespace) =>
+                Some((&namespace.module, name, *n)),
+            (Instruction::LoadGlobal(name), Some(Instruction::Call(n))) => Some((&module.name, name, *n)),
+            _ => None,
+        };
+        let site = callee.and_then(|(callee_module, name, n)| match locals[ip] {
+            // Nothing may jump to the call without going through the callee
+            Some((min, max)) if min == max && !targets.contains(&(ip + 1)) => {
+                let callee = modules.get(callee_module)?;
+                let name = mangle::resolve(callee, name, n)?;
+                let inlinable = !(callee.name == module.name && name == fun)
+                    && callee.arities.get(name).is_none_or(|arity| *arity == n)
+                    && is_inlinable(&callee.functions[name]);
+                if inlinable { Some((callee, name, n, min)) } else { None }
+            }
+            _ => None,
+        });
+        match site {
+            Some((callee, name, n, base)) => {
+                moved.push(body.len());
+                body.extend((0..n).map(|i| Instruction::StoreLocal(base + i)));
+                locations.extend((0..n).map(|_| module.location(fun, ip + 1).cloned()));
+                let call = absorb(origin(module, fun, ip + 1), Some(origin(module, fun, ip)));
+                if n == 0 {
+                    dropped = Some(call);
+                } else {
+                    origins.extend((0..n).map(|_| call.clone()));
+                }
+                for (callee_ip, instruction) in callee.functions[name].iter().enumerate() {
+                    body.push(relocate(instruction, callee, module, base, strings));
+                    locations.push(callee.location(name, callee_ip).cloned());
+                    origins.push(origin(callee, name, callee_ip));
+                }
+                inlined += 1;
+                ip += 2;
+            }
+            None => {
+                body.push(instructions[ip].clone());
+                locations.push(module.location(fun, ip).cloned());
+                origins.push(absorb(origin(module, fun, ip), dropped.take()));
+                ip += 1;
+            }
+        }
+    }
+    moved.push(body.len());
+    if inlined > 0 {
+        // Inlined bodies are straight-line, so any jump is the caller's own
+        for instruction in body.iter_mut() {
+            if let Instruction::Jump(target) | Instruction::Unless(target) = instruction {
+                *target = moved[*target];
+            }
+        }
+    }
+    (body, locations, origins, inlined)
+}
+
+// Where the instruction at `ip` of `fun` was before any pass
+fn origin(module: &Module, fun: &str, ip: usize) -> Origin {
+    module.origin(fun, ip).cloned().unwrap_or_else(|| Origin { module: module.name.clone(), fun: fun.to_string(), ips: (ip, ip) })
+}
+
+// `origin` also standing for the instructions `dropped` stood for, if they were of the same function
+fn absorb(mut origin: Origin, dropped: Option<Origin>) -> Origin {
+    if let Some(dropped) = dropped.filter(|dropped| dropped.module == origin.module && dropped.fun == origin.fun) {
+        origin.ips.0 = origin.ips.0.min(dropped.ips.0);
+    }
+    origin
+}
+
+fn is_inlinable(instructions: &[Instruction]) -> bool {
+    instructions.len() <= INLINE_LIMIT && !instructions.iter().any(|instruction| matches!(instruction,
+        Instruction::Jump(_) | Instruction::Unless(_) | Instruction::Checkpoint | Instruction::Rollback | Instruction::Commit
+    ))
+}
+
+// A callee instruction as the caller runs it: locals past the caller's, and references
+// into the callee's module made explicit
+fn relocate(instruction: &Instruction, callee: &Module, caller: &Module, base: usize, strings: &mut Vec<String>) -> Instruction {
+    match instruction {
+        Instruction::LoadLocal(idx) => Instruction::LoadLocal(base + idx),
+        Instruction::StoreLocal(idx) => Instruction::StoreLocal(base + idx),
+        Instruction::PushString(idx) if callee.name != caller.name => {
+            let string = &callee.strings[*idx];
+            match strings.iter().position(|s| s == string) {
+                Some(idx) => Instruction::PushString(idx),
+                None => {
+                    strings.push(string.clone());
+                    Instruction::PushString(strings.len() - 1)
+                }
+            }
+        }
+        Instruction::LoadGlobal(name) if callee.name != caller.name =>
+            Instruction::LoadName(ModuleName::new(callee.name.clone()), name.clone()),
+        instruction => instruction.clone(),
+    }
+}
+
+// Fewest and most locals initialized on entry to each instruction, None if it's never reached
+fn locals_range(instructions: &[Instruction], arguments: usize) -> Vec<Option<(usize, usize)>> {
+    let mut entry: Vec<Option<(usize, usize)>> = vec![None; instructions.len() + 1];
+    entry[0] = Some((arguments, arguments));
+    let mut work = vec![0];
+    while let Some(ip) = work.pop() {
+        let (mut min, mut max) = entry[ip].unwrap();
+        let successors = match instructions.get(ip) {
+            Some(Instruction::StoreLocal(idx)) => {
+                min = min.max(idx + 1);
+                max = max.max(idx + 1);
+                vec![ip + 1]
+            }
+            Some(Instruction::Jump(target)) => vec![*target],
+            Some(Instruction::Unless(target)) => vec![ip + 1, *target],
+            Some(Instruction::Rollback) | None => vec!(),
+            Some(_) => vec![ip + 1],
+        };
+        for next in successors {
+            let joined = entry[next].map_or((min, max), |(old_min, old_max)| (old_min.min(min), old_max.max(max)));
+            if entry[next] != Some(joined) {
+                entry[next] = Some(joined);
+                work.push(next);
+            }
+        }
+    }
+    entry
+}
+
+// Cleans up what frontends naively emit: jumps to jumps go straight to the end of the chain,
+// `PushInt; Unless` becomes a jump or nothing, and jumps to the next instruction go away.
+// `StoreLocal(i); LoadLocal(i)` stays, there's no cheaper way to put it without a Dup.
+// Returns how many rewrites it made
+pub(crate) fn peephole(modules: &mut HashMap<Vec<String>, Module>) -> usize {
+    let mut rewrites = 0;
+    for module in modules.values_mut() {
+        let name = &module.name;
+        for (fun, instructions) in module.functions.iter_mut() {
+            let mut locations = module.source_map.get_mut(fun);
+            let mut origins = module.origins.get(fun).cloned().unwrap_or_else(|| {
+                (0..instructions.len()).map(|ip| Origin { module: name.clone(), fun: fun.clone(), ips: (ip, ip) }).collect()
+            });
+            let mut made_any = false;
+            // Each rewrite may open up another
+            loop {
+                let made = thread_jumps(instructions) + fold_branches(instructions, locations.as_deref_mut(), &mut origins);
+                if made == 0 {
+                    break;
+                }
+                rewrites += made;
+                made_any = true;
+            }
+            if made_any {
+                module.origins.insert(fun.clone(), origins);
+            }
+        }
+    }
+    rewrites
+}
+
+fn thread_jumps(instructions: &mut [Instruction]) -> usize {
+    let mut threaded = 0;
+    for ip in 0..instructions.len() {
+        let target = match instructions[ip] {
+            Instruction::Jump(target) | Instruction::Unless(target) => target,
+            _ => continue,
+        };
+        // Bounded, jumps may well loop forever
+        let mut end = target;
+        for _ in 0..instructions.len() {
+            match instructions.get(end) {
+                Some(Instruction::Jump(next)) if *next != end => end = *next,
+                _ => break,
+            }
+        }
+        if end != target {
+            if let Instruction::Jump(target) | Instruction::Unless(target) = &mut instructions[ip] {
+                *target = end;
+            }
+            threaded += 1;
+        }
+    }
+    threaded
+}
+
+// Keeps `locations` in step, if the function has them, and `origins`
+fn fold_branches(instructions: &mut Vec<Instruction>, locations: Option<&mut Vec<Option<Location>>>, origins: &mut Vec<Origin>) -> usize {
+    let targets: HashSet<usize> = instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Jump(target) | Instruction::Unless(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+    let mut folded = vec!();
+    // Original offset of each folded instruction
+    let mut from = vec!();
+    // Where each original instruction ended up, plus the end
+    let mut moved = vec!();
+    let mut rewrites = 0;
+    let mut ip = 0;
+    while ip < instructions.len() {
+        moved.push(folded.len());
+        match (&instructions[ip], instructions.get(ip + 1)) {
+            // Unless only jumps on a 0, and nothing else may reach it with another value
+            (Instruction::PushInt(n), Some(Instruction::Unless(target))) if !targets.contains(&(ip + 1)) => {
+                moved.push(folded.len());
+                if *n == 0 {
+                    folded.push(Instruction::Jump(*target));
+                    from.push(ip + 1);
+                }
+                rewrites += 1;
+                ip += 2;
+            }
+            (Instruction::Jump(target), _) if *target == ip + 1 => {
+                rewrites += 1;
+                ip += 1;
+            }
+            (instruction, _) => {
+                folded.push(instruction.clone());
+                from.push(ip);
+                ip += 1;
+            }
+        }
+    }
+    moved.push(folded.len());
+    if rewrites > 0 {
+        for instruction in folded.iter_mut() {
+            if let Instruction::Jump(target) | Instruction::Unless(target) = instruction {
+                *target = moved[*target];
+            }
+        }
+        *instructions = folded;
+        if let Some(locations) = locations {
+            *locations = from.iter().map(|ip| locations.get(*ip).cloned().flatten()).collect();
+        }
+        // Each dropped instruction goes with the next one kept
+        let mut next = 0;
+        *origins = from.iter().map(|ip| {
+            let dropped = origins[next..*ip].iter().cloned().reduce(|first, last| absorb(last, Some(first)));
+            next = ip + 1;
+            absorb(origins[*ip].clone(), dropped)
+        }).collect();
+    }
+    rewrites
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use asm;
+    use embed::{Captured, VmBuilder};
+    use vm::VmConfig;
+    use super::*;
+
+    fn name(module: &str) -> Vec<String> {
+        vec!(module.to_string())
+    }
+
+    fn listing(instructions: &[Instruction]) -> Vec<String> {
+        instructions.iter().map(|instruction| format!("{:?}", instruction)).collect()
+    }
+
+    // What it prints, run with the config's passes
+    fn printed(source: &str, config: VmConfig) -> Vec<String> {
+        let output = Arc::new(Captured::default());
+        let vm = VmBuilder::new().config(VmConfig { quiet: true, ..config }).output(output.clone())
+            .module(asm::assemble(source).unwrap())
+            .build().unwrap();
+        vm.run().unwrap();
+        output.lines()
+    }
+
+    const BRANCHES: &str = "
+module Main
+
+fn MAIN
+at main.undo:1:1
+    Jump first
+    PushInt 9
+first:
+    Jump second
+second:
+at main.undo:2:1
+    PushInt 0
+    Unless zero
+    PushString \"not taken\"
+    LoadName Prelude.print
+    Call 1
+zero:
+at main.undo:3:1
+    PushInt 1
+    Unless end
+    PushString \"taken\"
+    LoadName Prelude.print
+    Call 1
+end:
+";
+
+    #[test]
+    fn peephole_folds_jumps_and_constant_branches() {
+        let mut modules = asm::modules(&[BRANCHES]);
+        assert!(peephole(&mut modules) > 0);
+        let main = &modules[&name("Main")];
+        let print = "LoadName(ModuleName { module: [\"Prelude\"] }, \"print\")";
+        // The first print stays, nothing reaches it anymore but that isn't for this pass to drop
+        assert_eq!(listing(&main.functions["MAIN"]), vec!(
+            "Jump(6)", "PushInt(9)", "Jump(6)", "PushString(0)", print, "Call(1)", "PushString(1)", print, "Call(1)",
+        ));
+        // Source locations still line up with what they're for
+        let lines: Vec<_> = main.source_map["MAIN"].iter().map(|location| location.as_ref().unwrap().line).collect();
+        assert_eq!(lines, vec!(1, 1, 2, 2, 2, 2, 3, 3, 3));
+        // Dropped instructions go with the next one kept
+        let origins: Vec<_> = main.origins["MAIN"].iter().map(|origin| origin.ips).collect();
+        assert_eq!(origins, vec!((0, 0), (1, 1), (2, 4), (5, 5), (6, 6), (7, 7), (8, 10), (11, 11), (12, 12)));
+        // Nothing left to do the second time round
+        assert_eq!(peephole(&mut modules), 0);
+    }
+
+    #[test]
+    fn inlined_code_remembers_where_it_came_from() {
+        let mut modules = asm::modules(&["
+module Main
+
+fn MAIN
+    PushInt 4
+    LoadGlobal twice
+    Call 1
+    LoadName Prelude.print
+    Call 1
+    LoadGlobal four
+    Call 0
+    PushString \"done\"
+
+fn twice 1
+    LoadLocal 0
+    LoadLocal 0
+    LoadName Prelude.+
+    Call 2
+
+fn four
+    PushInt 4
+"]);
+        assert_eq!(inline(&mut modules), 2);
+        let main = &modules[&name("Main")];
+        let origins: Vec<_> = main.origins["MAIN"].iter().map(|origin| (origin.fun.as_str(), origin.ips)).collect();
+        assert_eq!(origins, vec!(
+            ("MAIN", (0, 0)),
+            // The argument's store is the call
+            ("MAIN", (1, 2)),
+            ("twice", (0, 0)), ("twice", (1, 1)), ("twice", (2, 2)), ("twice", (3, 3)),
+            ("MAIN", (3, 3)), ("MAIN", (4, 4)),
+            ("four", (0, 0)),
+            // Nothing's left of a call without arguments, what comes after stands for it
+            ("MAIN", (5, 7)),
+        ));
+        assert!(!main.origins.contains_key("twice"));
+    }
+
+    #[test]
+    fn peephole_keeps_what_programs_print() {
+        let loops = "
+module Main
+
+fn MAIN
+    PushInt 3
+    StoreLocal 0
+loop:
+    Jump test
+test:
+    LoadLocal 0
+    Unless done
+    LoadLocal 0
+    LoadName Prelude.print
+    Call 1
+    PushInt 1
+    LoadLocal 0
+    LoadName Prelude.-
+    Call 2
+    StoreLocal 0
+    Jump loop
+done:
+    PushInt 1
+    Unless loop
+    PushString \"done\"
+    LoadName Prelude.print
+    Call 1
+";
+        for source in &[BRANCHES, loops] {
+            let before = printed(source, VmConfig::default());
+            assert_eq!(printed(source, VmConfig { peephole: true, ..VmConfig::default() }), before);
+        }
+    }
+
+    #[test]
+    fn dead_code_goes_with_its_strings() {
+        let mut modules = asm::modules(&[
+            "module Main\ndepends Lib\nfn MAIN\n    PushString \"kept\"\n    LoadName Lib.used\n    Call 1\nfn unused\n    PushString \"dropped\"\n    LoadGlobal unused\n    Call 0\n",
+            "module Lib\nfn used/0\n    PushString \"a\"\nfn used/1 1\n    LoadLocal 0\nfn unused\n    PushInt 1\n",
+        ]);
+        assert_eq!(eliminate_dead_code(&name("Main"), &mut modules), (2, 1));
+        let main = &modules[&name("Main")];
+        assert_eq!(main.functions.keys().collect::<Vec<_>>(), vec!("MAIN"));
+        assert_eq!(main.strings, vec!("kept"));
+        assert_eq!(listing(&main.functions["MAIN"])[0], "PushString(0)");
+        // Every overload of a name that's referred to stays
+        let mut lib: Vec<_> = modules[&name("Lib")].functions.keys().cloned().collect();
+        lib.sort();
+        assert_eq!(lib, vec!("used/0", "used/1"));
+        assert_eq!(modules[&name("Lib")].strings, vec!("a"));
+    }
+
+    // An embedder may call any function later, so building never drops one
+    #[test]
+    fn builders_keep_every_function() {
+        let source = "module Main\nfn MAIN\n    PushInt 1\nfn helper\n    PushInt 2\n";
+        let vm = VmBuilder::new().config(VmConfig { eliminate_dead_code: true, ..VmConfig::default() })
+            .module(asm::assemble(source).unwrap())
+            .build().unwrap();
+        assert_eq!(vm.call::<_, i64>("helper", ()).unwrap(), 2);
+    }
+}
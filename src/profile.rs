@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+use debugger::qualified_name;
+use vm::{cur_fn, format_module_name, step, Instruction, Module, State};
+
+#[derive(Default)]
+struct FnProfile {
+    calls: usize,
+    // Time spent with the function on the frame stack, not counting recursive calls twice
+    inclusive: Duration,
+    // Time spent running the function's own instructions
+    exclusive: Duration,
+    allocs: usize,
+}
+
+// Per-function timings for `--profile`, printed once the program is done
+pub(crate) struct Profiler {
+    functions: HashMap<String, FnProfile>,
+    // Function and entry time of every live frame, innermost last
+    active: Vec<(String, Duration)>,
+    // Time spent executing instructions so far, so the interpreter's own output isn't counted
+    clock: Duration,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Profiler {
+            functions: HashMap::new(),
+            active: vec!(),
+            clock: Duration::default(),
+        }
+    }
+
+    // Runs the current instruction, accounting for it
+    pub(crate) fn step<'a>(&mut self, state: &mut State<'a>, modules: &'a HashMap<Vec<String>, Module>) {
+        self.sync(state);
+        let fun = qualified_name(state.frames.back().expect("No current frame?!"));
+        let arena_len = state.gc.len();
+        let start = Instant::now();
+        step(state, modules, false);
+        let elapsed = start.elapsed();
+        self.clock += elapsed;
+
+        let profile = self.functions.entry(fun).or_default();
+        profile.exclusive += elapsed;
+        profile.allocs += state.gc.len().saturating_sub(arena_len);
+        self.sync(state);
+    }
+
+    // Matches `active` up with the frames, for calls, returns and rollbacks.
+    // Only the innermost frame is compared, anything below it is assumed unchanged
+    fn sync(&mut self, state: &State) {
+        while self.active.len() > state.frames.len() {
+            self.leave();
+        }
+        let top = state.frames.back().map(qualified_name);
+        if self.active.len() == state.frames.len() && self.active.last().map(|(fun, _)| fun) != top.as_ref() {
+            self.leave();
+        }
+        for frame in state.frames.iter().skip(self.active.len()) {
+            let fun = qualified_name(frame);
+            self.functions.entry(fun.clone()).or_default().calls += 1;
+            self.active.push((fun, self.clock));
+        }
+    }
+
+    fn leave(&mut self) {
+        let (fun, entered) = self.active.pop().unwrap();
+        if !self.active.iter().any(|(active, _)| *active == fun) {
+            self.functions.entry(fun).or_default().inclusive += self.clock - entered;
+        }
+    }
+
+    pub(crate) fn report(mut self) {
+        while !self.active.is_empty() {
+            self.leave();
+        }
+        let mut functions: Vec<(String, FnProfile)> = self.functions.into_iter().collect();
+        functions.sort_by(|(a_fun, a), (b_fun, b)| b.exclusive.cmp(&a.exclusive).then(a_fun.cmp(b_fun)));
+        let width = functions.iter().map(|(fun, _)| fun.len()).max().unwrap_or(0).max("function".len());
+        eprintln!("{:width$}  {:>8}  {:>12}  {:>12}  {:>8}", "function", "calls", "incl ms", "excl ms", "allocs", width = width);
+        for (fun, profile) in functions {
+            eprintln!(
+                "{:width$}  {:>8}  {:>12.3}  {:>12.3}  {:>8}",
+                fun,
+                profile.calls,
+                millis(profile.inclusive),
+                millis(profile.exclusive),
+                profile.allocs,
+                width = width,
+            );
+        }
+    }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// How many times each instruction ran, for `--opcode-counts`. Function returns count as `Return`
+pub(crate) struct OpcodeCounts(BTreeMap<&'static str, usize>);
+
+impl OpcodeCounts {
+    pub(crate) fn new() -> Self {
+        OpcodeCounts(BTreeMap::new())
+    }
+
+    // Must be called right before the current instruction executes
+    pub(crate) fn count(&mut self, state: &State) {
+        let frame = state.frames.back().expect("No current frame?!");
+        let name = cur_fn(frame.module, frame.fun.to_string())
+            .get(frame.ip)
+            .map_or("Return", |instruction| instruction.name());
+        *self.0.entry(name).or_insert(0) += 1;
+    }
+
+    pub(crate) fn report(self) {
+        let total: usize = self.0.values().sum();
+        let mut counts: Vec<(&str, usize)> = self.0.into_iter().collect();
+        counts.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+        eprintln!("{:12}  {:>10}  {:>6}", "instruction", "count", "%");
+        for (name, count) in counts {
+            eprintln!("{:12}  {:>10}  {:>6.2}", name, count, count as f64 * 100.0 / total as f64);
+        }
+    }
+}
+
+// Frame stacks sampled every so often, written out in the folded format flamegraph tools read:
+// one `outer;inner count` line per distinct stack
+pub(crate) struct Sampler(BTreeMap<String, usize>);
+
+impl Sampler {
+    pub(crate) fn new() -> Self {
+        Sampler(BTreeMap::new())
+    }
+
+    pub(crate) fn sample(&mut self, state: &State) {
+        let stack: Vec<String> = state.frames.iter().map(qualified_name).collect();
+        *self.0.entry(stack.join(";")).or_insert(0) += 1;
+    }
+
+    pub(crate) fn write(self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for (stack, count) in self.0 {
+            writeln!(file, "{} {}", stack, count)?;
+        }
+        file.flush()
+    }
+}
+
+// Which instructions ran, for `--coverage`. Keyed by `Module.fn`, with a hit count per instruction
+pub(crate) struct Coverage(BTreeMap<String, Vec<usize>>);
+
+impl Coverage {
+    // Starts with every function at zero, so the ones that never ran show up too
+    pub(crate) fn new(modules: &HashMap<Vec<String>, Module>) -> Self {
+        let mut functions = BTreeMap::new();
+        for module in modules.values() {
+            for (fun, instructions) in &module.functions {
+                functions.insert(format!("{}.{}", format_module_name(&module.name), fun), vec![0; instructions.len()]);
+            }
+        }
+        Coverage(functions)
+    }
+
+    // Must be called right before the current instruction executes
+    pub(crate) fn count(&mut self, state: &State) {
+        let frame = state.frames.back().expect("No current frame?!");
+        if let Some(hits) = self.0.get_mut(&qualified_name(frame)).and_then(|hits| hits.get_mut(frame.ip)) {
+            *hits += 1;
+        }
+    }
+
+    pub(crate) fn report(&self) {
+        let width = self.0.keys().map(String::len).max().unwrap_or(0).max("function".len());
+        eprintln!("{:width$}  {:>9}  {:>7}", "function", "covered", "%", width = width);
+        for (fun, hits) in &self.0 {
+            let covered = hits.iter().filter(|hits| **hits > 0).count();
+            let percent = if hits.is_empty() { 100.0 } else { covered as f64 * 100.0 / hits.len() as f64 };
+            eprintln!(
+                "{:width$}  {:>9}  {:>7.2}",
+                fun,
+                format!("{}/{}", covered, hits.len()),
+                percent,
+                width = width,
+            );
+        }
+    }
+
+    // LCOV tracefile, with every function as its own source file and instruction N on line N + 1,
+    // like the DAP listings
+    pub(crate) fn write(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for (fun, hits) in &self.0 {
+            writeln!(file, "SF:{}", fun)?;
+            for (ip, count) in hits.iter().enumerate() {
+                writeln!(file, "DA:{},{}", ip + 1, count)?;
+            }
+            writeln!(file, "LH:{}", hits.iter().filter(|hits| **hits > 0).count())?;
+            writeln!(file, "LF:{}", hits.len())?;
+            writeln!(file, "end_of_record")?;
+        }
+        file.flush()
+    }
+}
+
+// Branch edges taken, for `--edges`: how many times each Unless/Jump went from one ip to another.
+// Meant as feedback for coverage-guided fuzzers, writes `Module.fn from to count` lines
+pub(crate) struct Edges {
+    edges: BTreeMap<(String, usize, usize), usize>,
+    // The branch about to run, if any
+    pending: Option<(String, usize)>,
+}
+
+impl Edges {
+    pub(crate) fn new() -> Self {
+        Edges {
+            edges: BTreeMap::new(),
+            pending: None,
+        }
+    }
+
+    // Must be called right before the current instruction executes
+    pub(crate) fn before(&mut self, state: &State) {
+        let frame = state.frames.back().expect("No current frame?!");
+        self.pending = match cur_fn(frame.module, frame.fun.to_string()).get(frame.ip) {
+            Some(Instruction::Unless(_)) | Some(Instruction::Jump(_)) => Some((qualified_name(frame), frame.ip)),
+            _ => None,
+        };
+    }
+
+    // And right after
+    pub(crate) fn after(&mut self, state: &State) {
+        if let (Some((fun, from)), Some(frame)) = (self.pending.take(), state.frames.back()) {
+            *self.edges.entry((fun, from, frame.ip)).or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn write(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for ((fun, from, to), count) in &self.edges {
+            writeln!(file, "{} {} {} {}", fun, from, to, count)?;
+        }
+        file.flush()
+    }
+}
@@ -0,0 +1,72 @@
+// What a trap looks like: the message, the source line it came from if the module says, the
+// instructions around it, then who called it
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::fs;
+use std::panic;
+use std::sync::Once;
+use asm;
+use vm::{format_module_name, Frame};
+
+// Instructions listed on either side of the one that trapped
+const CONTEXT: usize = 2;
+
+thread_local! {
+    // Whether this thread is running an instruction, its panics are traps and get reported
+    static STEPPING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Makes the panic hook leave traps to `trap`, other panics still go to the hook there was
+pub(crate) fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| if !STEPPING.with(Cell::get) {
+            previous(info)
+        }));
+    });
+}
+
+pub(crate) fn stepping(stepping: bool) {
+    STEPPING.with(|cell| cell.set(stepping));
+}
+
+pub(crate) fn trap(message: &str, frames: &VecDeque<Frame>) -> String {
+    let mut report = format!("error: {}\n", message);
+    for (depth, frame) in frames.iter().rev().enumerate() {
+        // Callers are already past their Call
+        let ip = if depth == 0 { frame.ip } else { frame.ip - 1 };
+        let mut at = format!("{}.{}@{}", format_module_name(&frame.module.name), frame.fun, ip);
+        let location = frame.module.location(&frame.fun, ip);
+        if let Some(location) = location {
+            write!(at, " ({})", location).unwrap();
+        }
+        if depth > 0 {
+            writeln!(report, "   = called from {}", at).unwrap();
+            continue;
+        }
+        writeln!(report, "  --> {}", at).unwrap();
+        let line = location.and_then(|location| {
+            let source = fs::read_to_string(&location.file).ok()?;
+            source.lines().nth(location.line.checked_sub(1)?).map(String::from)
+        });
+        if let (Some(location), Some(line)) = (location, line) {
+            let gutter = location.line.to_string().len();
+            // Tabs stay tabs, so the caret lines up however they're shown
+            let indent: String = line.chars().take(location.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(report, "{:gutter$} |", "", gutter = gutter).unwrap();
+            writeln!(report, "{} | {}", location.line, line).unwrap();
+            writeln!(report, "{:gutter$} | {}^", "", indent, gutter = gutter).unwrap();
+        }
+        let instructions = &frame.module.functions[&frame.fun];
+        let end = (ip + CONTEXT + 1).min(instructions.len());
+        for (idx, instruction) in instructions.iter().enumerate().take(end).skip(ip.saturating_sub(CONTEXT)) {
+            let marker = if idx == ip { ">" } else { " " };
+            writeln!(report, "   {} {:>4}  {}", marker, idx, asm::format_instruction(frame.module, instruction)).unwrap();
+        }
+    }
+    report
+}
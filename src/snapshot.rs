@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use serde::{Serialize, Deserialize};
+use gc::{Ptr, GC};
+use link::Kept;
+use vm::{format_module_name, Checkpoint, Frame, Module, State, Value, VmConfig};
+
+// Frames refer to their module by name, to be looked up again on resume
+#[derive(Serialize, Deserialize)]
+struct SavedFrame {
+    module: Vec<String>,
+    fun: String,
+    ip: usize,
+    locals: Vec<Ptr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCheckpoint {
+    stack: Vec<Ptr>,
+    frames: Vec<SavedFrame>,
+}
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    gc: &'a GC,
+    stack: &'a [Ptr],
+    frames: Vec<SavedFrame>,
+    checkpoints: Vec<SavedCheckpoint>,
+    at_exit: &'a [(Vec<String>, String)],
+}
+
+#[derive(Deserialize)]
+struct SavedSnapshot {
+    gc: GC,
+    stack: Vec<Ptr>,
+    frames: Vec<SavedFrame>,
+    checkpoints: Vec<SavedCheckpoint>,
+    at_exit: Vec<(Vec<String>, String)>,
+}
+
+fn save_frames(frames: &VecDeque<Frame>) -> Vec<SavedFrame> {
+    frames.iter().map(|frame| SavedFrame {
+        module: frame.module.name.clone(),
+        fun: frame.fun.clone(),
+        ip: frame.ip,
+        locals: frame.locals.clone(),
+    }).collect()
+}
+
+fn check_ptrs(ptrs: &[Ptr], arena_len: usize) -> Result<(), String> {
+    match ptrs.iter().filter_map(|ptr| ptr.heap()).find(|i| *i >= arena_len) {
+        Some(i) => Err(format!("Dangling pointer {} in snapshot", i)),
+        None => Ok(()),
+    }
+}
+
+fn load_frames<'a>(
+    saved_frames: Vec<SavedFrame>,
+    arena_len: usize,
+    modules: &'a HashMap<Vec<String>, Module>,
+) -> Result<VecDeque<Frame<'a>>, String> {
+    let mut frames = VecDeque::new();
+    for saved in saved_frames {
+        let module = modules.get(&saved.module)
+            .ok_or(format!("Snapshot needs module {}, which isn't loaded", format_module_name(&saved.module)))?;
+        let fun = module.functions.get(&saved.fun)
+            .ok_or(format!("Snapshot needs {}.{}, which doesn't exist", format_module_name(&saved.module), saved.fun))?;
+        if saved.ip > fun.len() {
+            return Err(format!("Snapshot ip {} is out of {}.{}", saved.ip, format_module_name(&saved.module), saved.fun));
+        }
+        check_ptrs(&saved.locals, arena_len)?;
+        frames.push_back(Frame {
+            module,
+            fun: saved.fun,
+            ip: saved.ip,
+            locals: saved.locals,
+        });
+    }
+    if frames.is_empty() {
+        return Err("Snapshot has no frames left to run".to_string());
+    }
+    Ok(frames)
+}
+
+// Writes the whole machine state to `path`, going through a temporary file so a crash mid-write
+// doesn't clobber the previous snapshot
+pub(crate) fn save(state: &State, path: &str) -> Result<(), String> {
+    let snapshot = Snapshot {
+        gc: &state.gc,
+        stack: &state.stack,
+        frames: save_frames(&state.frames),
+        checkpoints: state.checkpoints.iter().map(|checkpoint| SavedCheckpoint {
+            stack: checkpoint.stack.clone(),
+            frames: save_frames(&checkpoint.frames),
+        }).collect(),
+        at_exit: &state.at_exit,
+    };
+    let tmp_path = format!("{}.tmp", path);
+    let file = File::create(&tmp_path).map_err(|err| err.to_string())?;
+    serde_json::to_writer(BufWriter::new(file), &snapshot).map_err(|err| err.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|err| err.to_string())
+}
+
+pub(crate) fn load<'a>(path: &str, modules: &'a HashMap<Vec<String>, Module>, kept: &'a Kept, config: &VmConfig) -> Result<State<'a>, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let snapshot: SavedSnapshot = serde_json::from_reader(BufReader::new(file)).map_err(|err| err.to_string())?;
+
+    let arena_len = snapshot.gc.len();
+    check_ptrs(&snapshot.stack, arena_len)?;
+    let weak_targets: Vec<Ptr> = snapshot.gc.iter()
+        .filter_map(|(_, value)| match value {
+            Value::WeakRef(target) => *target,
+            _ => None,
+        })
+        .collect();
+    check_ptrs(&weak_targets, arena_len)?;
+    let frames = load_frames(snapshot.frames, arena_len, modules)?;
+    let mut checkpoints = vec!();
+    for saved in snapshot.checkpoints {
+        check_ptrs(&saved.stack, arena_len)?;
+        checkpoints.push(Checkpoint {
+            stack: saved.stack,
+            frames: load_frames(saved.frames, arena_len, modules)?,
+        });
+    }
+
+    for (module, fun) in &snapshot.at_exit {
+        if !modules.get(module).is_some_and(|module| module.functions.contains_key(fun)) {
+            return Err(format!("Snapshot has an at_exit hook {}.{}, which doesn't exist", format_module_name(module), fun));
+        }
+    }
+
+    Ok(State {
+        gc: snapshot.gc,
+        stack: snapshot.stack,
+        frames,
+        checkpoints,
+        at_exit: snapshot.at_exit,
+        // Interned again as they're pushed
+        interned: HashMap::new(),
+        // NOTE: frames in modules the program loaded itself don't resume, they're looked up above
+        loaded: HashMap::new(),
+        kept,
+        // The config's, like for a fresh start
+        host: config.host.clone(),
+        features: config.features.clone(),
+        host_calls: None,
+        output: config.output.clone(),
+    })
+}
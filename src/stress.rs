@@ -0,0 +1,94 @@
+// Synthetic worst-case modules, for `bin gen-stress`
+use std::collections::BTreeMap as Map;
+use bc;
+use vm::{Instruction, Module, ModuleName};
+
+pub struct StressOptions {
+    pub functions: usize,
+    pub strings: usize,
+    // Length of each call chain, MAIN calls the head of every chain
+    pub depth: usize,
+}
+
+impl Default for StressOptions {
+    fn default() -> Self {
+        StressOptions {
+            functions: 10_000,
+            strings: 10_000,
+            depth: 100,
+        }
+    }
+}
+
+// Every function loads one of the strings then calls the next one in its chain,
+// MAIN prints the number of functions once all chains returned
+pub fn generate(options: &StressOptions) -> Module {
+    let depth = options.depth.max(1);
+    let strings: Vec<String> = (0..options.strings.max(1)).map(|i| format!("string {}", i)).collect();
+    let mut functions = Map::new();
+    let mut main = vec!();
+
+    for i in 0..options.functions {
+        let mut body = vec!(
+            Instruction::PushString(i % strings.len()),
+            Instruction::StoreLocal(0),
+        );
+        let next = i + 1;
+        if next % depth != 0 && next < options.functions {
+            body.push(Instruction::LoadGlobal(format!("f{}", next)));
+            body.push(Instruction::Call(0));
+        }
+        if i % depth == 0 {
+            main.push(Instruction::LoadGlobal(format!("f{}", i)));
+            main.push(Instruction::Call(0));
+        }
+        functions.insert(format!("f{}", i), body);
+    }
+    main.push(Instruction::PushInt(options.functions as i64));
+    main.push(Instruction::LoadName(ModuleName::new(vec!("Prelude".to_string())), "print".to_string()));
+    main.push(Instruction::Call(1));
+    functions.insert("MAIN".to_string(), main);
+
+    // Nothing takes arguments
+    let arities = functions.keys().map(|name| (name.clone(), 0)).collect();
+    Module {
+        format_version: bc::current_version(),
+        name: vec!("stress".to_string()),
+        strings,
+        functions,
+        dependencies: vec!(),
+        heap_reserve: 0,
+        arities,
+        exports: None,
+        dynamic_dependencies: vec!(),
+        source_map: Map::new(),
+        local_names: Map::new(),
+        version: None,
+        compiler: None,
+        checksum: None,
+        entrypoint: None,
+        conditions: Map::new(),
+        origins: Map::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use link;
+    use super::*;
+
+    // Linking has to stay about linear in the number of functions. Back when every LoadGlobal
+    // went through all of its module's functions, 20,000 of them took a minute on a debug build
+    #[test]
+    fn links_20k_functions_in_time() {
+        let module = generate(&StressOptions { functions: 20_000, strings: 100, depth: 100 });
+        let name = module.name.clone();
+        let modules: HashMap<Vec<String>, Module> = vec!((name.clone(), module)).into_iter().collect();
+        let start = Instant::now();
+        link::link(Some(&name), &modules).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_secs(10), "linking took {:?}", elapsed);
+    }
+}
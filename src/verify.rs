@@ -0,0 +1,358 @@
+// Bytecode verification, run by the linker once every reference resolves: jump targets stay in
+// their function, and the value stack never underflows the frame and has the same depth whichever
+// way an instruction is reached.
+//
+// Stack effects mostly follow from the instruction, except for calls, where they depend on the
+// callee. Callees pushed by LoadName/LoadGlobal are known, and a user function's effect is
+// whatever depth it returns with. Anything else (a ref passed around, recursion) makes the depth
+// unknown from there on, and those paths aren't checked until they join a known one.
+//
+// Then locals, which must be initialized in order: a LoadLocal needs the local stored first on
+// every path, a StoreLocal at most one past the ones so far. Arguments start out as the first
+// locals, so this needs to know how many a function gets: the entrypoint none, others their declared or
+// mangled arity, or failing that whatever their call sites above pass, the fewest if they
+// disagree. Functions without an arity only ever called through a ref passed around aren't checked.
+use std::collections::{HashMap, HashSet};
+use link::{LinkError, LinkErrorKind};
+use mangle;
+use vm::{format_module_name, is_host, is_prelude, Instruction, Module};
+
+// What we know about a stack slot: only function refs matter, for calls
+#[derive(Clone, PartialEq)]
+enum Slot {
+    Fn(Vec<String>, String),
+    Prelude(String),
+    Host,
+    Any,
+}
+
+// None once the depth is unknown
+type Stack = Option<Vec<Slot>>;
+
+struct Verifier<'a> {
+    modules: &'a HashMap<Vec<String>, Module>,
+    // Depth each function returns with, None if unknown
+    returns: HashMap<(Vec<String>, String), Option<usize>>,
+    in_progress: HashSet<(Vec<String>, String)>,
+    // Fewest arguments any call site passes
+    arguments: HashMap<(Vec<String>, String), usize>,
+}
+
+// Verifies `only` that module if given, every one otherwise
+pub(crate) fn verify(modules: &HashMap<Vec<String>, Module>, only: Option<&Vec<String>>) -> Result<(), LinkError> {
+    let mut verifier = Verifier {
+        modules,
+        returns: HashMap::new(),
+        in_progress: HashSet::new(),
+        arguments: HashMap::new(),
+    };
+    let mut names: Vec<&Vec<String>> = match only {
+        Some(name) => vec![name],
+        None => modules.keys().collect(),
+    };
+    names.sort();
+    for name in &names {
+        for fun in modules[*name].functions.keys() {
+            verifier.returns(name, fun)?;
+        }
+    }
+    for name in &names {
+        for (fun, instructions) in &modules[*name].functions {
+            let declared = mangle::arity(&modules[*name], fun);
+            let arguments = match declared.or_else(|| verifier.arguments.get(&(name.to_vec(), fun.clone())).cloned()) {
+                Some(arguments) => arguments,
+                None if fun == modules[*name].entrypoint() => 0,
+                None => continue,
+            };
+            locals(&modules[*name], fun, instructions, arguments).map_err(|(ip, kind)| LinkError {
+                module: name.to_vec(),
+                function: Some(fun.clone()),
+                ip: Some(ip),
+                span: None,
+                kind,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// Checks the initialization order, given how many locals the arguments fill
+fn locals(module: &Module, fun: &str, instructions: &[Instruction], arguments: usize) -> Result<(), (usize, LinkErrorKind)> {
+    // Fewest locals initialized on entry to each instruction, over every path there
+    let mut entry: Vec<Option<usize>> = vec![None; instructions.len() + 1];
+    entry[0] = Some(arguments);
+    let mut work = vec![0];
+    while let Some(ip) = work.pop() {
+        let mut initialized = entry[ip].unwrap();
+        let successors = match instructions.get(ip) {
+            Some(Instruction::LoadLocal(idx)) if *idx >= initialized =>
+                return Err((ip, LinkErrorKind::UninitializedLocal(module.local(fun, *idx), initialized))),
+            Some(Instruction::StoreLocal(idx)) if *idx > initialized =>
+                return Err((ip, LinkErrorKind::OutOfOrderLocal(module.local(fun, *idx), module.local(fun, initialized)))),
+            Some(Instruction::StoreLocal(idx)) => {
+                initialized = initialized.max(idx + 1);
+                vec![ip + 1]
+            }
+            Some(Instruction::Jump(target)) => vec![*target],
+            Some(Instruction::Unless(target)) => vec![ip + 1, *target],
+            Some(Instruction::Rollback) | None => vec!(),
+            Some(_) => vec![ip + 1],
+        };
+        for next in successors {
+            let joined = entry[next].map_or(initialized, |old| old.min(initialized));
+            if entry[next] != Some(joined) {
+                entry[next] = Some(joined);
+                work.push(next);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Verifier<'a> {
+    fn returns(&mut self, module: &[String], fun: &str) -> Result<Option<usize>, LinkError> {
+        let key = (module.to_vec(), fun.to_string());
+        if let Some(depth) = self.returns.get(&key) {
+            return Ok(*depth);
+        }
+        // Recursive call, we'll know once the outer one's done
+        if !self.in_progress.insert(key.clone()) {
+            return Ok(None);
+        }
+        let depth = self.function(module, fun);
+        self.in_progress.remove(&key);
+        let depth = depth?;
+        self.returns.insert(key, depth);
+        Ok(depth)
+    }
+
+    fn function(&mut self, module_name: &[String], fun: &str) -> Result<Option<usize>, LinkError> {
+        let modules = self.modules;
+        let module = &modules[module_name];
+        let instructions = &module.functions[fun];
+        let len = instructions.len();
+        let error = |ip, kind| LinkError {
+            module: module_name.to_vec(),
+            function: Some(fun.to_string()),
+            ip: Some(ip),
+            span: None,
+            kind,
+        };
+
+        for (ip, instruction) in instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Jump(target) | Instruction::Unless(target) if *target > len =>
+                    return Err(error(ip, LinkErrorKind::BadJumpTarget(*target))),
+                _ => {}
+            }
+        }
+
+        // Stack on entry to each instruction, None until it's reached. `len` is the return
+        let mut entry: Vec<Option<Stack>> = vec![None; len + 1];
+        entry[0] = Some(Some(vec!()));
+        let mut work = vec![0];
+        while let Some(ip) = work.pop() {
+            let stack = entry[ip].clone().unwrap();
+            let instruction = match instructions.get(ip) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let (after, successors) = self.effect(instruction, module, ip, stack, &error)?;
+            for next in successors {
+                let joined = match (&entry[next], &after) {
+                    (None, _) => after.clone(),
+                    (Some(None), _) => after.clone(),
+                    (Some(Some(old)), None) => Some(old.clone()),
+                    (Some(Some(old)), Some(new)) if old.len() != new.len() =>
+                        return Err(error(next, LinkErrorKind::InconsistentDepth(old.len(), new.len()))),
+                    (Some(Some(old)), Some(new)) => Some(old.iter().zip(new)
+                        .map(|(old, new)| if old == new { old.clone() } else { Slot::Any })
+                        .collect()),
+                };
+                if entry[next].as_ref() != Some(&joined) {
+                    entry[next] = Some(joined);
+                    work.push(next);
+                }
+            }
+        }
+        Ok(entry[len].clone().flatten().map(|stack| stack.len()))
+    }
+
+    // The stack after `instruction`, and which instructions may run next
+    fn effect(
+        &mut self,
+        instruction: &Instruction,
+        module: &Module,
+        ip: usize,
+        stack: Stack,
+        error: &impl Fn(usize, LinkErrorKind) -> LinkError,
+    ) -> Result<(Stack, Vec<usize>), LinkError> {
+        let successors = match instruction {
+            Instruction::Jump(target) => vec![*target],
+            Instruction::Unless(target) => vec![ip + 1, *target],
+            // Comes back right after its Checkpoint instead
+            Instruction::Rollback => vec!(),
+            _ => vec![ip + 1],
+        };
+        let mut stack = match stack {
+            Some(stack) => stack,
+            None => return Ok((None, successors)),
+        };
+        let pops = match instruction {
+            Instruction::StoreLocal(_) | Instruction::Unless(_) => 1,
+            Instruction::Call(n) => n + 1,
+            _ => 0,
+        };
+        if stack.len() < pops {
+            return Err(error(ip, LinkErrorKind::StackUnderflow(pops, stack.len())));
+        }
+        let popped = stack.split_off(stack.len() - pops);
+        match instruction {
+            Instruction::LoadName(namespace, name) if is_prelude(namespace) => stack.push(Slot::Prelude(name.clone())),
+            Instruction::LoadName(namespace, _) if is_host(namespace) => stack.push(Slot::Host),
+            Instruction::LoadName(namespace, name) => stack.push(Slot::Fn(namespace.module.clone(), name.clone())),
+            Instruction::LoadGlobal(name) => stack.push(Slot::Fn(module.name.clone(), name.clone())),
+            Instruction::PushInt(_) | Instruction::PushString(_) | Instruction::LoadLocal(_) | Instruction::Checkpoint =>
+                stack.push(Slot::Any),
+            Instruction::Call(_) => {
+                let results = match popped.last().unwrap() {
+                    Slot::Prelude(name) if name == "print" || name == "at_exit" => Some(0),
+                    Slot::Prelude(_) | Slot::Host => Some(1),
+                    // Not loaded yet, the program brings it in itself
+                    Slot::Fn(module, _) if !self.modules.contains_key(module) => None,
+                    Slot::Fn(module, fun) => {
+                        let fun = match mangle::resolve(&self.modules[module], fun, pops - 1) {
+                            Some(fun) => fun,
+                            None => return Err(error(ip, LinkErrorKind::NoSuchFunction(module.clone(), mangle::mangle(fun, pops - 1)))),
+                        };
+                        match self.modules[module].arities.get(fun) {
+                            Some(arity) if *arity != pops - 1 => return Err(error(ip,
+                                LinkErrorKind::ArityMismatch(format!("{}.{}", format_module_name(module), fun), *arity, pops - 1))),
+                            _ => {}
+                        }
+                        let arguments = self.arguments.entry((module.clone(), fun.clone())).or_insert(pops - 1);
+                        *arguments = (*arguments).min(pops - 1);
+                        self.returns(module, fun)?
+                    }
+                    Slot::Any => None,
+                };
+                match results {
+                    Some(results) => stack.extend(vec![Slot::Any; results]),
+                    None => return Ok((None, successors)),
+                }
+            }
+            Instruction::StoreLocal(_) | Instruction::Unless(_) | Instruction::Jump(_)
+                | Instruction::Rollback | Instruction::Commit => {}
+        }
+        Ok((Some(stack), successors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm;
+    use link;
+    use vm::Instruction;
+    use super::*;
+
+    // Where linking `modules` fails, and why
+    fn rejected(modules: &HashMap<Vec<String>, Module>) -> (Option<String>, Option<usize>, LinkErrorKind) {
+        let err = link::link(Some(&["M".to_string()]), modules).expect_err("linked fine");
+        (err.function, err.ip, err.kind)
+    }
+
+    fn rejects(source: &str) -> (Option<String>, Option<usize>, LinkErrorKind) {
+        rejected(&asm::modules(&[source]))
+    }
+
+    #[test]
+    fn accepts_consistent_functions() {
+        let modules = asm::modules(&["
+module M
+
+fn MAIN
+    PushInt 3
+    StoreLocal 0
+loop:
+    LoadLocal 0
+    Unless done
+    LoadLocal 0
+    LoadGlobal twice
+    Call 1
+    LoadName Prelude.print
+    Call 1
+    PushInt 0
+    StoreLocal 0
+    Jump loop
+done:
+
+fn twice 1
+    LoadLocal 0
+    LoadLocal 0
+    LoadName Prelude.+
+    Call 2
+"]);
+        assert!(link::link(Some(&["M".to_string()]), &modules).is_ok());
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_the_function() {
+        let (function, ip, kind) = rejects("module M\nfn MAIN\n    PushInt 1\n    Jump 5\n");
+        assert_eq!((function.as_deref(), ip), (Some("MAIN"), Some(1)));
+        assert!(matches!(kind, LinkErrorKind::BadJumpTarget(5)), "{:?}", kind);
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    PushInt 1\n    PushInt 2\n    LoadName Prelude.+\n    Call 3\n");
+        assert_eq!(ip, Some(3));
+        assert!(matches!(kind, LinkErrorKind::StackUnderflow(4, 3)), "{:?}", kind);
+    }
+
+    // f returns nothing, so there's nothing to store after calling it
+    #[test]
+    fn knows_what_callees_return() {
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    LoadGlobal f\n    Call 0\n    StoreLocal 0\nfn f 0\n    PushInt 1\n    StoreLocal 0\n");
+        assert_eq!(ip, Some(2));
+        assert!(matches!(kind, LinkErrorKind::StackUnderflow(1, 0)), "{:?}", kind);
+    }
+
+    #[test]
+    fn rejects_paths_of_different_depths() {
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    PushInt 1\n    Unless skip\n    PushInt 2\nskip:\n");
+        assert_eq!(ip, Some(3));
+        assert!(matches!(kind, LinkErrorKind::InconsistentDepth(..)), "{:?}", kind);
+    }
+
+    #[test]
+    fn rejects_missing_strings() {
+        let mut modules = asm::modules(&["module M\nfn MAIN\n    PushString \"only\"\n"]);
+        modules.get_mut(&vec!("M".to_string())).unwrap().functions.get_mut("MAIN").unwrap().push(Instruction::PushString(1));
+        let (_, ip, kind) = rejected(&modules);
+        assert_eq!(ip, Some(1));
+        assert!(matches!(kind, LinkErrorKind::NoSuchString(1)), "{:?}", kind);
+    }
+
+    #[test]
+    fn rejects_locals_out_of_order() {
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    LoadLocal 0\n");
+        assert_eq!(ip, Some(0));
+        assert!(matches!(kind, LinkErrorKind::UninitializedLocal(_, 0)), "{:?}", kind);
+
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    PushInt 1\n    StoreLocal 1\n");
+        assert_eq!(ip, Some(1));
+        assert!(matches!(kind, LinkErrorKind::OutOfOrderLocal(..)), "{:?}", kind);
+
+        // Only initialized on one of the paths there
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    PushInt 1\n    Unless load\n    PushInt 2\n    StoreLocal 0\nload:\n    LoadLocal 0\n");
+        assert_eq!(ip, Some(4));
+        assert!(matches!(kind, LinkErrorKind::UninitializedLocal(_, 0)), "{:?}", kind);
+    }
+
+    #[test]
+    fn rejects_calls_with_the_wrong_arity() {
+        let (_, ip, kind) = rejects("module M\nfn MAIN\n    LoadGlobal f\n    Call 0\nfn f 1\n    LoadLocal 0\n");
+        assert_eq!(ip, Some(1));
+        assert!(matches!(&kind, LinkErrorKind::ArityMismatch(callee, 1, 0) if callee == "M.f"), "{:?}", kind);
+    }
+}
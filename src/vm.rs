@@ -1,24 +1,66 @@
-use std::collections::{BTreeMap as Map, HashMap, HashSet};
+use std::collections::{BTreeMap as Map, HashMap};
 use std::collections::VecDeque;
-use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Instant;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use bc;
+use debugger::Debugger;
+use diagnostic::{Diagnostic, ErrorFormat};
+use embed::{self, HostFunctions, Output, Stdout};
+use gc::{self, Ptr, GC};
+use history::HostCalls;
+use intrinsics;
+use link::{self, Kept, LinkError, Resolver};
+use link_cache;
+use lint::{self, Level, Lint};
+use mangle;
+use opt;
+use profile::{self, Coverage, Edges, OpcodeCounts, Profiler, Sampler};
+use report;
+use snapshot;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Which `VmConfig::gc` may be
+pub use gc::STRATEGIES as GC_STRATEGIES;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde()]
-struct ModuleName {
-    module: Vec<String>,
+pub struct ModuleName {
+    pub(crate) module: Vec<String>,
 }
 
-fn is_prelude_(module_name: &Vec<String>) -> bool {
+impl ModuleName {
+    pub(crate) fn new(module: Vec<String>) -> Self {
+        ModuleName { module }
+    }
+
+    pub fn module(&self) -> &[String] {
+        &self.module
+    }
+}
+
+fn is_prelude_(module_name: &[String]) -> bool {
     module_name.len() == 1 && module_name[0] == "Prelude"
 }
 
-fn is_prelude(module_name: &ModuleName) -> bool {
+pub(crate) fn is_prelude(module_name: &ModuleName) -> bool {
     is_prelude_(&module_name.module)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// `Host` and anything under it is for the functions whoever embeds the VM registers
+fn is_host_(module_name: &[String]) -> bool {
+    module_name.first().is_some_and(|first| first == "Host")
+}
+
+pub(crate) fn is_host(module_name: &ModuleName) -> bool {
+    is_host_(&module_name.module)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "tag", content = "contents")]
-enum Instruction {
+pub enum Instruction {
     PushInt(i64),
     PushString(usize),
     LoadLocal(usize),
@@ -29,25 +71,327 @@ enum Instruction {
     Unless(usize),
     Jump(usize),
     Call(usize),
+    // Saves the stack and frames then pushes 0, Rollback comes back right after it with a 1 instead
+    Checkpoint,
+    // Restores (and discards) the latest checkpoint
+    Rollback,
+    // Discards the latest checkpoint
+    Commit,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Instruction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::PushInt(_) => "PushInt",
+            Instruction::PushString(_) => "PushString",
+            Instruction::LoadLocal(_) => "LoadLocal",
+            Instruction::StoreLocal(_) => "StoreLocal",
+            Instruction::LoadName(_, _) => "LoadName",
+            Instruction::LoadGlobal(_) => "LoadGlobal",
+            Instruction::Unless(_) => "Unless",
+            Instruction::Jump(_) => "Jump",
+            Instruction::Call(_) => "Call",
+            Instruction::Checkpoint => "Checkpoint",
+            Instruction::Rollback => "Rollback",
+            Instruction::Commit => "Commit",
+        }
+    }
+}
+
+// An instruction as frontends may write it: jumps can go to a label or be relative, "+2" or "-3"
+// from the jump itself, as well as absolute. Labels mark the instruction after them and take no
+// room, so source maps and the like count only the rest
+#[derive(Deserialize)]
+#[serde(tag = "tag", content = "contents")]
+pub(crate) enum RawInstruction {
+    PushInt(i64),
+    PushString(usize),
+    LoadLocal(usize),
+    StoreLocal(usize),
+    LoadName(ModuleName, String),
+    LoadGlobal(String),
+    Unless(Target),
+    Jump(Target),
+    Call(usize),
+    Checkpoint,
+    Rollback,
+    Commit,
+    Label(String),
+}
+
+pub(crate) enum Target {
+    Absolute(usize),
+    Relative(isize),
+    Label(String),
+}
+
+impl Target {
+    // A relative offset if it has a sign, otherwise a label
+    pub(crate) fn parse(target: &str) -> Result<Target, String> {
+        if !target.starts_with('+') && !target.starts_with('-') {
+            return Ok(Target::Label(target.to_string()));
+        }
+        target.trim_start_matches('+').parse().map(Target::Relative)
+            .map_err(|_| format!("bad relative offset {:?}", target))
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetVisitor;
+
+        impl<'de> Visitor<'de> for TargetVisitor {
+            type Value = Target;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an offset, a relative one like \"+2\" or a label")
+            }
+
+            fn visit_u64<E: de::Error>(self, offset: u64) -> Result<Target, E> {
+                Ok(Target::Absolute(offset as usize))
+            }
+
+            fn visit_str<E: de::Error>(self, target: &str) -> Result<Target, E> {
+                Target::parse(target).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TargetVisitor)
+    }
+}
+
+// Turns labels and relative offsets into absolute ones, for the JSON and the assembler alike. An
+// error says which of `raw` it's about, labels counting too
+pub(crate) fn resolve_labels(raw: Vec<RawInstruction>) -> Result<Vec<Instruction>, (usize, String)> {
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut ip = 0;
+    for (idx, instruction) in raw.iter().enumerate() {
+        match instruction {
+            RawInstruction::Label(label) => if labels.insert(label.clone(), ip).is_some() {
+                return Err((idx, format!("label {} is defined twice", label)));
+            },
+            _ => ip += 1,
+        }
+    }
+    let mut instructions = Vec::with_capacity(ip);
+    for (idx, instruction) in raw.into_iter().enumerate() {
+        let ip = instructions.len();
+        let target = |target| match target {
+            Target::Absolute(offset) => Ok(offset),
+            Target::Relative(offset) => (ip as isize).checked_add(offset).filter(|offset| *offset >= 0).map(|offset| offset as usize)
+                .ok_or((idx, format!("relative offset {} goes before the function", offset))),
+            Target::Label(label) => labels.get(&label).cloned().ok_or((idx, format!("no label {}", label))),
+        };
+        instructions.push(match instruction {
+            RawInstruction::PushInt(value) => Instruction::PushInt(value),
+            RawInstruction::PushString(idx) => Instruction::PushString(idx),
+            RawInstruction::LoadLocal(idx) => Instruction::LoadLocal(idx),
+            RawInstruction::StoreLocal(idx) => Instruction::StoreLocal(idx),
+            RawInstruction::LoadName(namespace, name) => Instruction::LoadName(namespace, name),
+            RawInstruction::LoadGlobal(name) => Instruction::LoadGlobal(name),
+            RawInstruction::Unless(offset) => Instruction::Unless(target(offset)?),
+            RawInstruction::Jump(offset) => Instruction::Jump(target(offset)?),
+            RawInstruction::Call(n) => Instruction::Call(n),
+            RawInstruction::Checkpoint => Instruction::Checkpoint,
+            RawInstruction::Rollback => Instruction::Rollback,
+            RawInstruction::Commit => Instruction::Commit,
+            RawInstruction::Label(_) => continue,
+        });
+    }
+    Ok(instructions)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Module {
+    // The layout it was written in as `MAJOR.MINOR`, see `bc::FORMAT_VERSION`. Read modules are
+    // upgraded to the current one
+    #[serde(default = "bc::unversioned")]
+    pub(crate) format_version: String,
     pub name: Vec<String>,
-    strings: Vec<String>,
-    functions: Map<String, Vec<Instruction>>,
-    dependencies: Vec<Vec<String>>,
+    pub(crate) strings: Vec<String>,
+    #[serde(deserialize_with = "unique_functions")]
+    pub(crate) functions: Map<String, Vec<Instruction>>,
+    pub(crate) dependencies: Vec<Vec<String>>,
+    // Objects the module expects to allocate, the arena makes room for them up front
+    #[serde(default)]
+    pub(crate) heap_reserve: usize,
+    // How many arguments each function takes, calls with any other count trap.
+    // Functions left out aren't checked
+    #[serde(default)]
+    pub(crate) arities: Map<String, usize>,
+    // Functions other modules may LoadName, None for all of them
+    #[serde(default)]
+    pub(crate) exports: Option<Vec<String>>,
+    // Modules it brings in with `load_module`, LoadNames into them only resolve once that ran
+    #[serde(default)]
+    pub(crate) dynamic_dependencies: Vec<Vec<String>>,
+    // Where each function's instructions came from, by offset. Functions and instructions
+    // without one are fine
+    #[serde(default)]
+    pub(crate) source_map: Map<String, Vec<Option<Location>>>,
+    // What the frontend called each function's locals, by index. Any may be left out
+    #[serde(default)]
+    pub(crate) local_names: Map<String, Vec<String>>,
+    // Which build of the module this is, and what built it, for telling artifacts apart
+    #[serde(default)]
+    pub(crate) version: Option<String>,
+    #[serde(default)]
+    pub(crate) compiler: Option<String>,
+    // `bc::checksum` of the rest, checked when it's read
+    #[serde(default)]
+    pub(crate) checksum: Option<String>,
+    // Function to start in when it's the entry module, MAIN if None
+    #[serde(default)]
+    pub(crate) entrypoint: Option<String>,
+    // The VM feature each function needs, if it needs one. Without it the function links as a
+    // stub that traps, see `link::conditions`
+    #[serde(default)]
+    pub(crate) conditions: Map<String, String>,
+    // Which instruction as read each one of a function the passes rewrote stands for, so the
+    // debugger can still go by those. Not part of the module, the link cache keeps it apart
+    #[serde(skip)]
+    pub(crate) origins: Map<String, Vec<Origin>>,
+}
+
+// Where an instruction was before the passes, maybe in another function for an inlined one
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Origin {
+    pub(crate) module: Vec<String>,
+    pub(crate) fun: String,
+    // First and last offset it stands for, the ones a pass dropped going with the next one kept
+    pub(crate) ips: (usize, usize),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl Module {
+    // `compiled by X, version V`, as much of it as the module says
+    pub fn provenance(&self) -> Option<String> {
+        match (&self.compiler, &self.version) {
+            (Some(compiler), Some(version)) => Some(format!("compiled by {}, version {}", compiler, version)),
+            (Some(compiler), None) => Some(format!("compiled by {}", compiler)),
+            (None, Some(version)) => Some(format!("version {}", version)),
+            (None, None) => None,
+        }
+    }
+
+    // Where running the module starts
+    pub fn entrypoint(&self) -> &str {
+        self.entrypoint.as_deref().unwrap_or("MAIN")
+    }
+
+    // Where the instruction at `ip` in `fun` came from, if the frontend said
+    pub(crate) fn location(&self, fun: &str, ip: usize) -> Option<&Location> {
+        self.source_map.get(fun).and_then(|locations| locations.get(ip)).and_then(Option::as_ref)
+    }
+
+    // Where the instruction at `ip` in `fun` was before the passes, None if it's still there
+    pub(crate) fn origin(&self, fun: &str, ip: usize) -> Option<&Origin> {
+        self.origins.get(fun).and_then(|origins| origins.get(ip))
+    }
+
+    // `local 3`, `local 3 (count)` if it has a name
+    pub(crate) fn local(&self, fun: &str, idx: usize) -> String {
+        match self.local_names.get(fun).and_then(|names| names.get(idx)).filter(|name| !name.is_empty()) {
+            Some(name) => format!("local {} ({})", idx, name),
+            None => format!("local {}", idx),
+        }
+    }
 }
 
-struct Frame<'a> {
-    module: &'a Module,
-    fun: String,
-    ip: usize,
-    locals: Vec<Ptr>, // XXX we'll want to serialize this when we store closures
+// JSON objects may repeat a key, only the last one would be kept otherwise
+// A function's instructions, saying which one is wrong if any
+struct Instructions<'a>(&'a str);
+
+impl<'de, 'a> DeserializeSeed<'de> for Instructions<'a> {
+    type Value = Vec<Instruction>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Instructions<'a> {
+    type Value = Vec<Instruction>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of instructions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut instructions = vec!();
+        loop {
+            match access.next_element::<RawInstruction>() {
+                Ok(Some(instruction)) => instructions.push(instruction),
+                Ok(None) => return resolve_labels(instructions)
+                    .map_err(|(idx, err)| de::Error::custom(format!("function {}, instruction {}: {}", self.0, idx, err))),
+                Err(err) => {
+                    // The outer error says where it is again
+                    let err = err.to_string();
+                    let err = err.rfind(" at line ").map_or(&err[..], |at| &err[..at]);
+                    return Err(de::Error::custom(format!("function {}, instruction {}: {}", self.0, instructions.len(), err)));
+                }
+            }
+        }
+    }
+}
+
+fn unique_functions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Map<String, Vec<Instruction>>, D::Error> {
+    struct Functions;
+
+    impl<'de> Visitor<'de> for Functions {
+        type Value = Map<String, Vec<Instruction>>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a map of function names to instructions")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let mut functions = Map::new();
+            while let Some(name) = access.next_key::<String>()? {
+                if functions.contains_key(&name) {
+                    return Err(de::Error::custom(format!("function {} is defined twice", name)));
+                }
+                let instructions = access.next_value_seed(Instructions(&name))?;
+                functions.insert(name, instructions);
+            }
+            Ok(functions)
+        }
+    }
+
+    deserializer.deserialize_map(Functions)
+}
+
+#[derive(Clone)]
+pub(crate) struct Frame<'a> {
+    pub(crate) module: &'a Module,
+    pub(crate) fun: String,
+    pub(crate) ip: usize,
+    pub(crate) locals: Vec<Ptr>, // XXX we'll want to serialize this when we store closures
     //     this will prevent captures from being gc'd
 }
 
-fn make_frame(module: &Module, name: String) -> Frame {
+// The ones `load_module` brought in or that got reloaded, then the modules passed in
+pub(crate) fn find_module<'a>(
+    modules: &'a HashMap<Vec<String>, Module>,
+    loaded: &HashMap<Vec<String>, &'a Module>,
+    name: &[String],
+) -> Option<&'a Module> {
+    loaded.get(name).cloned().or_else(|| modules.get(name))
+}
+
+fn make_frame(module: &Module, name: String) -> Frame<'_> {
     Frame {
         module,
         fun: name,
@@ -56,24 +400,40 @@ fn make_frame(module: &Module, name: String) -> Frame {
     }
 }
 
-fn cur_fn(module: &Module, fn_name: String) -> &Vec<Instruction> {
+pub(crate) fn cur_fn(module: &Module, fn_name: String) -> &Vec<Instruction> {
     module.functions.get(&fn_name).expect("No such fn")
 }
 
-enum Value {
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Value {
     IntVal(i64),
     StrVal(String),
     ModuleFnRef(Vec<String>, String),
     ThwartPtr(usize),
+    // Doesn't keep its target alive, the collector clears it once nothing else does
+    WeakRef(Option<Ptr>),
 }
 
 impl Value {
-    fn to_string(&self) -> String {
+    pub(crate) fn kind(&self) -> &'static str {
         match self {
-            Value::IntVal(i) => i.to_string(),
-            Value::StrVal(s) => s.to_string(),
-            Value::ModuleFnRef(_, f) => f.to_string(),
-            Value::ThwartPtr(_) => "Thwart ptr".to_string()
+            Value::IntVal(_) => "Int",
+            Value::StrVal(_) => "Str",
+            Value::ModuleFnRef(_, _) => "FnRef",
+            Value::ThwartPtr(_) => "Thwart",
+            Value::WeakRef(_) => "WeakRef",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::IntVal(i) => write!(f, "{}", i),
+            Value::StrVal(s) => write!(f, "{}", s),
+            Value::ModuleFnRef(_, name) => write!(f, "{}", name),
+            Value::ThwartPtr(_) => write!(f, "Thwart ptr"),
+            Value::WeakRef(_) => write!(f, "Weak ref"),
         }
     }
 }
@@ -84,256 +444,794 @@ impl Clone for Value {
             Value::IntVal(i) => Value::IntVal(*i),
             Value::StrVal(s) => Value::StrVal(s.to_string()),
             Value::ModuleFnRef(ns, f) => Value::ModuleFnRef(ns.iter().map(|s| s.to_string()).collect(), f.to_string()),
-            Value::ThwartPtr(i) => Value::ThwartPtr(*i)
+            Value::ThwartPtr(i) => Value::ThwartPtr(*i),
+            Value::WeakRef(ptr) => Value::WeakRef(*ptr),
         }
     }
 }
 
-// TODO we shouldn't have a single value type
-struct GC(Vec<Value>);
-
-// TODO 2nd arena
-#[derive(Clone, Copy)]
-struct Ptr(usize); //, usize);
-
-fn compact(mut gc: GC, arena: Vec<Value>, frames: VecDeque<Frame>, mut stack: Vec<Ptr>) -> Vec<Value> {
-    let mut new_arena: Vec<Value> = vec!();
-    for (i, mut ptr) in stack.iter_mut().enumerate() {
-        match gc.raw_at(i) {
-            Value::ThwartPtr(i) => ptr.0 = *i, // Rewrite ptr
-            v => {
-                // TODO potentially traverse into `v`
-                new_arena.push(v.clone());
-                ptr.0 = new_arena.len() - 1;
-                gc.set(i, Value::ThwartPtr(ptr.0));
+macro_rules! define_comparison_operator {
+    ( $op:tt, $gc:expr, $stack:expr, $arg_num:expr ) => {
+        {
+            let mut prev: i64 = match *$gc.at($stack.pop().unwrap()) {
+                Value::IntVal(val) => val,
+                _ => unreachable!("Checked by the signature")
+            };
+            let mut result = true;
+            let mut i: usize = 1;
+            while &i < $arg_num {
+                match *$gc.at($stack.pop().unwrap()) {
+                    Value::IntVal(val) => {
+                        result = result && prev $op val;
+                        prev = val;
+                    }
+                    _ => unreachable!("Checked by the signature")
+                }
+                i += 1;
             }
+            $stack.push($gc.int(result as i64))
         }
     }
-    for frame in frames {
-        for local in frame.locals {}
-    }
-    new_arena
-}
-
-impl GC {
-    fn at(&self, i: Ptr) -> &Value {
-        self.raw_at(i.0)
-    }
-
-    fn raw_at(&self, i: usize) -> &Value {
-        self.0.get(i).unwrap()
-    }
-
-    fn alloc(&mut self, v: Value) -> Ptr {
-        self.0.push(v);
-        Ptr(self.0.len() - 1)
-    }
-
-    fn set(&mut self, i: usize, v: Value) {
-        // TODO assert i <= self.0.len
-        self.0[i] = v;
-    }
-
-    fn new() -> Self {
-        GC { 0: Vec::new() }
-    }
 }
 
 macro_rules! define_arithmetic_operator {
     ( $op:tt, $gc:expr, $stack:expr, $arg_num:expr ) => {
         {
-            let mut result: i64 = match $gc.at($stack.pop().unwrap()) {
-                Value::IntVal(val) => *val,
-                _ => panic!("Cannot start a non-int")
+            let mut result: i64 = match *$gc.at($stack.pop().unwrap()) {
+                Value::IntVal(val) => val,
+                _ => unreachable!("Checked by the signature")
             };
             let mut i: usize = 1; // Start at 1, we already handled the first
             while &i < $arg_num {
-                match $gc.at($stack.pop().unwrap()) {
-                    Value::IntVal(val) => result = result $op val,
-                    _ => panic!("Cannot perform arithmetic on a non-int value")
+                match *$gc.at($stack.pop().unwrap()) {
+                    Value::IntVal(val) => result $op val,
+                    _ => unreachable!("Checked by the signature")
                 }
                 i += 1;
             }
-            $stack.push($gc.alloc(Value::IntVal(result)))
+            $stack.push($gc.int(result))
         }
     }
 }
 
-fn run_main(module_name: Vec<String>, modules: HashMap<Vec<String>, Module>) {
-    let mut gc = GC::new();
-    let mut stack: Vec<Ptr> = Vec::new();
-    let mut frames: VecDeque<Frame> = VecDeque::new();
-    let entrypoint_module: &Module = modules.get(&module_name).unwrap();
-    frames.push_back(make_frame(&entrypoint_module, "MAIN".to_string()));
+// Everything a run can be tuned with
+#[derive(Clone)]
+pub struct VmConfig {
+    pub debug: bool,
+    // Print every instruction to stderr as it runs, when not debugging
+    pub trace: bool,
+    // Objects to make room for in the arena up front, on top of what the modules ask for
+    pub arena_capacity: usize,
+    // Most objects the arena may hold once collected, None for no limit
+    pub max_heap: Option<usize>,
+    // Most bytes they may take once collected, None for no limit
+    pub heap_limit: Option<usize>,
+    // Most frames there may be at once, calls past that trap
+    pub max_frames: Option<usize>,
+    // Which collector to run, one of `GC_STRATEGIES`
+    pub gc: String,
+    // Objects allocated since the last collection before collecting again, at least.
+    // The arena may also double what survived the last one in between
+    pub gc_threshold: usize,
+    // Arena slots the incremental collector sweeps per instruction
+    pub gc_slice: usize,
+    // Memory the debugger may spend on history for `back`, in bytes
+    pub history_budget: usize,
+    // Where to write a snapshot of the VM state, every `snapshot_every` instructions
+    pub snapshot: Option<String>,
+    pub snapshot_every: usize,
+    // Snapshot to resume from instead of starting MAIN
+    pub resume: Option<String>,
+    // Function to start in instead of the entry module's own entrypoint
+    pub entrypoint: Option<String>,
+    // Stop once the modules link and verify, without running anything
+    pub check: bool,
+    // Print how long linking and running took, and how many instructions ran
+    pub timings: bool,
+    // Serve the debugger over the Debug Adapter Protocol on this port
+    pub dap: Option<u16>,
+    // Instructions the at_exit hooks get to run, all together
+    pub exit_fuel: usize,
+    // Print per-function timings once the program is done
+    pub profile: bool,
+    // Print heap statistics once the program is done
+    pub heap_stats: bool,
+    // Print how many times each instruction ran once the program is done
+    pub opcode_counts: bool,
+    // Where to write folded stacks for flamegraph tools, sampled every `sample_every` instructions
+    pub flamegraph: Option<String>,
+    pub sample_every: usize,
+    // Where to write which instructions ran, as an LCOV tracefile
+    pub coverage: Option<String>,
+    // Where to write the branch edges taken, for fuzzers
+    pub edges: Option<String>,
+    // Drop the functions and strings MAIN can't reach before running
+    pub eliminate_dead_code: bool,
+    // Inline calls to small functions before running
+    pub inline: bool,
+    // Rewrite obviously wasteful instruction sequences before running
+    pub peephole: bool,
+    // Where to keep the linked modules, to skip linking while they don't change
+    pub link_cache: Option<String>,
+    // How each lint is reported, any left out are allowed
+    pub lints: HashMap<Lint, Level>,
+    // How traps are reported on stderr
+    pub error_format: ErrorFormat,
+    // Only print what's asked for, not what it's doing or the traps it returns, for embedding
+    pub quiet: bool,
+    // What the program can call in the Host namespace
+    pub host: HostFunctions,
+    // VM features granted, functions that need any other one link as stubs that trap
+    pub features: Vec<String>,
+    // Where what it prints goes
+    pub output: Arc<dyn Output>,
+}
 
-    while !frames.is_empty() {
-        let mut cur_frame = frames.back_mut().unwrap();
-        let fun = cur_fn(&cur_frame.module, cur_frame.fun.to_string());
-        eprintln!("ip: {}", cur_frame.ip);
-        eprintln!("got: {:?}", fun.get(cur_frame.ip));
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            debug: false,
+            trace: false,
+            arena_capacity: 0,
+            max_heap: None,
+            heap_limit: None,
+            max_frames: None,
+            gc: "copying".to_string(),
+            gc_threshold: 1 << 16,
+            gc_slice: 1024,
+            history_budget: 16 * 1024 * 1024,
+            snapshot: None,
+            snapshot_every: 100_000,
+            resume: None,
+            entrypoint: None,
+            check: false,
+            timings: false,
+            dap: None,
+            exit_fuel: 1_000_000,
+            profile: false,
+            heap_stats: false,
+            opcode_counts: false,
+            flamegraph: None,
+            sample_every: 100,
+            coverage: None,
+            edges: None,
+            eliminate_dead_code: false,
+            inline: false,
+            peephole: false,
+            link_cache: None,
+            lints: HashMap::new(),
+            error_format: ErrorFormat::Human,
+            quiet: false,
+            host: HostFunctions::new(),
+            features: vec!(),
+            output: Arc::new(Stdout),
+        }
+    }
+}
 
-        match fun.get(cur_frame.ip) {
-            Some(Instruction::PushInt(n)) => {
-                stack.push(gc.alloc(Value::IntVal(*n)));
-                cur_frame.ip += 1;
-            }
+pub(crate) struct State<'a> {
+    pub(crate) gc: GC,
+    pub(crate) stack: Vec<Ptr>,
+    pub(crate) frames: VecDeque<Frame<'a>>,
+    pub(crate) checkpoints: Vec<Checkpoint<'a>>,
+    // Functions registered with `at_exit`, as (module, fn), run last first once MAIN returns
+    pub(crate) at_exit: Vec<(Vec<String>, String)>,
+    // String table constants already on the heap, per module and string index
+    pub(crate) interned: HashMap<Vec<String>, Vec<Option<Ptr>>>,
+    // Modules `load_module` brought in and reloaded ones, they stay loaded until the program ends
+    pub(crate) loaded: HashMap<Vec<String>, &'a Module>,
+    pub(crate) kept: &'a Kept,
+    pub(crate) host: HostFunctions,
+    // What modules loaded while it runs are linked with, as for the ones it started with
+    pub(crate) features: Vec<String>,
+    // Kept while debugging, so going back doesn't call host functions again
+    pub(crate) host_calls: Option<HostCalls>,
+    pub(crate) output: Arc<dyn Output>,
+}
 
-            Some(Instruction::PushString(n)) => {
-                let string = cur_frame.module.strings.get(*n).expect("No such string");
-                stack.push(gc.alloc(Value::StrVal(string.to_string())));
-                cur_frame.ip += 1;
+// What `Checkpoint` saves. The heap is never mutated in place, so keeping the pointers is enough
+#[derive(Clone)]
+pub(crate) struct Checkpoint<'a> {
+    pub(crate) stack: Vec<Ptr>,
+    pub(crate) frames: VecDeque<Frame<'a>>,
+}
+
+// Fails only when there's a snapshot to resume from that can't be
+pub(crate) fn run_main(module_name: Vec<String>, modules: &HashMap<Vec<String>, Module>, config: &VmConfig) -> Result<Option<i64>, String> {
+    let kept = Kept::default();
+    let state = match &config.resume {
+        Some(path) => snapshot::load(path, modules, &kept, config).map_err(|err| format!("Cannot resume from {}: {}", path, err))?,
+        None => {
+            let entrypoint_module: &Module = modules.get(&module_name).unwrap();
+            start(entrypoint_module, entrypoint_module.entrypoint().to_string(), &kept, config)
+        }
+    };
+    match run_state(state, modules, config) {
+        Some(embed::Value::Int(n)) => Ok(Some(n)),
+        _ => Ok(None),
+    }
+}
+
+// Runs `fun` with `args` as its arguments, to what it returns, always from the start
+pub(crate) fn call(
+    module: &Module,
+    fun: &str,
+    args: Vec<embed::Value>,
+    modules: &HashMap<Vec<String>, Module>,
+    config: &VmConfig,
+) -> Option<embed::Value> {
+    let kept = Kept::default();
+    let mut state = start(module, fun.to_string(), &kept, config);
+    let locals: Vec<Ptr> = args.into_iter().map(|arg| arg.into_vm(&mut state.gc)).collect();
+    state.frames.back_mut().unwrap().locals = locals;
+    run_state(state, modules, config)
+}
+
+pub(crate) fn start<'a>(module: &'a Module, fun: String, kept: &'a Kept, config: &VmConfig) -> State<'a> {
+    let mut frames = VecDeque::new();
+    frames.push_back(make_frame(module, fun));
+    State {
+        gc: GC::new(),
+        stack: Vec::new(),
+        frames,
+        checkpoints: vec!(),
+        at_exit: vec!(),
+        interned: HashMap::new(),
+        loaded: HashMap::new(),
+        kept,
+        host: config.host.clone(),
+        features: config.features.clone(),
+        host_calls: None,
+        output: config.output.clone(),
+    }
+}
+
+// The result is what's on top of the stack once the first frame returns
+fn run_state<'a>(mut state: State<'a>, modules: &'a HashMap<Vec<String>, Module>, config: &VmConfig) -> Option<embed::Value> {
+    let module_reserve: usize = modules.values().map(|module| module.heap_reserve).sum();
+    state.gc.reserve(config.arena_capacity + module_reserve);
+    let strategy = gc::strategy(&config.gc, config.gc_slice).unwrap_or_else(|| {
+        panic!("Unknown GC strategy {}, expected one of: {}", config.gc, gc::STRATEGIES.join(", "))
+    });
+    state.gc.set_strategy(strategy);
+    // What survived the last collection, the arena may grow by as much before the next one
+    let mut survivors = state.gc.occupied();
+    let mut executed: usize = 0;
+    let mut debugger = if config.debug || config.dap.is_some() { Some(Debugger::new(modules, config)) } else { None };
+    if debugger.is_some() {
+        state.host_calls = Some(HostCalls::default());
+    }
+    let mut profiler = if config.profile { Some(Profiler::new()) } else { None };
+    let mut opcode_counts = if config.opcode_counts { Some(OpcodeCounts::new()) } else { None };
+    let mut sampler = config.flamegraph.as_ref().map(|_| Sampler::new());
+    let mut coverage = config.coverage.as_ref().map(|_| Coverage::new(modules));
+    let mut edges = config.edges.as_ref().map(|_| Edges::new());
+
+    // Only counts down once the hooks start running
+    let mut fuel: Option<usize> = None;
+    let mut result = None;
+    let started = Instant::now();
+    report::install();
+
+    loop {
+        if state.frames.is_empty() {
+            if fuel.is_none() {
+                result = state.stack.last().and_then(|ptr| embed::Value::from_vm(&state.gc.at(*ptr)));
+            }
+            match state.at_exit.pop() {
+                Some((module, fun)) => {
+                    let hook = find_module(modules, &state.loaded, &module).expect("No such module");
+                    state.frames.push_back(make_frame(hook, fun));
+                    fuel.get_or_insert(config.exit_fuel);
+                }
+                None => break,
             }
+        }
+        if fuel == Some(0) {
+            state.output.warn(&format!("at_exit hooks ran out of fuel, {} left unfinished", state.at_exit.len() + 1));
+            break;
+        }
 
-            Some(Instruction::LoadLocal(idx)) => {
-                let ptr = cur_frame.locals.get(*idx).expect("Trying to access uninitialized local");
-                stack.push(*ptr);
-                cur_frame.ip += 1;
+        if let Some(debugger) = debugger.as_mut() {
+            debugger.pause(&mut state);
+            debugger.record(&mut state);
+        } else if config.trace {
+            let cur_frame = state.frames.back().unwrap();
+            let fun = cur_fn(cur_frame.module, cur_frame.fun.to_string());
+            match cur_frame.module.location(&cur_frame.fun, cur_frame.ip) {
+                Some(location) => eprintln!("ip: {} ({})", cur_frame.ip, location),
+                None => eprintln!("ip: {}", cur_frame.ip),
+            }
+            eprintln!("got: {:?}", fun.get(cur_frame.ip));
+        }
+        let collections = state.gc.collections();
+        if let Some(opcode_counts) = opcode_counts.as_mut() {
+            opcode_counts.count(&state);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.count(&state);
+        }
+        if let Some(edges) = edges.as_mut() {
+            edges.before(&state);
+        }
+        if let Some(sampler) = sampler.as_mut() {
+            if executed.is_multiple_of(config.sample_every) {
+                sampler.sample(&state);
             }
+        }
+        report::stepping(true);
+        let stepped = panic::catch_unwind(AssertUnwindSafe(|| match profiler.as_mut() {
+            Some(profiler) => profiler.step(&mut state, modules),
+            None => step(&mut state, modules, false),
+        }));
+        report::stepping(false);
+        if let Err(err) = stepped {
+            let message = err.downcast_ref::<String>().map(String::as_str)
+                .or_else(|| err.downcast_ref::<&str>().cloned())
+                .unwrap_or("trap");
+            panic::resume_unwind(Box::new(trapped(&state, "trap", message, config)));
+        }
+        if let Some(edges) = edges.as_mut() {
+            edges.after(&state);
+        }
+        if let Some(max) = config.max_frames.filter(|max| state.frames.len() > *max) {
+            exceeded(&state, "frame-limit", format!("Frame limit of {} exceeded", max), config);
+        }
 
-            Some(Instruction::StoreLocal(idx)) => {
-                let ptr = stack.pop().expect("Stack is empty, cannot store");
-                if cur_frame.locals.len() > *idx {
-                    cur_frame.locals[*idx] = ptr;
-                } else if cur_frame.locals.len() == *idx {
-                    cur_frame.locals.push(ptr);
-                } else {
-                    panic!("Out-of-order local initialization!");
-                }
-                cur_frame.ip += 1;
+        let over_limit = config.max_heap.is_some_and(|max| state.gc.occupied() > max)
+            || config.heap_limit.is_some_and(|limit| state.gc.size() > limit);
+        if (over_limit || state.gc.allocated() >= config.gc_threshold.max(survivors)) && !state.frames.is_empty() {
+            // Going over the limit can't wait for the sweep
+            gc::collect_state(&mut state, over_limit);
+            if let Some(max) = config.max_heap.filter(|max| state.gc.occupied() > *max) {
+                let message = format!("Heap limit of {} objects exceeded, {} remain after collecting", max, state.gc.occupied());
+                exceeded(&state, "heap-limit", message, config);
+            }
+            if let Some(limit) = config.heap_limit.filter(|limit| state.gc.size() > *limit) {
+                let message = format!("Heap limit of {} bytes exceeded, {} remain after collecting", limit, state.gc.size());
+                exceeded(&state, "heap-limit", message, config);
             }
+        }
+        // Either just now or by the program calling `gc`
+        if state.gc.collections() != collections {
+            survivors = state.gc.occupied();
+            if let Some(debugger) = debugger.as_mut() {
+                // What it recorded may point to objects that were just moved or freed
+                debugger.history.reset(&mut state);
+            }
+        } else if gc::slice(&mut state.gc) {
+            survivors = state.gc.occupied();
+        }
 
-            Some(Instruction::LoadName(namespace, name)) => {
-                if is_prelude(namespace) || modules.contains_key(&namespace.module) {
-                    stack.push(gc.alloc(Value::ModuleFnRef(namespace.module.clone(), name.clone())));
-                } else {
-                    eprintln!("Wrong module: {:?}", namespace);
-                    panic!("Trying to access to an un-loaded/unprovided module");
+        executed += 1;
+        if let Some(fuel) = fuel.as_mut() {
+            *fuel -= 1;
+        }
+        if let Some(path) = &config.snapshot {
+            if executed.is_multiple_of(config.snapshot_every) && !state.frames.is_empty() {
+                if let Err(err) = snapshot::save(&state, path) {
+                    state.output.warn(&format!("Cannot write snapshot to {}: {}", path, err));
                 }
-                cur_frame.ip += 1;
             }
+        }
+    }
+    if let Some(debugger) = debugger.as_mut() {
+        debugger.finish();
+    }
+    if config.timings {
+        eprintln!("timings: running {:.3} ms, {} instruction(s)", profile::millis(started.elapsed()), executed);
+    }
+    if config.heap_stats {
+        eprintln!("{}", gc::stats(&state));
+    }
+    if let Some(profiler) = profiler {
+        profiler.report();
+    }
+    if let Some(opcode_counts) = opcode_counts {
+        opcode_counts.report();
+    }
+    if let (Some(coverage), Some(path)) = (coverage, &config.coverage) {
+        coverage.report();
+        if let Err(err) = coverage.write(path) {
+            state.output.warn(&format!("Cannot write coverage to {}: {}", path, err));
+        }
+    }
+    if let (Some(edges), Some(path)) = (edges, &config.edges) {
+        if let Err(err) = edges.write(path) {
+            state.output.warn(&format!("Cannot write edges to {}: {}", path, err));
+        }
+    }
+    if let (Some(sampler), Some(path)) = (sampler, &config.flamegraph) {
+        if let Err(err) = sampler.write(path) {
+            state.output.warn(&format!("Cannot write folded stacks to {}: {}", path, err));
+        }
+    }
+    if !config.quiet {
+        eprintln!("Program done!");
+    }
+    result
+}
+
+// Reports the trap, it then unwinds with what's returned for whoever runs the program
+fn trapped(state: &State, code: &'static str, message: &str, config: &VmConfig) -> Diagnostic {
+    let diagnostic = Diagnostic::runtime(code, message.to_string(), &state.frames);
+    match config.error_format {
+        _ if config.quiet => {}
+        ErrorFormat::Human => eprint!("{}", report::trap(message, &state.frames)),
+        ErrorFormat::Json => eprintln!("{}", diagnostic.to_json()),
+    }
+    diagnostic
+}
+
+// Stops the program over a limit, reported like a trap
+fn exceeded(state: &State, code: &'static str, message: String, config: &VmConfig) -> ! {
+    panic::resume_unwind(Box::new(trapped(state, code, &message, config)))
+}
+
+// String table constants are allocated once and shared by every push, values are never mutated
+fn intern(interned: &mut HashMap<Vec<String>, Vec<Option<Ptr>>>, gc: &mut GC, module: &Module, idx: usize) -> Ptr {
+    if let Some(ptr) = interned.get(&module.name[..]).and_then(|slots| slots.get(idx)).and_then(|slot| *slot) {
+        return ptr;
+    }
+    let string = module.strings.get(idx).expect("No such string");
+    let ptr = gc.alloc(Value::StrVal(string.to_string()));
+    interned.entry(module.name.clone()).or_insert_with(|| vec![None; module.strings.len()])[idx] = Some(ptr);
+    ptr
+}
+
+// Executes the current frame's instruction. `quiet` drops the program's output, for replays
+pub(crate) fn step<'a>(state: &mut State<'a>, modules: &'a HashMap<Vec<String>, Module>, quiet: bool) {
+    let State { gc, stack, frames, checkpoints, at_exit, interned, loaded, kept, host, features, host_calls, output } = state;
+    let cur_frame = frames.back_mut().unwrap();
+    let fun = cur_fn(cur_frame.module, cur_frame.fun.to_string());
 
-            Some(Instruction::LoadGlobal(name)) => {
-                // TODO make sure the function exists
-                stack.push(gc.alloc(Value::ModuleFnRef(cur_frame.module.name.clone(), name.clone())));
-                cur_frame.ip += 1;
+    match fun.get(cur_frame.ip) {
+        Some(Instruction::PushInt(n)) => {
+            stack.push(gc.int(*n));
+            cur_frame.ip += 1;
+        }
+
+        Some(Instruction::PushString(n)) => {
+            stack.push(intern(interned, gc, cur_frame.module, *n));
+            cur_frame.ip += 1;
+        }
+
+        Some(Instruction::LoadLocal(idx)) => {
+            let ptr = cur_frame.locals.get(*idx).unwrap_or_else(||
+                panic!("Trying to access uninitialized {}", cur_frame.module.local(&cur_frame.fun, *idx)));
+            stack.push(*ptr);
+            cur_frame.ip += 1;
+        }
+
+        Some(Instruction::StoreLocal(idx)) => {
+            let ptr = stack.pop().expect("Stack is empty, cannot store");
+            if cur_frame.locals.len() > *idx {
+                cur_frame.locals[*idx] = ptr;
+            } else if cur_frame.locals.len() == *idx {
+                cur_frame.locals.push(ptr);
+            } else {
+                panic!("Out-of-order local initialization!");
             }
+            cur_frame.ip += 1;
+        }
 
-            Some(Instruction::Jump(offset)) => {
-                cur_frame.ip = *offset;
+        Some(Instruction::LoadName(namespace, name)) => {
+            if is_prelude(namespace) || is_host(namespace) || find_module(modules, loaded, &namespace.module).is_some() {
+                stack.push(gc.alloc(Value::ModuleFnRef(namespace.module.clone(), name.clone())));
+            } else {
+                panic!("Trying to access to an un-loaded/unprovided module {}", format_module_name(&namespace.module));
             }
+            cur_frame.ip += 1;
+        }
 
-            Some(Instruction::Unless(offset)) => {
-                let ptr = stack.pop().expect("Nothing left on stack");
-                let value = gc.at(ptr);
-                match value {
-                    Value::IntVal(n) =>
-                        if *n == 0i64 {
-                            cur_frame.ip = *offset
-                        } else {
-                            cur_frame.ip += 1
-                        }
-                    _ =>
+        Some(Instruction::LoadGlobal(name)) => {
+            // The linker made sure the function exists
+            stack.push(gc.alloc(Value::ModuleFnRef(cur_frame.module.name.clone(), name.clone())));
+            cur_frame.ip += 1;
+        }
+
+        Some(Instruction::Jump(offset)) => {
+            cur_frame.ip = *offset;
+        }
+
+        Some(Instruction::Unless(offset)) => {
+            let ptr = stack.pop().expect("Nothing left on stack");
+            let value = gc.at(ptr);
+            match &*value {
+                Value::IntVal(n) =>
+                    if *n == 0i64 {
+                        cur_frame.ip = *offset
+                    } else {
                         cur_frame.ip += 1
-                }
+                    }
+                _ =>
+                    cur_frame.ip += 1
             }
+        }
 
-            Some(Instruction::Call(arg_num)) => {
-                // TODO need to think of a story for local functions and returning closures
-                // one of the first thing we need is probably at semantic analysis stage. extract them to
-                // be fake functions, and have an instruction to curry them, i.e.:
-                // ModuleFnRefWithLocals([String], String, Locals: vec<Ptr>)
-                let ptr = stack.pop().expect("Nothing left on stack to call");
-                let value = gc.at(ptr);
-                match value {
-                    Value::ModuleFnRef(ns, name) if is_prelude_(&ns) => {
-                        match name.as_str() {
-                            "print" =>
-                                for _ in 1..=*arg_num {
-                                    println!("{}", gc.at(stack.pop().unwrap()).to_string());
+        Some(Instruction::Call(arg_num)) => {
+            // TODO need to think of a story for local functions and returning closures
+            // one of the first thing we need is probably at semantic analysis stage. extract them to
+            // be fake functions, and have an instruction to curry them, i.e.:
+            // ModuleFnRefWithLocals([String], String, Locals: vec<Ptr>)
+            let ptr = stack.pop().expect("Nothing left on stack to call");
+            let value = gc.at(ptr);
+            match &*value {
+                Value::ModuleFnRef(ns, name) if is_prelude_(ns) => {
+                    intrinsics::check(name, *arg_num, stack, gc);
+                    match name.as_str() {
+                        "print" =>
+                            for _ in 1..=*arg_num {
+                                let value = gc.at(stack.pop().unwrap());
+                                if !quiet {
+                                    output.print(&value.to_string());
                                 }
-                            "+" => define_arithmetic_operator!(+, gc, stack, arg_num),
-                            "-" => define_arithmetic_operator!(-, gc, stack, arg_num),
-                            "/" => define_arithmetic_operator!(/, gc, stack, arg_num),
-                            "*" => define_arithmetic_operator!(*, gc, stack, arg_num),
-                            ">" => define_arithmetic_operator!(>, gc, stack, arg_num),
-                            "<" => define_arithmetic_operator!(<, gc, stack, arg_num),
-                            "==" => define_arithmetic_operator!(==, gc, stack, arg_num),
-                            ">=" => define_arithmetic_operator!(>=, gc, stack, arg_num),
-                            "<=" => define_arithmetic_operator!(<=, gc, stack, arg_num),
-                            "!=" => define_arithmetic_operator!(!=, gc, stack, arg_num),
-                            // TODO ++
-                            _ => panic!("No such prelude fn: {name}", name = name)
+                            }
+                        "at_exit" => {
+                            match &*gc.at(stack.pop().unwrap()) {
+                                Value::ModuleFnRef(ns, name) if !is_prelude_(ns) => {
+                                    let module = find_module(modules, loaded, ns).expect("No such module");
+                                    let name = mangle::resolve(module, name, 0).unwrap_or_else(||
+                                        panic!("at_exit hooks take no arguments, {}.{} has no such overload", format_module_name(ns), name));
+                                    if module.arities.get(name).is_some_and(|arity| *arity != 0) {
+                                        panic!("at_exit hooks take no arguments, {}.{} does", format_module_name(ns), name);
+                                    }
+                                    at_exit.push((ns.clone(), name.clone()));
+                                }
+                                _ => panic!("at_exit needs a module function"),
+                            }
+                        }
+                        "trap" => match &*gc.at(stack.pop().unwrap()) {
+                            Value::StrVal(message) => panic!("{}", message),
+                            _ => unreachable!("Checked by the signature"),
+                        },
+                        "gc" => {
+                            // NOTE: increment IP here, since collecting needs all the frames
+                            cur_frame.ip += 1;
+                            let reclaimed = gc::collect(gc, gc::roots(stack, frames, checkpoints, interned), true);
+                            stack.push(gc.int(reclaimed as i64));
+                            return;
+                        }
+                        "load_module" => {
+                            let path = match &*gc.at(stack.pop().unwrap()) {
+                                Value::StrVal(path) => path.clone(),
+                                _ => unreachable!("Checked by the signature"),
+                            };
+                            let module = link::load(&path, modules, loaded, kept, features)
+                                .unwrap_or_else(|err| panic!("Cannot load module {}: {}", path, err));
+                            stack.push(gc.alloc(Value::StrVal(format_module_name(&module.name))));
+                        }
+                        "version" => {
+                            let name: Vec<String> = match &*gc.at(stack.pop().unwrap()) {
+                                Value::StrVal(name) => name.split('.').map(str::to_string).collect(),
+                                _ => unreachable!("Checked by the signature"),
+                            };
+                            let module = find_module(modules, loaded, &name)
+                                .unwrap_or_else(|| panic!("No module {} is loaded", format_module_name(&name)));
+                            let version = module.version.clone().unwrap_or_default();
+                            stack.push(gc.alloc(Value::StrVal(version)));
                         }
-                        cur_frame.ip += 1;
+                        "weak" => {
+                            let target = stack.pop().unwrap();
+                            stack.push(gc.alloc(Value::WeakRef(Some(target))));
+                        }
+                        "deref" => {
+                            let weak = stack.pop().unwrap();
+                            let default = stack.pop().unwrap();
+                            match *gc.at(weak) {
+                                Value::WeakRef(target) => stack.push(target.unwrap_or(default)),
+                                _ => unreachable!("Checked by the signature"),
+                            }
+                        }
+                        "+" => define_arithmetic_operator!(+=, gc, stack, arg_num),
+                        "-" => define_arithmetic_operator!(-=, gc, stack, arg_num),
+                        "/" => define_arithmetic_operator!(/=, gc, stack, arg_num),
+                        "*" => define_arithmetic_operator!(*=, gc, stack, arg_num),
+                        ">" => define_comparison_operator!(>, gc, stack, arg_num),
+                        "<" => define_comparison_operator!(<, gc, stack, arg_num),
+                        "==" => define_comparison_operator!(==, gc, stack, arg_num),
+                        ">=" => define_comparison_operator!(>=, gc, stack, arg_num),
+                        "<=" => define_comparison_operator!(<=, gc, stack, arg_num),
+                        "!=" => define_comparison_operator!(!=, gc, stack, arg_num),
+                        // TODO ++
+                        _ => unreachable!("{} has a signature but no implementation", name)
                     }
+                    cur_frame.ip += 1;
+                }
 
-                    Value::ModuleFnRef(ns, name) => {
-                        // NOTE: increment IP here, since adding a frame will invalidate our borrow
-                        cur_frame.ip += 1;
-                        let mut new_frame = make_frame(modules.get(ns).unwrap(), name.to_string());
-                        // Reverse arguments because we push(pop())
-                        for _ in (1..=*arg_num).rev() {
-                            new_frame.locals.push(stack.pop().unwrap());
+                Value::ModuleFnRef(ns, name) if is_host_(ns) => {
+                    let function = host.get(&(ns.clone(), name.clone())).unwrap_or_else(||
+                        panic!("No host function {}.{}", format_module_name(ns), name));
+                    let args: Vec<embed::Value> = (0..*arg_num).map(|_| {
+                        let value = gc.at(stack.pop().unwrap());
+                        embed::Value::from_vm(&value).unwrap_or_else(|| panic!("Host functions can't take a {}", value.kind()))
+                    }).collect();
+                    let result = match host_calls.as_mut().and_then(HostCalls::replayed) {
+                        Some(result) => result,
+                        None => {
+                            let result = function(&args).unwrap_or_else(|err| panic!("{}.{}: {}", format_module_name(ns), name, err));
+                            if let Some(host_calls) = host_calls.as_mut() {
+                                host_calls.record(&result);
+                            }
+                            result
                         }
-                        frames.push_back(new_frame);
+                    };
+                    stack.push(result.into_vm(gc));
+                    cur_frame.ip += 1;
+                }
+
+                Value::ModuleFnRef(ns, name) => {
+                    let module = find_module(modules, loaded, ns).unwrap();
+                    let name = mangle::resolve(module, name, *arg_num).unwrap_or_else(||
+                        panic!("{}.{} has no overload taking {} argument(s)", format_module_name(ns), name, arg_num));
+                    if let Some(arity) = module.arities.get(name).filter(|arity| **arity != *arg_num) {
+                        panic!("{}.{} takes {} argument(s), called with {}", format_module_name(ns), name, arity, arg_num);
                     }
-                    _ => {
-                        panic!("Can't call!");
+                    // NOTE: increment IP here, since adding a frame will invalidate our borrow
+                    cur_frame.ip += 1;
+                    let mut new_frame = make_frame(module, name.to_string());
+                    // Reverse arguments because we push(pop())
+                    for _ in (1..=*arg_num).rev() {
+                        new_frame.locals.push(stack.pop().unwrap());
                     }
+                    frames.push_back(new_frame);
+                }
+                _ => {
+                    panic!("Can't call!");
                 }
             }
+        }
+
+        Some(Instruction::Checkpoint) => {
+            cur_frame.ip += 1;
+            checkpoints.push(Checkpoint {
+                stack: stack.clone(),
+                frames: frames.clone(),
+            });
+            stack.push(gc.int(0));
+        }
 
-            None => {
-                // TODO reinstate some sort of %bsp?
+        Some(Instruction::Rollback) => {
+            let checkpoint = checkpoints.pop().expect("Rollback without a Checkpoint");
+            *stack = checkpoint.stack;
+            *frames = checkpoint.frames;
+            stack.push(gc.int(1));
+        }
 
-                frames.pop_back().expect("No current frame?!");
-            }
+        Some(Instruction::Commit) => {
+            checkpoints.pop().expect("Commit without a Checkpoint");
+            cur_frame.ip += 1;
+        }
+
+        None => {
+            // TODO reinstate some sort of %bsp?
+
+            frames.pop_back().expect("No current frame?!");
         }
     }
-    eprintln!("Program done!");
 }
 
 
-fn ensure_all_loaded(modules: &HashMap<Vec<String>, Module>) -> HashSet<Vec<String>> {
-    let mut bfs: Vec<Vec<String>> = modules.keys().into_iter().map(|name| name.clone()).collect();
-    let mut seen: HashSet<Vec<String>> = HashSet::new();
-    let mut missing = HashSet::new();
-    while !bfs.is_empty() { // TODO while pop
-        let item: Vec<String> = bfs.pop().unwrap();
-        match modules.get(&item) {
-            Some(&ref module) => {
-                // Mark current module as seen
-                seen.insert(item.clone());
-                // Traverse all deps, add them to the BFS if we haven't seen them already
-                for dep in &module.dependencies {
-                    if !seen.contains(dep) {
-                        bfs.push(dep.to_vec());
-                    }
-                }
-            }
-            None => {
-                // No dynamic module loading, if it's not in `deps` it's missing
-                missing.insert(item.clone());
-            }
+pub(crate) fn format_module_name(name: &[String]) -> String {
+    name.join(".")
+}
+
+// Links, then runs whichever passes are on
+fn link_modules(module: &[String], modules: &mut HashMap<Vec<String>, Module>, config: &VmConfig) -> Result<(), LinkError> {
+    let main = if config.resume.is_some() { None } else { Some(module) };
+    link::conditions(modules, &config.features);
+    link::link(main, modules)?;
+    link::host_functions(modules, &config.host)?;
+    lint::check(modules, &config.lints, &*config.output)?;
+    // A snapshot's frames may be anywhere, keep everything as is when resuming
+    if config.inline && config.resume.is_none() {
+        let inlined = opt::inline(modules);
+        if !config.quiet {
+            eprintln!("Inlined {} call(s)", inlined);
+        }
+    }
+    if config.peephole && config.resume.is_none() {
+        let rewrites = opt::peephole(modules);
+        if !config.quiet {
+            eprintln!("Made {} peephole rewrite(s)", rewrites);
+        }
+    }
+    if config.eliminate_dead_code && config.resume.is_none() {
+        let (functions, strings) = opt::eliminate_dead_code(module, modules);
+        if !config.quiet {
+            eprintln!("Dropped {} unreachable function(s) and {} string(s)", functions, strings);
         }
     }
-    missing
+    Ok(())
 }
 
-fn format_module_name(name: &Vec<String>) -> String {
-    name.join(".")
+// Why a program didn't get to run
+#[derive(Debug)]
+pub enum RunError {
+    Link(LinkError),
+    // The snapshot `--resume` names can't be read, or isn't for these modules
+    Resume(String),
+}
+
+impl RunError {
+    // As for `Diagnostic::code`
+    pub fn code(&self) -> &'static str {
+        match self {
+            RunError::Link(err) => err.kind.code(),
+            RunError::Resume(_) => "resume",
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Link(err) => write!(f, "Cannot link: {}", err),
+            RunError::Resume(err) => write!(f, "{}", err),
+        }
+    }
 }
 
-pub fn run(module: Vec<String>, modules: HashMap<Vec<String>, Module>) {
-    let missing_modules = ensure_all_loaded(&modules);
-    if !missing_modules.is_empty() {
-        let missing_names = missing_modules
-            .into_iter()
-            .map(|m| format_module_name(&m))
-            .collect::<Vec<String>>()
-            .join(", ");
-        panic!("Missing module(s): {}", missing_names);
+impl From<LinkError> for RunError {
+    fn from(err: LinkError) -> Self {
+        RunError::Link(err)
+    }
+}
+
+// The program's result is the int the entrypoint leaves on top of the stack when it returns, if
+// it does. Whatever the at_exit hooks leave doesn't count
+pub fn run(module: Vec<String>, modules: HashMap<Vec<String>, Module>, config: VmConfig) -> Result<Option<i64>, RunError> {
+    run_with_resolver(module, modules, config, &mut |_| Ok(None))
+}
+
+// Like `run`, but modules missing from `modules` are located with `resolver` as they're referenced
+pub fn run_with_resolver(
+    module: Vec<String>,
+    mut modules: HashMap<Vec<String>, Module>,
+    config: VmConfig,
+    resolver: &mut Resolver,
+) -> Result<Option<i64>, RunError> {
+    prepare(&module, &mut modules, &config, resolver)?;
+    if config.check {
+        if !config.quiet {
+            eprintln!("Checked {:?}", module);
+        }
+        return Ok(None);
+    }
+    if !config.quiet {
+        eprintln!("Running {:?}...", module);
+    }
+    run_main(module, &modules, &config).map_err(RunError::Resume)
+}
+
+// Everything before running: resolving what's missing, then linking or the link cache
+pub(crate) fn prepare(
+    module: &[String],
+    modules: &mut HashMap<Vec<String>, Module>,
+    config: &VmConfig,
+    resolver: &mut Resolver,
+) -> Result<(), LinkError> {
+    let started = Instant::now();
+    link::resolve(module, modules, resolver)?;
+    if let (Some(entrypoint), Some(main)) = (&config.entrypoint, modules.get_mut(module)) {
+        main.entrypoint = Some(entrypoint.clone());
+    }
+    let key = config.link_cache.as_ref().map(|_| link_cache::key(module, modules, config));
+    let cached = config.link_cache.as_ref().and_then(|path| link_cache::load(path, key.as_ref().unwrap()));
+    match cached {
+        Some(rewritten) => modules.extend(rewritten.into_iter().map(|module| (module.name.clone(), module))),
+        None => {
+            link_modules(module, modules, config).map_err(|err| err.locate(modules))?;
+            if let (Some(path), Some(key)) = (&config.link_cache, &key) {
+                if let Err(err) = link_cache::save(path, key, modules) {
+                    config.output.warn(&format!("Cannot write link cache to {}: {}", path, err));
+                }
+            }
+        }
+    }
+    if config.timings {
+        // Modules off the module path are loaded while resolving, so that counts too
+        eprintln!("timings: linking {:.3} ms", profile::millis(started.elapsed()));
     }
-    eprintln!("Running {:?}...", module);
-    run_main(module, modules);
+    Ok(())
 }
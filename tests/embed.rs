@@ -0,0 +1,252 @@
+// Running programs through VmBuilder as an embedder would, with what they print captured
+extern crate lib;
+
+use std::sync::Arc;
+use lib::asm;
+use lib::embed::{Captured, Value, Vm, VmBuilder, VmError};
+use lib::link::LinkErrorKind;
+use lib::lint::{Level, Lint};
+use lib::vm::VmConfig;
+
+const MAIN: &str = "
+module Main
+
+fn MAIN
+    PushString \"hello\"
+    LoadName Prelude.print
+    Call 1
+
+fn add 2
+    LoadLocal 1
+    LoadLocal 0
+    LoadName Prelude.+
+    Call 2
+
+fn greet 1
+    LoadLocal 0
+";
+
+fn build(source: &str, builder: VmBuilder) -> Vm {
+    builder.module(asm::assemble(source).unwrap()).build().unwrap()
+}
+
+#[test]
+fn prints_to_the_output() {
+    let output = Arc::new(Captured::default());
+    let vm = build(MAIN, VmBuilder::new().output(output.clone()));
+    vm.run().unwrap();
+    vm.run().unwrap();
+    assert_eq!(output.take(), vec!("hello", "hello"));
+    assert!(output.lines().is_empty());
+}
+
+#[test]
+fn calls_convert_arguments_and_results() {
+    let vm = build(MAIN, VmBuilder::new());
+    assert_eq!(vm.call::<_, i64>("add", (2, 3)).unwrap(), 5);
+    assert_eq!(vm.call::<_, u8>("Main::add", vec!(200, 55)).unwrap(), 255);
+    assert_eq!(vm.call::<_, String>("greet", ("world",)).unwrap(), "world");
+    assert_eq!(vm.call::<_, Value>("greet", (7,)).unwrap(), Value::Int(7));
+
+    match vm.call::<_, i64>("add", (1u64, u64::MAX)) {
+        Err(VmError::Argument(err)) => assert_eq!(err, "add: argument 2: 18446744073709551615 doesn't fit in an Int"),
+        other => panic!("expected an argument error, got {:?}", other.map(|_| ())),
+    }
+    match vm.call::<_, String>("add", (1, 2)) {
+        Err(VmError::Return(err)) => assert_eq!(err, "Main.add: expected Str, got Int"),
+        other => panic!("expected a return error, got {:?}", other.map(|_| ())),
+    }
+    match vm.call::<_, i64>("add", (1,)) {
+        Err(VmError::Link(err)) => assert!(matches!(err.kind, LinkErrorKind::ArityMismatch(_, 2, 1)), "{:?}", err.kind),
+        other => panic!("expected a link error, got {:?}", other.map(|_| ())),
+    }
+    match vm.call::<_, i64>("Main::missing", ()) {
+        Err(VmError::Link(err)) => assert!(matches!(err.kind, LinkErrorKind::NoSuchFunction(..)), "{:?}", err.kind),
+        other => panic!("expected a link error, got {:?}", other.map(|_| ())),
+    }
+}
+
+const HOST: &str = "
+module Main
+
+fn MAIN
+    PushInt 21
+    LoadName Host.App.twice
+    Call 1
+    LoadName Prelude.print
+    Call 1
+
+fn fail
+    PushString \"no\"
+    LoadName Host.App.fail
+    Call 1
+";
+
+#[test]
+fn programs_call_host_functions() {
+    let output = Arc::new(Captured::default());
+    let vm = build(HOST, VmBuilder::new()
+        .output(output.clone())
+        .register_fn("App::twice", |(n,): (i64,)| Ok(n * 2))
+        .register("App::fail", |args| Err(format!("refused {:?}", args))));
+    vm.run().unwrap();
+    assert_eq!(output.lines(), vec!("42"));
+
+    match vm.call::<_, Value>("fail", ()) {
+        Err(VmError::Trap(diagnostic)) => {
+            assert_eq!(diagnostic.code, "trap");
+            assert_eq!(diagnostic.message, "Host.App.fail: refused [Str(\"no\")]");
+            assert_eq!(diagnostic.function.as_deref(), Some("fail"));
+        }
+        other => panic!("expected a trap, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn host_functions_have_to_be_registered() {
+    let module = asm::assemble(HOST).unwrap();
+    let err = VmBuilder::new().module(module).register_fn("App::twice", |(n,): (i64,)| Ok(n)).build().err().unwrap();
+    assert!(matches!(&err.kind, LinkErrorKind::NoSuchHostFunction(module, fun) if module == &["Host", "App"] && fun == "fail"), "{:?}", err.kind);
+}
+
+#[test]
+fn programs_see_module_versions() {
+    let vm = build("module Main\nversion 3\nfn MAIN\n    PushInt 0\nfn version_of 1\n    LoadLocal 0\n    LoadName Prelude.version\n    Call 1\n", VmBuilder::new());
+    assert_eq!(vm.call::<_, String>("version_of", ("Main",)).unwrap(), "3");
+    match vm.call::<_, String>("version_of", ("Other",)) {
+        Err(VmError::Trap(diagnostic)) => assert_eq!(diagnostic.message, "No module Other is loaded"),
+        other => panic!("expected a trap, got {:?}", other.map(|_| ())),
+    }
+}
+
+const CONDITIONAL: &str = "
+module Main
+
+fn MAIN
+    PushInt 0
+
+fn fetch 1
+needs net
+    LoadLocal 0
+    LoadName Host.Net.fetch
+    Call 1
+";
+
+#[test]
+fn conditional_functions_need_their_feature() {
+    // Net.fetch needn't be registered when nothing can call it
+    let sandboxed = build(CONDITIONAL, VmBuilder::new());
+    match sandboxed.call::<_, String>("fetch", ("url",)) {
+        Err(VmError::Trap(diagnostic)) => assert_eq!(diagnostic.message, "Main.fetch needs the net feature"),
+        other => panic!("expected a trap, got {:?}", other.map(|_| ())),
+    }
+
+    let full = build(CONDITIONAL, VmBuilder::new()
+        .feature("net")
+        .register_fn("Net::fetch", |(url,): (String,)| Ok(format!("fetched {}", url))));
+    assert_eq!(full.call::<_, String>("fetch", ("url",)).unwrap(), "fetched url");
+    let unregistered = VmBuilder::new().module(asm::assemble(CONDITIONAL).unwrap()).feature("net").build();
+    assert!(matches!(unregistered.err().unwrap().kind, LinkErrorKind::NoSuchHostFunction(..)));
+}
+
+#[test]
+fn traps_come_back_as_errors() {
+    let source = "
+module Main
+
+fn MAIN
+    PushInt 0
+    PushInt 1
+    LoadName Prelude./
+    Call 2
+
+fn forever
+    LoadGlobal forever
+    Call 0
+";
+    let vm = build(source, VmBuilder::new().max_frames(50));
+    match vm.run() {
+        Err(VmError::Trap(diagnostic)) => {
+            assert_eq!(diagnostic.code, "trap");
+            assert_eq!(diagnostic.message, "attempt to divide by zero");
+            assert_eq!(diagnostic.module, Some(vec!("Main".to_string())));
+            assert_eq!(diagnostic.ip, Some(3));
+        }
+        other => panic!("expected a trap, got {:?}", other),
+    }
+    match vm.call::<_, Value>("forever", ()) {
+        Err(VmError::Trap(diagnostic)) => assert_eq!(diagnostic.code, "frame-limit"),
+        other => panic!("expected the frame limit, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn warnings_go_to_the_output() {
+    let output = Arc::new(Captured::default());
+    let config = VmConfig {
+        quiet: true,
+        snapshot: Some("/nonexistent/dir/snapshot.json".to_string()),
+        snapshot_every: 1,
+        ..VmConfig::default()
+    };
+    let vm = build(MAIN, VmBuilder::new().config(config).output(output.clone()));
+    vm.run().unwrap();
+    assert_eq!(output.lines(), vec!("hello"));
+    let warnings = output.warnings();
+    assert!(!warnings.is_empty() && warnings.iter().all(|warning| warning.starts_with("Cannot write snapshot to /nonexistent/dir/snapshot.json")), "{:?}", warnings);
+}
+
+#[test]
+fn forks_share_modules_not_output() {
+    let output = Arc::new(Captured::default());
+    let vm = build(MAIN, VmBuilder::new().output(output.clone()));
+    let mut fork = vm.fork();
+    assert!(std::ptr::eq(vm.modules(), fork.modules()));
+
+    let speculative = Arc::new(Captured::default());
+    fork.set_output(speculative.clone());
+    fork.run().unwrap();
+    assert_eq!(fork.call::<_, i64>("add", (1, 2)).unwrap(), 3);
+    drop(fork);
+    assert_eq!(speculative.lines(), vec!("hello"));
+    assert!(output.lines().is_empty());
+
+    vm.run().unwrap();
+    assert_eq!(output.lines(), vec!("hello"));
+}
+
+// Each run has a heap of its own, values go from one to the next as `Value`s. Function refs are
+// by name, so they go for whatever the receiving VM has by that name
+#[test]
+fn values_move_between_vms() {
+    let from = build(&format!("{}\nfn pick\n    LoadGlobal add\n", MAIN), VmBuilder::new());
+    let apply = "\nfn apply 1\n    PushInt 2\n    PushInt 3\n    LoadLocal 0\n    Call 2\n";
+    let to = build(&format!("{}{}", MAIN, apply), VmBuilder::new());
+    let greeting = from.call::<_, Value>("greet", ("hi",)).unwrap();
+    assert_eq!(to.call::<_, String>("greet", (greeting,)).unwrap(), "hi");
+
+    let add = from.call::<_, Value>("pick", ()).unwrap();
+    assert_eq!(add, Value::Function(vec!("Main".to_string()), "add".to_string()));
+    assert_eq!(to.call::<_, i64>("apply", (add.clone(),)).unwrap(), 5);
+    let without = build(&format!("module Main\nfn MAIN\n    PushInt 0\n{}", apply), VmBuilder::new());
+    assert!(matches!(without.call::<_, i64>("apply", (add,)), Err(VmError::Trap(_))));
+}
+
+#[test]
+fn bad_snapshots_are_errors() {
+    let config = VmConfig { quiet: true, resume: Some("/nonexistent/snapshot.json".to_string()), ..VmConfig::default() };
+    let vm = build(MAIN, VmBuilder::new().config(config));
+    match vm.run() {
+        Err(VmError::Resume(err)) => assert!(err.starts_with("Cannot resume from /nonexistent/snapshot.json: "), "{}", err),
+        other => panic!("expected a resume error, got {:?}", other),
+    }
+}
+
+#[test]
+fn lint_warnings_go_to_the_output() {
+    let output = Arc::new(Captured::default());
+    let mut config = VmConfig { quiet: true, ..VmConfig::default() };
+    config.lints.insert(Lint::UnusedFunction, Level::Warn);
+    build("module Main\nfn MAIN\n    PushInt 1\nfn helper\n    PushInt 2\n", VmBuilder::new().config(config).output(output.clone()));
+    assert_eq!(output.warnings(), vec!("warning: Main.helper: never referenced [unused-function]"));
+}
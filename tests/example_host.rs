@@ -0,0 +1,25 @@
+// The example host, run against a captured output
+extern crate lib;
+
+// Its `main` is for `cargo run --example host`
+#[allow(dead_code)]
+#[path = "../examples/host.rs"]
+mod host;
+
+use std::sync::Arc;
+use lib::embed::Captured;
+
+#[test]
+fn handlers_run_each_tick_and_traps_are_reported() {
+    let output = Arc::new(Captured::default());
+    let log = host::run(2, output.clone()).unwrap();
+    assert_eq!(output.lines(), vec!("plugin loaded"));
+    assert_eq!(log, vec!(
+        "reported 0",
+        "tick 0: on_tick returned Int(0)",
+        "tick 0: share trapped: attempt to divide by zero",
+        "reported 1",
+        "tick 1: on_tick returned Int(0)",
+        "tick 1: share returned Int(100)",
+    ));
+}